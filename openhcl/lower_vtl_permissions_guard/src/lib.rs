@@ -14,6 +14,7 @@
 use anyhow::Result;
 use inspect::Inspect;
 use std::sync::Arc;
+use thiserror::Error;
 use user_driver::DmaClient;
 use user_driver::memory::MemoryBlock;
 use virt::VtlMemoryProtection;
@@ -35,10 +36,18 @@ fn new_from_pages(
         vtl_protect: Arc<dyn VtlMemoryProtection + Send + Sync>,
         pages: &[u64],
     ) -> Result<Self> {
-        for pfn in pages {
-            vtl_protect
-                .modify_vtl_page_setting(*pfn, hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
-                .context("failed to update VTL protections on page")?;
+        for (i, pfn) in pages.iter().enumerate() {
+            if let Err(err) =
+                vtl_protect.modify_vtl_page_setting(*pfn, hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
+            {
+                // Restore the protections on the pages that were already
+                // modified.
+                drop(Self {
+                    vtl_protect,
+                    pages: pages[..i].to_vec(),
+                });
+                return Err(err).context("failed to update VTL protections on page");
+            }
         }
         Ok(Self {
             vtl_protect,
@@ -71,6 +80,14 @@ fn drop(&mut self) {
     }
 }
 
+/// Error returned by [`LowerVtlMemorySpawner`] when the allocation succeeded
+/// but the VTL permissions of its pages could not be lowered.
+///
+/// The allocation is freed before this error is returned.
+#[derive(Debug, Error)]
+#[error("failed to lower VTL permissions on memory block")]
+pub struct LowerVtlPermissionsError(#[source] anyhow::Error);
+
 /// A [`DmaClient`] wrapper that will lower the VTL permissions of the page
 /// on the allocated memory block.
 #[derive(Inspect)]
@@ -97,7 +114,7 @@ fn allocate_dma_buffer(&self, len: usize) -> Result<MemoryBlock> {
         let mem = self.spawner.allocate_dma_buffer(len)?;
         let vtl_guard =
             PagesAccessibleToLowerVtl::new_from_pages(self.vtl_protect.clone(), mem.pfns())
-                .context("failed to lower VTL permissions on memory block")?;
+                .map_err(LowerVtlPermissionsError)?;
 
         Ok(MemoryBlock::new(LowerVtlDmaBuffer {
             block: mem,
@@ -12,13 +12,17 @@
 use hcl_mapper::HclMapper;
 use inspect::Inspect;
 use lower_vtl_permissions_guard::LowerVtlMemorySpawner;
+use lower_vtl_permissions_guard::LowerVtlPermissionsError;
 use memory_range::MemoryRange;
 use page_pool_alloc::PagePool;
 use page_pool_alloc::PagePoolAllocator;
 use page_pool_alloc::PagePoolAllocatorSpawner;
+use parking_lot::Mutex;
 use std::sync::Arc;
+use std::sync::Weak;
 use user_driver::DmaClient;
 use user_driver::lockmem::LockedMemorySpawner;
+use virt::VtlMemoryProtection;
 
 /// Save restore support for [`OpenhclDmaManager`].
 pub mod save_restore {
@@ -122,16 +126,20 @@ pub struct OpenhclDmaManager {
 }
 
 /// The required VTL permissions on DMA allocations.
-#[derive(Inspect)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Inspect)]
 pub enum LowerVtlPermissionPolicy {
     /// No specific permission constraints are required.
     Any,
     /// All allocations must be accessible to VTL0.
     Vtl0,
+    /// All allocations must be accessible to VTL0. If the VTL permissions of
+    /// an allocation cannot be lowered, the allocation is made from the
+    /// shared pool instead, which is accessible to all VTLs.
+    Vtl0WithSharedFallback,
 }
 
 /// The CVM page visibility required for DMA allocations.
-#[derive(Copy, Clone, Inspect)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Inspect)]
 pub enum AllocationVisibility {
     /// Allocations must be shared with the host (aka host visible).
     Shared,
@@ -151,12 +159,33 @@ pub struct DmaClientParameters {
     /// Whether allocations should be persistent. Persistent allocations can
     /// survive save/restore.
     pub persistent_allocations: bool,
+    /// Whether non-persistent private allocations should come from the
+    /// private pool, if there is one, rather than from locked VTL2 memory,
+    /// for NUMA locality and inspectability.
+    pub prefer_pool: bool,
 }
 
 struct DmaManagerInner {
     shared_spawner: Option<PagePoolAllocatorSpawner>,
     private_spawner: Option<PagePoolAllocatorSpawner>,
-    lower_vtl: Option<Arc<DmaManagerLowerVtl>>,
+    lower_vtl: Option<Arc<dyn VtlMemoryProtection + Send + Sync>>,
+    /// Clients created by this manager, for diagnostics. Entries for dropped
+    /// clients are pruned lazily.
+    clients: Mutex<Vec<Weak<OpenhclDmaClient>>>,
+}
+
+/// A summary of an active [`OpenhclDmaClient`], as returned by
+/// [`OpenhclDmaManager::clients`].
+#[derive(Debug, Clone, Inspect)]
+pub struct DmaClientSummary {
+    /// The name of the client.
+    pub device_name: String,
+    /// The required VTL permissions on allocations.
+    pub lower_vtl_policy: LowerVtlPermissionPolicy,
+    /// The required CVM page visibility for allocations.
+    pub allocation_visibility: AllocationVisibility,
+    /// Whether allocations are persistent.
+    pub persistent_allocations: bool,
 }
 
 /// Used by [`OpenhclDmaManager`] to modify VTL permissions via
@@ -180,7 +209,7 @@ pub fn new() -> anyhow::Result<Arc<Self>> {
     }
 }
 
-impl virt::VtlMemoryProtection for DmaManagerLowerVtl {
+impl VtlMemoryProtection for DmaManagerLowerVtl {
     fn modify_vtl_page_setting(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> anyhow::Result<()> {
         self.mshv_hvcall
             .modify_vtl_protection_mask(
@@ -201,11 +230,13 @@ fn new_dma_client(&self, params: DmaClientParameters) -> anyhow::Result<Arc<Open
                 lower_vtl_policy,
                 allocation_visibility,
                 persistent_allocations,
+                prefer_pool,
             } = &params;
 
             struct ClientCreation<'a> {
                 allocation_visibility: AllocationVisibility,
                 persistent_allocations: bool,
+                prefer_pool: bool,
                 shared_spawner: Option<&'a PagePoolAllocatorSpawner>,
                 private_spawner: Option<&'a PagePoolAllocatorSpawner>,
             }
@@ -213,6 +244,7 @@ struct ClientCreation<'a> {
             let creation = ClientCreation {
                 allocation_visibility: *allocation_visibility,
                 persistent_allocations: *persistent_allocations,
+                prefer_pool: *prefer_pool,
                 shared_spawner: self.shared_spawner.as_ref(),
                 private_spawner: self.private_spawner.as_ref(),
             };
@@ -221,6 +253,7 @@ struct ClientCreation<'a> {
                 ClientCreation {
                     allocation_visibility: AllocationVisibility::Shared,
                     persistent_allocations: _,
+                    prefer_pool: _,
                     shared_spawner: Some(shared),
                     private_spawner: _,
                 } => {
@@ -237,6 +270,7 @@ struct ClientCreation<'a> {
                 ClientCreation {
                     allocation_visibility: AllocationVisibility::Shared,
                     persistent_allocations: _,
+                    prefer_pool: _,
                     shared_spawner: None,
                     private_spawner: _,
                 } => {
@@ -246,19 +280,29 @@ struct ClientCreation<'a> {
                 ClientCreation {
                     allocation_visibility: AllocationVisibility::Private,
                     persistent_allocations: true,
+                    prefer_pool: _,
+                    shared_spawner: _,
+                    private_spawner: Some(private),
+                }
+                | ClientCreation {
+                    allocation_visibility: AllocationVisibility::Private,
+                    persistent_allocations: false,
+                    prefer_pool: true,
                     shared_spawner: _,
                     private_spawner: Some(private),
                 } => match lower_vtl_policy {
                     LowerVtlPermissionPolicy::Any => {
                         // Only the private pool supports persistent
-                        // allocations.
+                        // allocations, and it is used for non-persistent
+                        // ones if the caller prefers it.
                         DmaClientBacking::PrivatePool(
                             private
                                 .allocator(device_name.into())
                                 .context("failed to create private allocator")?,
                         )
                     }
-                    LowerVtlPermissionPolicy::Vtl0 => {
+                    LowerVtlPermissionPolicy::Vtl0
+                    | LowerVtlPermissionPolicy::Vtl0WithSharedFallback => {
                         // Private memory must be wrapped in a lower VTL memory
                         // spawner, as otherwise it is accessible to VTL2 only.
                         DmaClientBacking::PrivatePoolLowerVtl(LowerVtlMemorySpawner::new(
@@ -277,6 +321,7 @@ struct ClientCreation<'a> {
                 ClientCreation {
                     allocation_visibility: AllocationVisibility::Private,
                     persistent_allocations: true,
+                    prefer_pool: _,
                     shared_spawner: _,
                     private_spawner: None,
                 } => {
@@ -286,6 +331,7 @@ struct ClientCreation<'a> {
                 ClientCreation {
                     allocation_visibility: AllocationVisibility::Private,
                     persistent_allocations: false,
+                    prefer_pool: _,
                     shared_spawner: _,
                     private_spawner: _,
                 } => match lower_vtl_policy {
@@ -294,7 +340,8 @@ struct ClientCreation<'a> {
                         // using normal VTL2 ram is fine.
                         DmaClientBacking::LockedMemory(LockedMemorySpawner)
                     }
-                    LowerVtlPermissionPolicy::Vtl0 => {
+                    LowerVtlPermissionPolicy::Vtl0
+                    | LowerVtlPermissionPolicy::Vtl0WithSharedFallback => {
                         // `LockedMemorySpawner` uses private VTL2 ram, so
                         // lowering VTL permissions is required.
                         DmaClientBacking::LockedMemoryLowerVtl(LowerVtlMemorySpawner::new(
@@ -311,7 +358,46 @@ struct ClientCreation<'a> {
             }
         };
 
-        Ok(Arc::new(OpenhclDmaClient { backing, params }))
+        // Only allocations whose VTL permissions must be lowered can fail
+        // over to the shared pool.
+        let shared_fallback = match (&params.lower_vtl_policy, &backing) {
+            (
+                LowerVtlPermissionPolicy::Vtl0WithSharedFallback,
+                DmaClientBacking::PrivatePoolLowerVtl(_)
+                | DmaClientBacking::LockedMemoryLowerVtl(_),
+            ) => self
+                .shared_spawner
+                .as_ref()
+                .map(|shared| shared.allocator(params.device_name.clone()))
+                .transpose()
+                .context("failed to create shared fallback allocator")?,
+            _ => None,
+        };
+
+        let client = Arc::new(OpenhclDmaClient {
+            backing,
+            params,
+            shared_fallback,
+        });
+
+        let mut clients = self.clients.lock();
+        clients.retain(|c| c.strong_count() > 0);
+        clients.push(Arc::downgrade(&client));
+        Ok(client)
+    }
+
+    fn clients(&self) -> Vec<DmaClientSummary> {
+        self.clients
+            .lock()
+            .iter()
+            .filter_map(|c| c.upgrade())
+            .map(|c| DmaClientSummary {
+                device_name: c.params.device_name.clone(),
+                lower_vtl_policy: c.params.lower_vtl_policy,
+                allocation_visibility: c.params.allocation_visibility,
+                persistent_allocations: c.params.persistent_allocations,
+            })
+            .collect()
     }
 }
 
@@ -365,6 +451,7 @@ pub fn new(
                 } else {
                     Some(DmaManagerLowerVtl::new().context("failed to create lower vtl")?)
                 },
+                clients: Mutex::new(Vec::new()),
             }),
             shared_pool,
             private_pool,
@@ -377,6 +464,12 @@ pub fn new_client(&self, params: DmaClientParameters) -> anyhow::Result<Arc<Open
         self.inner.new_dma_client(params)
     }
 
+    /// Returns a summary of each client created by this manager that has not
+    /// yet been dropped.
+    pub fn clients(&self) -> Vec<DmaClientSummary> {
+        self.inner.clients()
+    }
+
     /// Returns a [`DmaClientSpawner`] for creating DMA clients.
     pub fn client_spawner(&self) -> DmaClientSpawner {
         DmaClientSpawner {
@@ -470,6 +563,10 @@ fn attach_pending_buffers(&self) -> anyhow::Result<Vec<user_driver::memory::Memo
 pub struct OpenhclDmaClient {
     backing: DmaClientBacking,
     params: DmaClientParameters,
+    /// Used for allocations whose VTL permissions could not be lowered, per
+    /// [`LowerVtlPermissionPolicy::Vtl0WithSharedFallback`].
+    #[inspect(skip)]
+    shared_fallback: Option<PagePoolAllocator>,
 }
 
 impl DmaClient for OpenhclDmaClient {
@@ -477,10 +574,207 @@ fn allocate_dma_buffer(
         &self,
         total_size: usize,
     ) -> anyhow::Result<user_driver::memory::MemoryBlock> {
-        self.backing.allocate_dma_buffer(total_size)
+        match self.backing.allocate_dma_buffer(total_size) {
+            Ok(mem) => Ok(mem),
+            Err(err) => match &self.shared_fallback {
+                Some(shared) if err.downcast_ref::<LowerVtlPermissionsError>().is_some() => {
+                    tracing::warn!(
+                        device_name = self.params.device_name,
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "falling back to shared pool allocation"
+                    );
+                    shared.allocate_dma_buffer(total_size)
+                }
+                _ => Err(err),
+            },
+        }
     }
 
     fn attach_pending_buffers(&self) -> anyhow::Result<Vec<user_driver::memory::MemoryBlock>> {
         self.backing.attach_pending_buffers()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use page_pool_alloc::TestMapper;
+
+    /// Lowers VTL permissions, or fails to if `fail` is set.
+    struct TestVtlProtection {
+        fail: bool,
+    }
+
+    impl VtlMemoryProtection for TestVtlProtection {
+        fn modify_vtl_page_setting(
+            &self,
+            _pfn: u64,
+            flags: hvdef::HvMapGpaFlags,
+        ) -> anyhow::Result<()> {
+            if self.fail && flags != hvdef::HV_MAP_GPA_PERMISSIONS_NONE {
+                anyhow::bail!("permission change not allowed");
+            }
+            Ok(())
+        }
+    }
+
+    const SHARED_PAGES: std::ops::Range<u64> = 0..16;
+    const PRIVATE_PAGES: std::ops::Range<u64> = 16..32;
+
+    fn test_pools() -> (PagePool, PagePool) {
+        let shared = PagePool::new(
+            &[MemoryRange::from_4k_gpn_range(SHARED_PAGES)],
+            TestMapper::new(PRIVATE_PAGES.end).unwrap(),
+        )
+        .unwrap();
+        let private = PagePool::new(
+            &[MemoryRange::from_4k_gpn_range(PRIVATE_PAGES)],
+            TestMapper::new(PRIVATE_PAGES.end).unwrap(),
+        )
+        .unwrap();
+        (shared, private)
+    }
+
+    fn test_inner(shared: &PagePool, private: &PagePool, fail: bool) -> DmaManagerInner {
+        DmaManagerInner {
+            shared_spawner: Some(shared.allocator_spawner()),
+            private_spawner: Some(private.allocator_spawner()),
+            lower_vtl: Some(Arc::new(TestVtlProtection { fail })),
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn test_client(
+        shared: &PagePool,
+        private: &PagePool,
+        fail: bool,
+        lower_vtl_policy: LowerVtlPermissionPolicy,
+    ) -> Arc<OpenhclDmaClient> {
+        test_inner(shared, private, fail)
+            .new_dma_client(DmaClientParameters {
+                device_name: "test".into(),
+                lower_vtl_policy,
+                allocation_visibility: AllocationVisibility::Private,
+                persistent_allocations: true,
+                prefer_pool: false,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_shared_fallback() {
+        let (shared, private) = test_pools();
+
+        // Lowering permissions succeeds, so the private pool is used.
+        let client = test_client(
+            &shared,
+            &private,
+            false,
+            LowerVtlPermissionPolicy::Vtl0WithSharedFallback,
+        );
+        let mem = client.allocate_dma_buffer(4096).unwrap();
+        assert!(PRIVATE_PAGES.contains(&mem.pfns()[0]));
+        drop(mem);
+        drop(client);
+
+        // Lowering permissions fails, so the shared pool is used.
+        let client = test_client(
+            &shared,
+            &private,
+            true,
+            LowerVtlPermissionPolicy::Vtl0WithSharedFallback,
+        );
+        let mem = client.allocate_dma_buffer(4096).unwrap();
+        assert!(SHARED_PAGES.contains(&mem.pfns()[0]));
+    }
+
+    #[test]
+    fn test_prefer_pool() {
+        let (shared, private) = test_pools();
+        let inner = test_inner(&shared, &private, false);
+        let client = |prefer_pool| {
+            inner
+                .new_dma_client(DmaClientParameters {
+                    device_name: format!("test_{prefer_pool}"),
+                    lower_vtl_policy: LowerVtlPermissionPolicy::Any,
+                    allocation_visibility: AllocationVisibility::Private,
+                    persistent_allocations: false,
+                    prefer_pool,
+                })
+                .unwrap()
+        };
+
+        assert!(matches!(
+            client(false).backing,
+            DmaClientBacking::LockedMemory(_)
+        ));
+        let pooled = client(true);
+        assert!(matches!(pooled.backing, DmaClientBacking::PrivatePool(_)));
+        let mem = pooled.allocate_dma_buffer(4096).unwrap();
+        assert!(PRIVATE_PAGES.contains(&mem.pfns()[0]));
+    }
+
+    #[test]
+    fn test_no_shared_fallback() {
+        let (shared, private) = test_pools();
+
+        let client = test_client(&shared, &private, true, LowerVtlPermissionPolicy::Vtl0);
+        let err = client.allocate_dma_buffer(4096).unwrap_err();
+        assert!(err.downcast_ref::<LowerVtlPermissionsError>().is_some());
+    }
+
+    #[test]
+    fn test_clients() {
+        let (shared, private) = test_pools();
+        let inner = Arc::new(test_inner(&shared, &private, false));
+        let manager = OpenhclDmaManager {
+            shared_pool: Some(shared),
+            private_pool: Some(private),
+            inner,
+        };
+
+        let a = manager
+            .new_client(DmaClientParameters {
+                device_name: "a".into(),
+                lower_vtl_policy: LowerVtlPermissionPolicy::Any,
+                allocation_visibility: AllocationVisibility::Shared,
+                persistent_allocations: false,
+                prefer_pool: false,
+            })
+            .unwrap();
+        let b = manager
+            .client_spawner()
+            .new_client(DmaClientParameters {
+                device_name: "b".into(),
+                lower_vtl_policy: LowerVtlPermissionPolicy::Vtl0,
+                allocation_visibility: AllocationVisibility::Private,
+                persistent_allocations: true,
+                prefer_pool: false,
+            })
+            .unwrap();
+
+        let clients = manager.clients();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].device_name, "a");
+        assert_eq!(
+            clients[0].allocation_visibility,
+            AllocationVisibility::Shared
+        );
+        assert!(!clients[0].persistent_allocations);
+        assert_eq!(clients[1].device_name, "b");
+        assert_eq!(clients[1].lower_vtl_policy, LowerVtlPermissionPolicy::Vtl0);
+        assert_eq!(
+            clients[1].allocation_visibility,
+            AllocationVisibility::Private
+        );
+        assert!(clients[1].persistent_allocations);
+
+        // Dropped clients are no longer listed.
+        drop(a);
+        let clients = manager.clients();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].device_name, "b");
+        drop(b);
+        assert!(manager.clients().is_empty());
+    }
+}
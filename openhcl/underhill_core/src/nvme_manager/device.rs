@@ -214,6 +214,7 @@ fn create_dma_client(
                     AllocationVisibility::Private
                 },
                 persistent_allocations: save_restore_supported,
+                prefer_pool: false,
             })
             .map_err(NvmeSpawnerError::DmaClient)
     }
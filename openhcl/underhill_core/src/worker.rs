@@ -834,6 +834,7 @@ async fn new_underhill_nic(
             lower_vtl_policy: LowerVtlPermissionPolicy::Any,
             allocation_visibility,
             persistent_allocations: false,
+            prefer_pool: false,
         })?;
 
         // We need a persistent client if keepalive is enabled or if there is a
@@ -844,6 +845,7 @@ async fn new_underhill_nic(
                 device_name: format!("nic_{}", nic_config.pci_id),
                 lower_vtl_policy: LowerVtlPermissionPolicy::Any,
                 persistent_allocations: true,
+                prefer_pool: false,
                 allocation_visibility,
             })?)
         } else {
@@ -2025,6 +2027,7 @@ async fn new_underhill_vm(
                     AllocationVisibility::Private
                 },
                 persistent_allocations: false,
+                prefer_pool: false,
             })
             .context("get dma client")?,
     );
@@ -2299,12 +2302,14 @@ async fn new_underhill_vm(
                 lower_vtl_policy: LowerVtlPermissionPolicy::Any,
                 allocation_visibility: AllocationVisibility::Shared,
                 persistent_allocations: false,
+                prefer_pool: false,
             })?,
             private_dma_client: dma_manager.new_client(DmaClientParameters {
                 device_name: "partition-private".into(),
                 lower_vtl_policy: LowerVtlPermissionPolicy::Any,
                 allocation_visibility: AllocationVisibility::Private,
                 persistent_allocations: false,
+                prefer_pool: false,
             })?,
         })
     } else {
@@ -3339,6 +3344,7 @@ async fn new_underhill_vm(
                             AllocationVisibility::Private
                         },
                         persistent_allocations: false,
+                        prefer_pool: false,
                     })?,
                     vpci_relay_mmio,
                     if use_mmio_hypercalls {
@@ -3618,6 +3624,7 @@ async fn new_underhill_vm(
                     lower_vtl_policy: LowerVtlPermissionPolicy::Vtl0,
                     allocation_visibility: AllocationVisibility::Private,
                     persistent_allocations: false,
+                    prefer_pool: false,
                 })
                 .context("shutdown relay dma client")?,
             shutdown_guest,
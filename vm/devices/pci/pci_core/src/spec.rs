@@ -1264,7 +1264,9 @@ pub enum SriovExtendedCapabilityHeader: u16 {
                 HEADER = 0x00,
                 CAPS_CONTROL = 0x04,
                 INITIAL_TOTAL_VFS = 0x0C,
+                NUM_VFS = 0x10,
                 VF_OFFSET_STRIDE = 0x14,
+                VF_DEVICE_ID = 0x18,
                 VF_BAR0 = 0x24,
             }
         }
@@ -0,0 +1,8 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+#![expect(missing_docs)]
+
+fn main() {
+    build_rs_guest_arch::emit_guest_arch()
+}
@@ -9,17 +9,22 @@
 //! resource and power management, like Linux does, as opposed to the
 //! message-based interface, like Windows does.
 
+#[cfg(any(test, feature = "test"))]
+pub mod test_helpers;
 mod tests;
 
 use anyhow::Context;
 use futures::FutureExt;
 use futures::Stream;
 use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
 use futures_concurrency::future::Race;
 use guestmem::MemoryRead;
 use inspect::Inspect;
 use inspect::InspectMut;
 use mesh::rpc::FailableRpc;
+use mesh::rpc::Rpc;
 use mesh::rpc::RpcSend;
 use openhcl_tdisp::GuestToHostCommand;
 use openhcl_tdisp::GuestToHostCommandExt;
@@ -39,12 +44,20 @@
 use pal_async::task::Spawn;
 use pal_async::task::Task;
 use parking_lot::Mutex;
+use pci_core::spec::caps::EXT_CAP_END;
+use pci_core::spec::caps::EXT_CAP_START;
+use pci_core::spec::caps::ExtendedCapabilityId;
+use pci_core::spec::caps::sriov::SriovExtendedCapabilityHeader;
 use pci_core::spec::cfg_space::Command;
 use pci_core::spec::cfg_space::HeaderType00;
 use pci_core::spec::hwid::HardwareIds;
+use std::collections::VecDeque;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::Ordering;
 use std::task::Poll;
+use std::time::Duration;
 use tdisp::devicereport::TdiReportStruct;
 use thiserror::Error;
 use vmbus_async::queue::IncomingPacket;
@@ -55,6 +68,7 @@
 use vmcore::vpci_msi::MapVpciInterrupt;
 use vmcore::vpci_msi::MsiAddressData;
 use vmcore::vpci_msi::RegisterInterruptError;
+use vmcore::vpci_msi::VpciInterruptParameters;
 use vpci_protocol as protocol;
 use vpci_protocol::MAX_VPCI_TDISP_COMMAND_SIZE;
 use vpci_protocol::SlotNumber;
@@ -68,7 +82,8 @@
 /// A VPCI client instance, for a single VPCI bus.
 pub struct VpciClient {
     req: mesh::Sender<WorkerRequest>,
-    task: Task<()>,
+    task: Task<WorkerState>,
+    stop: mesh::OneshotSender<()>,
 }
 
 impl Inspect for VpciClient {
@@ -81,15 +96,35 @@ enum WorkerRequest {
     Inspect(inspect::Deferred),
     MapInterrupt(
         FailableRpc<
-            (DeviceId, vpci_protocol::MsiResourceDescriptor2),
+            (DeviceId, vpci_protocol::MsiResourceDescriptor3),
             protocol::MsiResourceRemapped,
         >,
     ),
     UnmapInterrupt(FailableRpc<(DeviceId, vpci_protocol::MsiResourceRemapped), ()>),
     QueryResourceRequirements(FailableRpc<DeviceId, protocol::QueryResourceRequirementsReply>),
-    Init(FailableRpc<DeviceId, ()>),
+    Init(FailableRpc<(DeviceId, Arc<Mutex<ConfigSpaceShadows>>), ()>),
     Done(DeviceId),
     TdispCommand(FailableRpc<protocol::VpciTdispCommand, GuestToHostResponse>),
+    WaitForDevice(DeviceWaiter),
+    Rescan(Rpc<(), ()>),
+}
+
+/// A pending [`VpciClient::wait_for_device`] call.
+#[derive(Inspect)]
+struct DeviceWaiter {
+    /// The (vendor ID, device ID) pairs to match.
+    #[inspect(debug)]
+    hw_ids: Vec<(u16, u16)>,
+    #[inspect(skip)]
+    send: mesh::OneshotSender<VpciDeviceDescription>,
+}
+
+impl DeviceWaiter {
+    fn matches(&self, hw_ids: &HardwareIds) -> bool {
+        self.hw_ids.iter().any(|&(vendor_id, device_id)| {
+            hw_ids.vendor_id == vendor_id && hw_ids.device_id == device_id
+        })
+    }
 }
 
 #[derive(Debug, Copy, Clone, Inspect)]
@@ -134,8 +169,18 @@ async fn transact<
     }
 
     async fn negotiate(&mut self) -> anyhow::Result<protocol::ProtocolVersion> {
-        // Try to negotiate versions in order from newest to oldest
-        let versions = &[protocol::ProtocolVersion::VB];
+        // Try to negotiate versions in order from newest to oldest. ARM64
+        // interrupts can only be described starting with FE.
+        let versions: &[_] = if cfg!(guest_arch = "aarch64") {
+            &[protocol::ProtocolVersion::FE]
+        } else {
+            &[
+                protocol::ProtocolVersion::FE,
+                protocol::ProtocolVersion::VB,
+                protocol::ProtocolVersion::RS1,
+                protocol::ProtocolVersion::WIN10,
+            ]
+        };
 
         for &version in versions {
             tracing::debug!(?version, "trying protocol version");
@@ -158,6 +203,41 @@ async fn negotiate(&mut self) -> anyhow::Result<protocol::ProtocolVersion> {
 
         anyhow::bail!("no supported VPCI protocol version found");
     }
+
+    /// Starts a transaction to move the bus to the D0 state, with its MMIO
+    /// space at `gpa`.
+    ///
+    /// The completion may come after the device list, so the worker must be
+    /// started before waiting on the returned receiver.
+    async fn send_fdo_d0_entry(
+        &mut self,
+        tx: &mut slab::Slab<Tx>,
+        tx_id_base: u64,
+        gpa: u64,
+    ) -> anyhow::Result<mesh::OneshotReceiver<Result<Vec<VpciDeviceDescription>, protocol::Status>>>
+    {
+        let (fdo_entry_send, fdo_entry_recv) = mesh::oneshot();
+        let entry = tx.vacant_entry();
+        let tx_id = index_to_tx_id(tx_id_base, entry.key())?;
+        entry.insert(Tx::FdoD0Entry(fdo_entry_send));
+        self.queue
+            .split()
+            .1
+            .write(OutgoingPacket {
+                transaction_id: tx_id,
+                packet_type: vmbus_ring::OutgoingPacketType::InBandWithCompletion,
+                payload: &[protocol::FdoD0Entry {
+                    message_type: protocol::MessageType::FDO_D0_ENTRY,
+                    padding: 0,
+                    mmio_start: gpa,
+                }
+                .as_bytes()],
+            })
+            .await
+            .context("failed to send FDO D0 entry")?;
+
+        Ok(fdo_entry_recv)
+    }
 }
 
 async fn send_eject_complete<M: RingMem>(
@@ -192,6 +272,41 @@ pub trait MemoryAccess: Send {
 /// The amount of MMIO space required by the VPCI bus.
 pub const MMIO_SIZE: u64 = 0x2000;
 
+/// Options for connecting to a VPCI bus.
+#[derive(Debug, Clone, Inspect)]
+pub struct VpciClientOptions {
+    /// The first transaction ID to use for requests to the host. Transaction
+    /// IDs below this value are never sent, which allows avoiding a range
+    /// reserved by the host.
+    ///
+    /// Hyper-V VPCI doesn't like transaction IDs of 0, so this must be
+    /// nonzero.
+    #[inspect(hex)]
+    pub tx_id_base: u64,
+    /// How long to wait for the host to complete the FDO D0 entry handshake
+    /// when connecting or reconnecting before giving up.
+    pub fdo_entry_timeout: Duration,
+    /// The largest BAR size the host may report for a device. Initializing a
+    /// device with a larger BAR fails.
+    #[inspect(hex)]
+    pub max_bar_size: u64,
+    /// How long to wait for the owner of an ejected device to drop it before
+    /// completing the eject anyway. After that, the device's config space
+    /// reads as all ones. If `None`, wait indefinitely.
+    pub eject_timeout: Option<Duration>,
+}
+
+impl Default for VpciClientOptions {
+    fn default() -> Self {
+        Self {
+            tx_id_base: 1,
+            fdo_entry_timeout: Duration::from_secs(5),
+            max_bar_size: 4 << 30,
+            eject_timeout: None,
+        }
+    }
+}
+
 /// A device description, which represents a VPCI device available on a bus.
 #[derive(Inspect)]
 pub struct VpciDeviceDescription {
@@ -199,13 +314,15 @@ pub struct VpciDeviceDescription {
     #[inspect(skip)]
     config_space: Arc<Mutex<ConfigSpaceAccessor>>,
     id: DeviceId,
-    numa_node: u16,
+    numa_node: Arc<AtomicU16>,
     #[inspect(hex)]
     serial_num: u32,
     #[inspect(skip)]
     req: mesh::Sender<WorkerRequest>,
     #[inspect(skip)]
     eject: mesh::Receiver<VpciDeviceEjected>,
+    #[inspect(hex)]
+    max_bar_size: u64,
 }
 
 /// An initialized VPCI device.
@@ -214,17 +331,36 @@ pub struct VpciDevice {
     hw_ids: HardwareIds,
     #[inspect(skip)]
     config_space: Arc<Mutex<ConfigSpaceAccessor>>,
-    numa_node: u16,
+    numa_node: Arc<AtomicU16>,
     #[inspect(hex)]
     serial_num: u32,
     #[inspect(flatten)]
     dev: InUseDevice,
-    shadows: Mutex<ConfigSpaceShadows>,
+    shadows: Arc<Mutex<ConfigSpaceShadows>>,
     #[inspect(hex, iter_by_index)]
     bar_masks: [u32; 6],
     #[inspect(hex, iter_by_index)]
     /// RAO == Read As One
     bar_rao: [u32; 6],
+    #[inspect(with = "inspect_recent_accesses")]
+    recent_accesses: Mutex<VecDeque<ConfigAccess>>,
+}
+
+/// The number of recent config space accesses kept per device, for debugging.
+const RECENT_ACCESS_COUNT: usize = 8;
+
+/// A config space access made through [`VpciDevice`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+struct ConfigAccess {
+    #[inspect(hex)]
+    offset: u16,
+    #[inspect(hex)]
+    value: u32,
+    is_write: bool,
+}
+
+fn inspect_recent_accesses(accesses: &Mutex<VecDeque<ConfigAccess>>) -> impl Inspect {
+    inspect::iter_by_index(accesses.lock().clone())
 }
 
 #[derive(Inspect)]
@@ -246,6 +382,16 @@ struct ConfigSpaceShadows {
     bars: [u32; 6],
 }
 
+impl ConfigSpaceShadows {
+    /// Writes the shadowed BARs to device `id`.
+    fn write_bars(&self, accessor: &mut ConfigSpaceAccessor, id: DeviceId) {
+        for (i, &bar) in self.bars.iter().enumerate() {
+            let bar_offset = HeaderType00::BAR0.0 + (i as u16 * 4);
+            accessor.write(id, bar_offset, bar);
+        }
+    }
+}
+
 impl ConfigSpaceAccessor {
     fn enable_slot(&mut self, id: DeviceId) {
         let i = u32::from(id.slot) as usize;
@@ -325,9 +471,10 @@ pub fn hw_ids(&self) -> &HardwareIds {
         &self.hw_ids
     }
 
-    /// Returns the NUMA node of the device.
+    /// Returns the NUMA node of the device, as most recently reported by the
+    /// host.
     pub fn numa_node(&self) -> u16 {
-        self.numa_node
+        self.numa_node.load(Ordering::Relaxed)
     }
 
     /// Returns the serial number of the device.
@@ -349,6 +496,9 @@ pub async fn init(self) -> anyhow::Result<(VpciDevice, VpciDeviceEject)> {
             "queried requirements"
         );
 
+        // Validate the BARs before the device is enabled.
+        let bar_rao = bar_rao(&requirements.bars, self.max_bar_size)?;
+
         let Self {
             hw_ids,
             config_space,
@@ -357,35 +507,23 @@ pub async fn init(self) -> anyhow::Result<(VpciDevice, VpciDeviceEject)> {
             serial_num,
             req,
             eject,
+            max_bar_size: _,
         } = self;
 
         // After this, the device is considered initialized and the caller is
         // responsible notifying the worker when the device is no longer in use.
         let dev = InUseDevice { req, id };
 
-        dev.req.call_failable(WorkerRequest::Init, id).await?;
-
-        let mut high64 = false;
-        let mut bar_rao = [0; 6];
-        for ((i, &bar), rao) in requirements.bars.iter().enumerate().zip(&mut bar_rao) {
-            if high64 {
-                high64 = false;
-                *rao = 0;
-            } else {
-                let bits = pci_core::spec::cfg_space::BarEncodingBits::from(bar);
-                if bits.use_pio() {
-                    anyhow::bail!("BAR {} is PIO, which is not supported by VPCI", i);
-                }
-                *rao = bar & 0xf;
-                high64 = bits.type_64_bit();
-            }
-        }
+        let shadows = Arc::new(Mutex::new(ConfigSpaceShadows {
+            command: Command::new(),
+            bars: [0; 6],
+        }));
+        dev.req
+            .call_failable(WorkerRequest::Init, (id, shadows.clone()))
+            .await?;
 
         let device = VpciDevice {
-            shadows: Mutex::new(ConfigSpaceShadows {
-                command: Command::new(),
-                bars: [0; 6],
-            }),
+            shadows,
             bar_masks: requirements.bars,
             bar_rao,
             hw_ids,
@@ -393,16 +531,84 @@ pub async fn init(self) -> anyhow::Result<(VpciDevice, VpciDeviceEject)> {
             numa_node,
             serial_num,
             dev,
+            recent_accesses: Mutex::new(VecDeque::with_capacity(RECENT_ACCESS_COUNT)),
         };
 
         Ok((device, VpciDeviceEject(eject)))
     }
 }
 
+/// Validates the BAR masks reported by the host, returning the bits of each
+/// BAR that read as one.
+fn bar_rao(bars: &[u32; 6], max_bar_size: u64) -> anyhow::Result<[u32; 6]> {
+    let mut high64 = false;
+    let mut bar_rao = [0; 6];
+    for ((i, &bar), rao) in bars.iter().enumerate().zip(&mut bar_rao) {
+        if high64 {
+            high64 = false;
+            *rao = 0;
+        } else {
+            let bits = pci_core::spec::cfg_space::BarEncodingBits::from(bar);
+            if bits.use_pio() {
+                anyhow::bail!("BAR {} is PIO, which is not supported by VPCI", i);
+            }
+            // The upper half of a 32-bit BAR's mask is implicitly all
+            // ones, as is that of a 64-bit BAR missing its upper half.
+            let high = if bits.type_64_bit() {
+                bars.get(i + 1).copied().unwrap_or(!0)
+            } else {
+                !0
+            };
+            if bar & !0xf == 0 && (!bits.type_64_bit() || high == 0) {
+                // The BAR is not implemented.
+                *rao = 0;
+                high64 = bits.type_64_bit();
+                continue;
+            }
+            let mask = u64::from(bar & !0xf) | (u64::from(high) << 32);
+            let size = (!mask).wrapping_add(1);
+            if size > max_bar_size {
+                anyhow::bail!(
+                    "BAR {} size {:#x} exceeds the maximum of {:#x}",
+                    i,
+                    size,
+                    max_bar_size
+                );
+            }
+            *rao = bar & 0xf;
+            high64 = bits.type_64_bit();
+        }
+    }
+    Ok(bar_rao)
+}
+
+/// SR-IOV parameters of a physical function, read from its SR-IOV extended
+/// capability.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+pub struct SriovInfo {
+    /// The config space offset of the SR-IOV capability.
+    #[inspect(hex)]
+    pub cap_offset: u16,
+    /// The number of VFs initially associated with the PF.
+    pub initial_vfs: u16,
+    /// The maximum number of VFs the PF supports.
+    pub total_vfs: u16,
+    /// The number of VFs currently enabled.
+    pub num_vfs: u16,
+    /// The routing ID offset of the first VF from the PF.
+    pub first_vf_offset: u16,
+    /// The routing ID distance between consecutive VFs.
+    pub vf_stride: u16,
+    /// The device ID reported by the VFs.
+    #[inspect(hex)]
+    pub vf_device_id: u16,
+}
+
 /// Stream that notifies that the device has been ejected or removed.
 pub struct VpciDeviceEject(mesh::Receiver<VpciDeviceEjected>);
 
 /// The kind of device removal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RemovalKind {
     /// The host requested that the device be ejected.
     Eject,
@@ -410,11 +616,15 @@ pub enum RemovalKind {
     SurpriseRemove,
 }
 
-/// Notification that the device is being ejected.
+/// Notification that the device is being ejected or has been removed.
 ///
 /// The [`VpciDeviceEject`] stream will be closed when the device is actually
 /// removed.
-pub struct VpciDeviceEjected;
+#[derive(Debug)]
+pub struct VpciDeviceEjected {
+    /// Whether the host requested an eject or removed the device without one.
+    pub kind: RemovalKind,
+}
 
 impl Stream for VpciDeviceEject {
     type Item = VpciDeviceEjected;
@@ -428,6 +638,118 @@ fn poll_next(
 }
 
 impl VpciDevice {
+    /// Returns the NUMA node of the device, as most recently reported by the
+    /// host.
+    pub fn numa_node(&self) -> u16 {
+        self.numa_node.load(Ordering::Relaxed)
+    }
+
+    /// Returns the serial number of the device.
+    pub fn serial_num(&self) -> u32 {
+        self.serial_num
+    }
+
+    /// Returns the device's subsystem vendor ID and subsystem ID.
+    pub fn subsystem_ids(&self) -> (u16, u16) {
+        (
+            self.hw_ids.type0_sub_vendor_id,
+            self.hw_ids.type0_sub_system_id,
+        )
+    }
+
+    /// Returns whether the host can satisfy an interrupt registration with
+    /// `vector_count` vectors and `params`, without leaving an interrupt
+    /// registered.
+    ///
+    /// The VPCI protocol has no query-only variant of interrupt creation, so
+    /// this registers the interrupt and immediately unregisters it. Any
+    /// failure to register, including invalid parameters, returns false.
+    pub async fn can_register_interrupt(
+        &self,
+        vector_count: u32,
+        params: &VpciInterruptParameters<'_>,
+    ) -> bool {
+        match self.register_interrupt(vector_count, params).await {
+            Ok(MsiAddressData { address, data }) => {
+                self.unregister_interrupt(address, data).await;
+                true
+            }
+            Err(err) => {
+                tracing::debug!(
+                    error = &err as &dyn std::error::Error,
+                    vector_count,
+                    "interrupt registration probe failed"
+                );
+                false
+            }
+        }
+    }
+
+    /// Registers several interrupts, each given as a vector count and
+    /// parameters, returning the results in the same order.
+    ///
+    /// All the requests are sent to the host before waiting for any replies, so
+    /// this takes a single round trip instead of one per interrupt.
+    pub async fn register_interrupts_batch(
+        &self,
+        requests: &[(u32, VpciInterruptParameters<'_>)],
+    ) -> Vec<Result<MsiAddressData, RegisterInterruptError>> {
+        let pending = requests
+            .iter()
+            .map(|(vector_count, params)| {
+                msi_descriptor(*vector_count, params).map(|interrupt| {
+                    self.dev
+                        .req
+                        .call_failable(WorkerRequest::MapInterrupt, (self.dev.id, interrupt))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = Vec::with_capacity(pending.len());
+        for pending in pending {
+            let result = match pending {
+                Ok(rpc) => rpc
+                    .await
+                    .map(registered_interrupt)
+                    .map_err(RegisterInterruptError::new),
+                Err(err) => Err(err),
+            };
+            results.push(result);
+        }
+        results
+    }
+
+    /// Binds the device's TDISP interface, starts the device, and fetches its
+    /// TDI report.
+    ///
+    /// The TDISP protocol must already have been negotiated with
+    /// [`TdispVirtualDeviceInterface::tdisp_get_device_interface_info`]. If
+    /// starting the device or fetching the report fails, the interface is
+    /// unbound again so that the device is not left half bound.
+    pub async fn tdisp_bring_up(&self) -> anyhow::Result<TdiReportStruct> {
+        self.tdisp_bind_interface()
+            .await
+            .context("failed to bind TDISP interface")?;
+
+        let result = async {
+            self.tdisp_start_device()
+                .await
+                .context("failed to start TDISP device")?;
+            self.tdisp_get_tdi_report().await
+        }
+        .await;
+
+        if result.is_err() {
+            if let Err(err) = self.tdisp_unbind(TdispGuestUnbindReason::Graceful).await {
+                tracing::error!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "failed to unbind TDISP interface after failed bring up"
+                );
+            }
+        }
+        result
+    }
+
     /// Reads device configuration space.
     ///
     /// Some values will be handled without communicating with the host.
@@ -452,8 +774,8 @@ pub fn read_cfg(&self, offset: u16) -> u32 {
                     | ((self.hw_ids.base_class.0 as u32) << 24)
             }
             HeaderType00::SUBSYSTEM_ID => {
-                (self.hw_ids.type0_sub_vendor_id as u32)
-                    | ((self.hw_ids.type0_sub_system_id as u32) << 16)
+                let (sub_vendor_id, sub_system_id) = self.subsystem_ids();
+                (sub_vendor_id as u32) | ((sub_system_id as u32) << 16)
             }
             HeaderType00::BAR0
             | HeaderType00::BAR1
@@ -470,12 +792,142 @@ pub fn read_cfg(&self, offset: u16) -> u32 {
             _ => self.config_space.lock().read(self.dev.id, offset),
         };
         tracing::trace!(?offset, value, "config space read");
+        self.record_access(offset, value, false);
         value
     }
 
+    /// Reads a byte of device configuration space, via [`Self::read_cfg`].
+    pub fn read_cfg_u8(&self, offset: u16) -> u8 {
+        (self.read_cfg(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Reads a word of device configuration space, via [`Self::read_cfg`].
+    ///
+    /// `offset` is rounded down to a multiple of 2.
+    pub fn read_cfg_u16(&self, offset: u16) -> u16 {
+        (self.read_cfg(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Writes a byte of device configuration space, via [`Self::write_cfg`].
+    pub fn write_cfg_u8(&self, offset: u16, value: u8) {
+        self.write_cfg_partial(offset, 0xff, value.into());
+    }
+
+    /// Writes a word of device configuration space, via [`Self::write_cfg`].
+    ///
+    /// `offset` is rounded down to a multiple of 2.
+    pub fn write_cfg_u16(&self, offset: u16, value: u16) {
+        self.write_cfg_partial(offset & !1, 0xffff, value.into());
+    }
+
+    /// Enables memory decoding in the command register, which also writes
+    /// the BARs to the device.
+    ///
+    /// Since Hyper-V does not always report the memory enable bit on reads,
+    /// the bit is confirmed against the shadowed command register, and the
+    /// device is only checked for a response. Fails if the device does not
+    /// respond, for example because it was removed.
+    pub fn enable_mmio(&self) -> anyhow::Result<()> {
+        let offset = HeaderType00::STATUS_COMMAND.0;
+        let command = Command::from(self.read_cfg_u16(offset));
+        if !command.mmio_enabled() {
+            self.write_cfg_u16(offset, command.with_mmio_enabled(true).into());
+        }
+        if self.read_cfg_raw(offset) == !0 {
+            anyhow::bail!("device did not respond after enabling memory decoding");
+        }
+        let command = Command::from(self.read_cfg_u16(offset));
+        anyhow::ensure!(command.mmio_enabled(), "memory decoding was not enabled");
+        Ok(())
+    }
+
+    /// Writes the bits of `value` selected by `mask`, both relative to
+    /// `offset`, by reading and rewriting the containing dword.
+    fn write_cfg_partial(&self, offset: u16, mask: u32, value: u32) {
+        let aligned = offset & !3;
+        let shift = (offset & 3) * 8;
+        let mask = mask << shift;
+        let mut old = self.read_cfg(aligned);
+        if HeaderType00(aligned) == HeaderType00::STATUS_COMMAND {
+            // Status bits are cleared by writing ones, so writing back the
+            // current status would clear it.
+            old &= 0xffff;
+        }
+        self.write_cfg(aligned, (old & !mask) | ((value << shift) & mask));
+    }
+
+    /// Records a config space access for inspection, evicting the oldest one
+    /// if the record is full.
+    fn record_access(&self, offset: u16, value: u32, is_write: bool) {
+        let mut recent = self.recent_accesses.lock();
+        if recent.len() == RECENT_ACCESS_COUNT {
+            recent.pop_front();
+        }
+        recent.push_back(ConfigAccess {
+            offset,
+            value,
+            is_write,
+        });
+    }
+
+    /// Reads device configuration space directly from the host, bypassing
+    /// the shadowed command register, BARs, and cached hardware IDs.
+    ///
+    /// This is for diagnosing disagreements between the host and the guest.
+    /// Use [`Self::read_cfg`] for everything else.
+    pub fn read_cfg_raw(&self, offset: u16) -> u32 {
+        let value = self.config_space.lock().read(self.dev.id, offset);
+        tracing::trace!(?offset, value, "raw config space read");
+        value
+    }
+
+    /// Returns the config space offset of the extended capability with ID
+    /// `id`, or `None` if the device does not have one.
+    fn find_extended_capability(&self, id: ExtendedCapabilityId) -> Option<u16> {
+        let mut offset = EXT_CAP_START;
+        // Bound the walk in case the host reports a cycle.
+        for _ in 0..(EXT_CAP_END - EXT_CAP_START) / 4 {
+            let header = self.read_cfg(offset);
+            if header == 0 || header == !0 {
+                break;
+            }
+            if header as u16 == id.0 {
+                return Some(offset);
+            }
+            let next = ((header >> 20) & 0xffc) as u16;
+            if next < EXT_CAP_START {
+                break;
+            }
+            offset = next;
+        }
+        None
+    }
+
+    /// Returns the device's SR-IOV parameters, or `None` if the device does
+    /// not have an SR-IOV capability.
+    pub fn sriov_info(&self) -> Option<SriovInfo> {
+        let cap_offset = self.find_extended_capability(ExtendedCapabilityId::SRIOV)?;
+        let read = |reg: SriovExtendedCapabilityHeader| self.read_cfg(cap_offset + reg.0);
+
+        let vfs = read(SriovExtendedCapabilityHeader::INITIAL_TOTAL_VFS);
+        let num_vfs = read(SriovExtendedCapabilityHeader::NUM_VFS);
+        let offset_stride = read(SriovExtendedCapabilityHeader::VF_OFFSET_STRIDE);
+        let vf_device_id = read(SriovExtendedCapabilityHeader::VF_DEVICE_ID);
+        Some(SriovInfo {
+            cap_offset,
+            initial_vfs: vfs as u16,
+            total_vfs: (vfs >> 16) as u16,
+            num_vfs: num_vfs as u16,
+            first_vf_offset: offset_stride as u16,
+            vf_stride: (offset_stride >> 16) as u16,
+            vf_device_id: (vf_device_id >> 16) as u16,
+        })
+    }
+
     /// Writes device configuration space.
     pub fn write_cfg(&self, offset: u16, value: u32) {
         tracing::trace!(?offset, value, "config space write");
+        self.record_access(offset, value, true);
         let mut shadows = self.shadows.lock();
         let shadows = &mut *shadows;
         let mut accessor = self.config_space.lock();
@@ -484,10 +936,7 @@ pub fn write_cfg(&self, offset: u16, value: u32) {
                 let new_command = Command::from(value as u16);
                 if new_command.mmio_enabled() && !shadows.command.mmio_enabled() {
                     // Flush the BAR shadow to the device.
-                    for (i, &bar) in shadows.bars.iter().enumerate() {
-                        let bar_offset = HeaderType00::BAR0.0 + (i as u16 * 4);
-                        accessor.write(self.dev.id, bar_offset, bar);
-                    }
+                    shadows.write_bars(&mut accessor, self.dev.id);
                 }
                 shadows.command = new_command;
             }
@@ -510,6 +959,24 @@ pub fn write_cfg(&self, offset: u16, value: u32) {
     }
 }
 
+/// An error handling a packet from the host.
+#[derive(Error, Debug)]
+enum PacketError {
+    /// The packet was malformed or unexpected. The packet is dropped, but the
+    /// bus keeps running.
+    #[error("invalid packet")]
+    Invalid(#[source] anyhow::Error),
+    /// The channel failed. This is fatal to the bus.
+    #[error("channel failure")]
+    Channel(#[source] anyhow::Error),
+}
+
+impl From<anyhow::Error> for PacketError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Invalid(err)
+    }
+}
+
 #[derive(Error, Debug)]
 #[error("invalid vector count: {0}")]
 struct InvalidVectorCount(u32);
@@ -522,40 +989,63 @@ pub fn write_cfg(&self, offset: u16, value: u32) {
 #[error("invalid processor number: {0}")]
 struct InvalidProcessor(u32);
 
+/// Builds the interrupt descriptor for a registration of `vector_count`
+/// vectors with `params`.
+///
+/// The worker converts this to the layout of the negotiated protocol version.
+fn msi_descriptor(
+    vector_count: u32,
+    params: &VpciInterruptParameters<'_>,
+) -> Result<protocol::MsiResourceDescriptor3, RegisterInterruptError> {
+    let mut interrupt = protocol::MsiResourceDescriptor3 {
+        vector: params.vector,
+        delivery_mode: if params.multicast {
+            protocol::DeliveryMode::LOWEST_PRIORITY
+        } else {
+            protocol::DeliveryMode::FIXED
+        },
+        vector_count: vector_count
+            .try_into()
+            .map_err(|_| RegisterInterruptError::new(InvalidVectorCount(vector_count)))?,
+        reserved: 0,
+        processor_count: 0,
+        processor_array: [0; 32],
+        reserved2: 0,
+    };
+    for (d, &s) in interrupt
+        .processor_array
+        .iter_mut()
+        .zip(params.target_processors)
+    {
+        *d = s
+            .try_into()
+            .map_err(|_| RegisterInterruptError::new(InvalidProcessor(s)))?;
+        interrupt.processor_count += 1;
+    }
+    Ok(interrupt)
+}
+
+/// Converts the host's reply to an interrupt registration.
+fn registered_interrupt(resource: protocol::MsiResourceRemapped) -> MsiAddressData {
+    tracing::debug!(
+        address = resource.address,
+        data = resource.data_payload,
+        "registered interrupt"
+    );
+
+    MsiAddressData {
+        address: resource.address,
+        data: resource.data_payload,
+    }
+}
+
 impl MapVpciInterrupt for VpciDevice {
     async fn register_interrupt(
         &self,
         vector_count: u32,
         params: &vmcore::vpci_msi::VpciInterruptParameters<'_>,
     ) -> Result<MsiAddressData, RegisterInterruptError> {
-        let mut interrupt = protocol::MsiResourceDescriptor2 {
-            // TODO: use MsiResourceDescriptor3 to support ARM64.
-            vector: params
-                .vector
-                .try_into()
-                .map_err(|_| RegisterInterruptError::new(VectorTooLarge(params.vector)))?,
-            delivery_mode: if params.multicast {
-                protocol::DeliveryMode::LOWEST_PRIORITY
-            } else {
-                protocol::DeliveryMode::FIXED
-            },
-            vector_count: vector_count
-                .try_into()
-                .map_err(|_| RegisterInterruptError::new(InvalidVectorCount(vector_count)))?,
-            processor_count: 0,
-            processor_array: [0; 32],
-            reserved: 0,
-        };
-        for (d, &s) in interrupt
-            .processor_array
-            .iter_mut()
-            .zip(params.target_processors)
-        {
-            *d = s
-                .try_into()
-                .map_err(|_| RegisterInterruptError::new(InvalidProcessor(s)))?;
-            interrupt.processor_count += 1;
-        }
+        let interrupt = msi_descriptor(vector_count, params)?;
         let resource = self
             .dev
             .req
@@ -563,16 +1053,7 @@ async fn register_interrupt(
             .await
             .map_err(RegisterInterruptError::new)?;
 
-        tracing::debug!(
-            address = resource.address,
-            data = resource.data_payload,
-            "registered interrupt"
-        );
-
-        Ok(MsiAddressData {
-            address: resource.address,
-            data: resource.data_payload,
-        })
+        Ok(registered_interrupt(resource))
     }
 
     async fn unregister_interrupt(&self, address: u64, data: u32) {
@@ -768,6 +1249,10 @@ struct VpciClientWorker<M: RingMem> {
     conn: VpciConnection<M>,
     #[inspect(flatten)]
     state: WorkerState,
+    /// Stops the worker so that the client can reconnect. `None` once the
+    /// client has been detached.
+    #[inspect(skip)]
+    stop: Option<mesh::OneshotReceiver<()>>,
 }
 
 #[derive(Inspect)]
@@ -784,22 +1269,67 @@ struct WorkerState {
     #[inspect(skip)]
     init_devices: Option<Vec<VpciDeviceDescription>>,
     #[inspect(iter_by_index)]
+    waiters: Vec<DeviceWaiter>,
+    #[inspect(iter_by_index)]
     slots: Vec<Option<SlotState>>,
     next_seq: u64,
+    #[inspect(hex)]
+    tx_id_base: u64,
+    fdo_entry_timeout: Duration,
+    #[inspect(hex)]
+    max_bar_size: u64,
+    eject_timeout: Option<Duration>,
+    /// Expire with the ID of an ejected device once its owner has had
+    /// `eject_timeout` to drop it.
+    #[inspect(skip)]
+    eject_timers: FuturesUnordered<BoxFuture<'static, DeviceId>>,
     #[inspect(skip)]
     buf: Vec<u8>,
 }
 
 #[derive(Inspect)]
+#[inspect(extra = "Self::inspect_extra")]
 struct SlotState {
     hw_ids: HardwareIds,
     serial_num: u32,
+    /// Shared with the device's description and, once initialized, the
+    /// device, so that they observe NUMA node updates from the host.
+    numa_node: Arc<AtomicU16>,
     in_use: bool,
     removed: bool,
     ejected: bool,
     #[inspect(skip)]
     eject: mesh::Sender<VpciDeviceEjected>,
     seq: u64,
+    /// The device's shadowed config space, while the device is in use.
+    #[inspect(skip)]
+    shadows: Option<Arc<Mutex<ConfigSpaceShadows>>>,
+}
+
+impl SlotState {
+    /// Notifies the device's owner that the host removed the device from the
+    /// bus, unless it was ejected first.
+    fn notify_removed(&self) {
+        if !self.ejected {
+            self.eject.send(VpciDeviceEjected {
+                kind: RemovalKind::SurpriseRemove,
+            });
+        }
+    }
+
+    /// Summarizes whether the device is usable, and if not, why.
+    fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
+        let state = if self.removed {
+            "removed"
+        } else if self.ejected {
+            "ejected"
+        } else if self.in_use {
+            "in_use"
+        } else {
+            "available"
+        };
+        resp.field("state", state);
+    }
 }
 
 #[derive(Inspect)]
@@ -817,6 +1347,21 @@ enum Tx {
     TdispCommand(#[inspect(skip)] FailableRpc<(), GuestToHostResponse>),
 }
 
+impl Tx {
+    /// Fails the transaction without sending it to the host.
+    fn fail(self, err: anyhow::Error) {
+        match self {
+            // Dropping the sender fails the receiver.
+            Tx::FdoD0Entry(_) => {}
+            Tx::CreateInterrupt(rpc) => rpc.fail(err),
+            Tx::DeleteInterrupt(rpc) => rpc.fail(err),
+            Tx::QueryResourceRequirements(rpc) => rpc.fail(err),
+            Tx::AssignedResources(rpc) => rpc.fail(err),
+            Tx::TdispCommand(rpc) => rpc.fail(err),
+        }
+    }
+}
+
 impl VpciClient {
     /// Instantiates a new VPCI client, connecting to the VPCI bus avilable via
     /// `channel`. Returns the initial set of devices available on the bus.
@@ -825,11 +1370,33 @@ impl VpciClient {
     /// configuration space. `devices` will receive dynamically added devices as
     /// they are added to the bus.
     pub async fn connect<M: 'static + RingMem + Sync>(
+        driver: impl Spawn,
+        channel: RawAsyncChannel<M>,
+        mmio: Box<dyn MemoryAccess>,
+        devices: mesh::Sender<VpciDeviceDescription>,
+    ) -> anyhow::Result<(Self, Vec<VpciDeviceDescription>)> {
+        Self::connect_with_options(driver, channel, mmio, devices, VpciClientOptions::default())
+            .await
+    }
+
+    /// Like [`Self::connect`], but with non-default `options`.
+    pub async fn connect_with_options<M: 'static + RingMem + Sync>(
         driver: impl Spawn,
         channel: RawAsyncChannel<M>,
         mut mmio: Box<dyn MemoryAccess>,
         devices: mesh::Sender<VpciDeviceDescription>,
+        options: VpciClientOptions,
     ) -> anyhow::Result<(Self, Vec<VpciDeviceDescription>)> {
+        let VpciClientOptions {
+            tx_id_base,
+            fdo_entry_timeout,
+            max_bar_size,
+            eject_timeout,
+        } = options;
+        if tx_id_base == 0 {
+            anyhow::bail!("transaction ID base must be nonzero");
+        }
+
         let mut conn = VpciConnection {
             queue: Queue::new(channel)?,
         };
@@ -844,31 +1411,13 @@ pub async fn connect<M: 'static + RingMem + Sync>(
         tracing::debug!(gpa, "requesting fdo d0 entry");
 
         let mut tx = slab::Slab::new();
-
-        // Start a transaction to move the bus to the D0 state. The completion
-        // may come after the device list, so start the task and wait for the
-        // reply afterwards.
-        let (fdo_entry_send, fdo_entry_recv) = mesh::oneshot();
-        let tx_id = index_to_tx_id(tx.insert(Tx::FdoD0Entry(fdo_entry_send)));
-        conn.queue
-            .split()
-            .1
-            .write(OutgoingPacket {
-                transaction_id: tx_id,
-                packet_type: vmbus_ring::OutgoingPacketType::InBandWithCompletion,
-                payload: &[protocol::FdoD0Entry {
-                    message_type: protocol::MessageType::FDO_D0_ENTRY,
-                    padding: 0,
-                    mmio_start: gpa,
-                }
-                .as_bytes()],
-            })
-            .await
-            .context("failed to send FDO D0 entry")?;
+        let fdo_entry_recv = conn.send_fdo_d0_entry(&mut tx, tx_id_base, gpa).await?;
 
         let (req_send, req_recv) = mesh::channel();
+        let (stop_send, stop_recv) = mesh::oneshot();
         let worker = VpciClientWorker {
             conn,
+            stop: Some(stop_recv),
             state: WorkerState {
                 tx,
                 req: req_recv,
@@ -882,16 +1431,30 @@ pub async fn connect<M: 'static + RingMem + Sync>(
                     slot_seq: Vec::new(),
                 })),
                 init_devices: Some(Vec::new()),
+                waiters: Vec::new(),
                 slots: Vec::new(),
                 next_seq: 1,
+                tx_id_base,
+                fdo_entry_timeout,
+                max_bar_size,
+                eject_timeout,
+                eject_timers: FuturesUnordered::new(),
                 buf: vec![0; protocol::MAXIMUM_PACKET_SIZE],
             },
         };
 
         let task = driver.spawn("vpci-client", worker.run());
-        let r = fdo_entry_recv
+        let r = match mesh::CancelContext::new()
+            .with_timeout(fdo_entry_timeout)
+            .until_cancelled(fdo_entry_recv)
             .await
-            .context("no response to FDO D0 entry")?;
+        {
+            Ok(r) => r.context("no response to FDO D0 entry")?,
+            Err(_) => {
+                task.cancel().await;
+                anyhow::bail!("timed out after {fdo_entry_timeout:?} waiting for FDO D0 entry");
+            }
+        };
 
         let init_devices = match r {
             Ok(v) => v,
@@ -906,11 +1469,184 @@ pub async fn connect<M: 'static + RingMem + Sync>(
         let this = Self {
             req: req_send,
             task,
+            stop: stop_send,
         };
 
         Ok((this, init_devices))
     }
 
+    /// Reconnects to the VPCI bus over a new `channel`, after the previous
+    /// channel was reset.
+    ///
+    /// This stops the worker and closes the previous channel, if that has not
+    /// already happened due to the failure of the channel, then renegotiates
+    /// the protocol version and moves the bus back to the D0 state. The new
+    /// device list is reconciled against the devices known before the reset:
+    /// devices that are still present keep their existing handles, and those
+    /// that were initialized are re-enabled, with their BARs and command
+    /// register restored if memory decoding was enabled; devices that vanished
+    /// are removed, just as when the host removes them from the bus. Returns
+    /// the devices that are new to the bus.
+    pub async fn reconnect<M: 'static + RingMem + Sync>(
+        self,
+        driver: impl Spawn,
+        channel: RawAsyncChannel<M>,
+    ) -> anyhow::Result<(Self, Vec<VpciDeviceDescription>)> {
+        let Self { req, task, stop } = self;
+        stop.send(());
+        let mut state = task.await;
+
+        // Transactions sent on the old channel will never complete. Dropping
+        // them fails the pending requests.
+        state.tx.clear();
+        state.init_devices = Some(Vec::new());
+        let gpa = {
+            let mut config_space = state.config_space.lock();
+            // Don't assume the host preserved the slot selection.
+            config_space.current_slot = (!0).into();
+            config_space.base_gpa
+        };
+        let in_use = state
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot_index, slot)| {
+                let slot = slot.as_ref()?;
+                let id = DeviceId {
+                    slot: (slot_index as u32).into(),
+                    seq: slot.seq,
+                };
+                Some((id, slot.shadows.clone()?))
+            })
+            .collect::<Vec<_>>();
+        let config_space = state.config_space.clone();
+
+        let mut conn = VpciConnection {
+            queue: Queue::new(channel)?,
+        };
+
+        state.protocol_version = conn
+            .negotiate()
+            .await
+            .context("failed to negotiate protocol version")?;
+
+        tracing::debug!(gpa, "requesting fdo d0 entry after reconnect");
+
+        let fdo_entry_recv = conn
+            .send_fdo_d0_entry(&mut state.tx, state.tx_id_base, gpa)
+            .await?;
+
+        let fdo_entry_timeout = state.fdo_entry_timeout;
+        let (stop_send, stop_recv) = mesh::oneshot();
+        let task = driver.spawn(
+            "vpci-client",
+            VpciClientWorker {
+                conn,
+                state,
+                stop: Some(stop_recv),
+            }
+            .run(),
+        );
+        let r = match mesh::CancelContext::new()
+            .with_timeout(fdo_entry_timeout)
+            .until_cancelled(fdo_entry_recv)
+            .await
+        {
+            Ok(r) => r.context("no response to FDO D0 entry")?,
+            Err(_) => {
+                task.cancel().await;
+                anyhow::bail!("timed out after {fdo_entry_timeout:?} waiting for FDO D0 entry");
+            }
+        };
+
+        let new_devices = match r {
+            Ok(v) => v,
+            Err(status) => {
+                task.cancel().await;
+                anyhow::bail!("failed to enter D0 state: {:#x?}", status);
+            }
+        };
+
+        // Re-enable the devices that were in use before the reset. This fails
+        // for any that are no longer on the bus.
+        for (id, shadows) in in_use {
+            if let Err(err) = req
+                .call_failable(WorkerRequest::Init, (id, shadows.clone()))
+                .await
+            {
+                tracing::debug!(
+                    ?id,
+                    error = &err as &dyn std::error::Error,
+                    "device not re-enabled after reconnect"
+                );
+                continue;
+            }
+            // The host does not preserve the device's config space across the
+            // reset, so restore it if the device was decoding memory.
+            let shadows = shadows.lock();
+            if shadows.command.mmio_enabled() {
+                let mut accessor = config_space.lock();
+                shadows.write_bars(&mut accessor, id);
+                accessor.write(
+                    id,
+                    HeaderType00::STATUS_COMMAND.0,
+                    u16::from(shadows.command).into(),
+                );
+            }
+        }
+
+        tracing::debug!(gpa, "reconnect successful");
+
+        Ok((
+            Self {
+                req,
+                task,
+                stop: stop_send,
+            },
+            new_devices,
+        ))
+    }
+
+    /// Waits for a device matching any of the (vendor ID, device ID) pairs in
+    /// `hw_ids` to be added to the bus, failing if none appears within
+    /// `timeout`.
+    ///
+    /// Only devices added after this call are considered. A matching device is
+    /// returned here instead of being sent to the `devices` sender passed to
+    /// [`Self::connect`].
+    pub async fn wait_for_device(
+        &self,
+        hw_ids: &[(u16, u16)],
+        timeout: Duration,
+    ) -> anyhow::Result<VpciDeviceDescription> {
+        let (send, recv) = mesh::oneshot();
+        self.req.send(WorkerRequest::WaitForDevice(DeviceWaiter {
+            hw_ids: hw_ids.to_vec(),
+            send,
+        }));
+        let device = mesh::CancelContext::new()
+            .with_timeout(timeout)
+            .until_cancelled(recv)
+            .await
+            .context("timed out waiting for device")?
+            .context("vpci client worker is gone")?;
+        Ok(device)
+    }
+
+    /// Asks the worker to hand out the devices on the bus again, for example
+    /// if the caller suspects that it missed a device being added.
+    ///
+    /// Each device that is not in use or being ejected is sent again on the
+    /// `devices` sender passed to [`Self::connect`] (or to a matching
+    /// [`Self::wait_for_device`] call). Descriptions of these devices that were
+    /// handed out earlier can no longer be initialized.
+    pub async fn rescan(&self) -> anyhow::Result<()> {
+        self.req
+            .call(WorkerRequest::Rescan, ())
+            .await
+            .context("vpci client worker is gone")
+    }
+
     /// Shuts down the VPCI bus client.
     pub async fn shutdown(self) {
         drop(self.req);
@@ -924,13 +1660,16 @@ pub fn detach(self) {
 }
 
 impl<M: RingMem> VpciClientWorker<M> {
-    async fn run(mut self) {
+    /// Runs the worker, returning its state so that the client can reconnect
+    /// over a new channel.
+    async fn run(mut self) -> WorkerState {
         if let Err(err) = self.run_inner().await {
             tracing::error!(
                 error = err.as_ref() as &dyn std::error::Error,
                 "vpci client worker failed"
             );
         }
+        self.state
     }
 
     async fn run_inner(&mut self) -> anyhow::Result<()> {
@@ -940,27 +1679,68 @@ async fn run_inner(&mut self) -> anyhow::Result<()> {
                 enum Event<T, U> {
                     Packet(T),
                     Request(U),
+                    EjectTimeout(DeviceId),
+                    Stop(bool),
                 }
 
                 let read_packet = read.read().map(Event::Packet);
                 let req = self.state.req.next().map(Event::Request);
+                let eject_timers = &mut self.state.eject_timers;
+                let eject_timeout = async {
+                    match eject_timers.next().await {
+                        Some(id) => Event::EjectTimeout(id),
+                        None => std::future::pending().await,
+                    }
+                };
+                let stop = &mut self.stop;
+                let stop = async {
+                    match stop {
+                        Some(stop) => Event::Stop(stop.await.is_ok()),
+                        None => std::future::pending().await,
+                    }
+                };
 
-                let event = (read_packet, req).race().await;
+                let event = (read_packet, req, eject_timeout, stop).race().await;
                 match event {
                     Event::Packet(p) => {
                         let p = p.context("failed to read packet")?;
                         match &*p {
                             IncomingPacket::Data(p) => {
-                                self.state.handle_packet(&mut write, p).await?;
+                                match self.state.handle_packet(&mut write, p).await {
+                                    Ok(()) => {}
+                                    Err(PacketError::Invalid(err)) => {
+                                        tracelimit::warn_ratelimited!(
+                                            error = err.as_ref() as &dyn std::error::Error,
+                                            "dropping invalid packet"
+                                        );
+                                    }
+                                    Err(PacketError::Channel(err)) => return Err(err),
+                                }
                             }
                             IncomingPacket::Completion(p) => {
-                                self.state.handle_completion(p)?;
+                                if let Err(err) = self.state.handle_completion(p) {
+                                    tracelimit::warn_ratelimited!(
+                                        error = err.as_ref() as &dyn std::error::Error,
+                                        "dropping invalid completion"
+                                    );
+                                }
                             }
                         }
                         None
                     }
                     Event::Request(Some(req)) => self.state.handle_req(&mut write, req).await?,
                     Event::Request(None) => break,
+                    Event::EjectTimeout(id) => {
+                        self.state.handle_eject_timeout(&mut write, id).await?;
+                        None
+                    }
+                    Event::Stop(true) => break,
+                    Event::Stop(false) => {
+                        // The client was detached, so it can no longer
+                        // reconnect.
+                        self.stop = None;
+                        None
+                    }
                 }
             };
             if let Some(deferred) = deferred {
@@ -982,15 +1762,46 @@ fn slot_mut(&mut self, id: DeviceId) -> Option<&mut SlotState> {
         Some(slot)
     }
 
+    /// Completes the eject of device `id` if its owner has not dropped it
+    /// within the eject timeout.
+    ///
+    /// The device is cut off from the bus: its config space reads as all
+    /// ones, and dropping it later has no effect.
+    async fn handle_eject_timeout<M: RingMem>(
+        &mut self,
+        write: &mut vmbus_async::queue::WriteHalf<'_, M>,
+        id: DeviceId,
+    ) -> anyhow::Result<()> {
+        let seq = self.next_seq;
+        let Some(slot) = self.slot_mut(id) else {
+            return Ok(());
+        };
+        if !slot.in_use {
+            return Ok(());
+        }
+        tracing::warn!(
+            ?id,
+            "ejected device was not released in time, completing eject"
+        );
+        slot.in_use = false;
+        slot.shadows = None;
+        // Orphan the device so that its eventual release does not complete
+        // the eject again.
+        slot.seq = seq;
+        self.next_seq += 1;
+        self.config_space.lock().disable_slot(id.slot);
+        send_eject_complete(write, id.slot).await
+    }
+
     async fn handle_packet<M: RingMem>(
         &mut self,
         write: &mut vmbus_async::queue::WriteHalf<'_, M>,
         p: &vmbus_async::queue::DataPacket<'_, M>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), PacketError> {
         let mut reader = p.reader();
         let len = reader.len();
         let buf = self.buf.get_mut(..len).context("packet too large")?;
-        reader.read(buf)?;
+        reader.read(buf).context("failed to read packet")?;
 
         let (packet_type, _) = protocol::MessageType::read_from_prefix(buf)
             .ok()
@@ -999,95 +1810,27 @@ async fn handle_packet<M: RingMem>(
         tracing::debug!(?packet_type, "received packet");
 
         match packet_type {
-            protocol::MessageType::BUS_RELATIONS2 => {
-                let (bus_relations, devices) = protocol::QueryBusRelations2::read_from_prefix(buf)
-                    .ok()
-                    .context("failed to read bus relations")?;
-
-                let (devices, _) =
-                    <[Unalign<protocol::DeviceDescription2>]>::ref_from_prefix_with_elems(
-                        devices,
-                        bus_relations.device_count as usize,
-                    )
-                    .ok()
-                    .context("failed to read bus relation devices")?;
-
-                for slot in self.slots.iter_mut().flatten() {
-                    slot.removed = true;
-                }
-
-                for device in devices {
-                    let device = device.get();
-                    let slot_index = u32::from(device.slot) as usize;
-                    if slot_index >= u8::MAX as usize {
-                        anyhow::bail!("invalid slot index {slot_index}");
-                    }
-                    if let Some(Some(slot)) = self.slots.get_mut(slot_index) {
-                        if slot.hw_ids.device_id == device.pnp_id.device_id
-                            && slot.hw_ids.vendor_id == device.pnp_id.vendor_id
-                            && slot.serial_num == device.serial_num
-                        {
-                            slot.removed = false;
-                            continue;
-                        }
-                        self.slots[slot_index] = None;
-                    }
-
-                    let hw_ids = HardwareIds {
-                        vendor_id: device.pnp_id.vendor_id,
-                        device_id: device.pnp_id.device_id,
-                        revision_id: device.pnp_id.revision_id,
-                        prog_if: device.pnp_id.prog_if.into(),
-                        sub_class: device.pnp_id.sub_class.into(),
-                        base_class: device.pnp_id.base_class.into(),
-                        type0_sub_vendor_id: device.pnp_id.sub_vendor_id,
-                        type0_sub_system_id: device.pnp_id.sub_system_id,
-                    };
-
-                    if slot_index >= self.slots.len() {
-                        self.slots.resize_with(slot_index + 1, || None);
-                    }
-                    let seq = self.next_seq;
-                    self.next_seq += 1;
-                    let (eject_send, eject_recv) = mesh::channel();
-                    self.slots[slot_index] = Some(SlotState {
-                        hw_ids,
+            protocol::MessageType::BUS_RELATIONS
+                if self.protocol_version < protocol::ProtocolVersion::VB =>
+            {
+                let devices = read_bus_relations::<protocol::DeviceDescription>(buf)?
+                    .into_iter()
+                    .map(|device| protocol::DeviceDescription2 {
+                        pnp_id: device.pnp_id,
+                        slot: device.slot,
                         serial_num: device.serial_num,
-                        removed: false,
-                        ejected: false,
-                        eject: eject_send,
-                        in_use: false,
-                        seq,
-                    });
-                    let vpci_device = VpciDeviceDescription {
-                        hw_ids,
-                        config_space: self.config_space.clone(),
-                        id: DeviceId {
-                            slot: device.slot,
-                            seq,
-                        },
-                        numa_node: device.numa_node,
-                        serial_num: device.serial_num,
-                        req: self.req.sender(),
-                        eject: eject_recv,
-                    };
-                    if let Some(init_devices) = &mut self.init_devices {
-                        init_devices.push(vpci_device);
-                    } else {
-                        self.send_devices.send(vpci_device);
-                    }
-                }
-
-                for (slot_index, slot_slot) in self.slots.iter_mut().enumerate() {
-                    let Some(slot) = slot_slot else { continue };
-                    if !slot.removed {
-                        continue;
-                    }
-                    self.config_space
-                        .lock()
-                        .disable_slot((slot_index as u32).into());
-                    *slot_slot = None;
-                }
+                        flags: protocol::DeviceDescription2Flags::new(),
+                        numa_node: 0,
+                        rsvd: 0,
+                    })
+                    .collect::<Vec<_>>();
+                self.handle_bus_relations(&devices)?;
+            }
+            protocol::MessageType::BUS_RELATIONS2
+                if self.protocol_version >= protocol::ProtocolVersion::VB =>
+            {
+                let devices = read_bus_relations::<protocol::DeviceDescription2>(buf)?;
+                self.handle_bus_relations(&devices)?;
             }
             protocol::MessageType::EJECT => {
                 let (eject, _) = protocol::PdoMessage::read_from_prefix(buf)
@@ -1095,33 +1838,215 @@ async fn handle_packet<M: RingMem>(
                     .context("failed to read eject packet")?;
                 let slot_index = u32::from(eject.slot) as usize;
                 let Some(Some(slot)) = self.slots.get_mut(slot_index) else {
-                    anyhow::bail!("eject packet for unknown slot {slot_index}");
+                    return Err(
+                        anyhow::anyhow!("eject packet for unknown slot {slot_index}").into(),
+                    );
                 };
                 if !std::mem::replace(&mut slot.ejected, true) {
                     if slot.in_use {
-                        slot.eject.send(VpciDeviceEjected);
+                        slot.eject.send(VpciDeviceEjected {
+                            kind: RemovalKind::Eject,
+                        });
+                        if let Some(timeout) = self.eject_timeout {
+                            let id = DeviceId {
+                                slot: eject.slot,
+                                seq: slot.seq,
+                            };
+                            self.eject_timers.push(Box::pin(async move {
+                                mesh::CancelContext::new()
+                                    .with_timeout(timeout)
+                                    .cancelled()
+                                    .await;
+                                id
+                            }));
+                        }
                     } else {
-                        send_eject_complete(write, eject.slot).await?;
+                        send_eject_complete(write, eject.slot)
+                            .await
+                            .map_err(PacketError::Channel)?;
                     }
                 } else {
                     tracing::warn!("eject packet for device that is already ejected");
                 }
             }
             p => {
-                anyhow::bail!("unexpected packet type: {:?}", p);
+                return Err(anyhow::anyhow!("unexpected packet type: {:?}", p).into());
             }
         }
         Ok(())
     }
 
+    /// Updates the slots to match the devices reported in a bus relations
+    /// message.
+    fn handle_bus_relations(
+        &mut self,
+        devices: &[protocol::DeviceDescription2],
+    ) -> anyhow::Result<()> {
+        // Validate the whole message before updating any state so that
+        // an invalid message can be dropped.
+        if let Some(device) = devices
+            .iter()
+            .find(|device| u32::from(device.slot) as usize >= u8::MAX as usize)
+        {
+            anyhow::bail!("invalid slot index {}", u32::from(device.slot));
+        }
+
+        for slot in self.slots.iter_mut().flatten() {
+            slot.removed = true;
+        }
+
+        for device in devices {
+            let slot_index = u32::from(device.slot) as usize;
+            if let Some(Some(slot)) = self.slots.get_mut(slot_index) {
+                if slot.hw_ids.device_id == device.pnp_id.device_id
+                    && slot.hw_ids.vendor_id == device.pnp_id.vendor_id
+                    && slot.serial_num == device.serial_num
+                {
+                    slot.removed = false;
+                    // The host may move the device to a different NUMA
+                    // node, for example after rebalancing.
+                    let old_numa_node = slot.numa_node.swap(device.numa_node, Ordering::Relaxed);
+                    if old_numa_node != device.numa_node {
+                        tracing::info!(
+                            slot_index,
+                            old_numa_node,
+                            new_numa_node = device.numa_node,
+                            "device numa node changed"
+                        );
+                    }
+                    continue;
+                }
+                // A different device replaced this one. Cut off the old
+                // device's config space access, since the slot now belongs to
+                // the new device.
+                slot.notify_removed();
+                self.config_space
+                    .lock()
+                    .disable_slot((slot_index as u32).into());
+                self.slots[slot_index] = None;
+            }
+
+            let hw_ids = HardwareIds {
+                vendor_id: device.pnp_id.vendor_id,
+                device_id: device.pnp_id.device_id,
+                revision_id: device.pnp_id.revision_id,
+                prog_if: device.pnp_id.prog_if.into(),
+                sub_class: device.pnp_id.sub_class.into(),
+                base_class: device.pnp_id.base_class.into(),
+                type0_sub_vendor_id: device.pnp_id.sub_vendor_id,
+                type0_sub_system_id: device.pnp_id.sub_system_id,
+            };
+
+            if slot_index >= self.slots.len() {
+                self.slots.resize_with(slot_index + 1, || None);
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let (eject_send, eject_recv) = mesh::channel();
+            let numa_node = Arc::new(AtomicU16::new(device.numa_node));
+            self.slots[slot_index] = Some(SlotState {
+                hw_ids,
+                serial_num: device.serial_num,
+                numa_node: numa_node.clone(),
+                removed: false,
+                ejected: false,
+                eject: eject_send,
+                in_use: false,
+                seq,
+                shadows: None,
+            });
+            let vpci_device = VpciDeviceDescription {
+                hw_ids,
+                config_space: self.config_space.clone(),
+                id: DeviceId {
+                    slot: device.slot,
+                    seq,
+                },
+                numa_node,
+                serial_num: device.serial_num,
+                req: self.req.sender(),
+                eject: eject_recv,
+                max_bar_size: self.max_bar_size,
+            };
+            self.offer_device(vpci_device);
+        }
+
+        for (slot_index, slot_slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot_slot else { continue };
+            if !slot.removed {
+                continue;
+            }
+            slot.notify_removed();
+            self.config_space
+                .lock()
+                .disable_slot((slot_index as u32).into());
+            *slot_slot = None;
+        }
+        Ok(())
+    }
+
+    /// Hands out a newly created device description.
+    fn offer_device(&mut self, vpci_device: VpciDeviceDescription) {
+        if let Some(init_devices) = &mut self.init_devices {
+            init_devices.push(vpci_device);
+        } else {
+            // Hand the device to the first waiter that wants it, skipping any
+            // that have timed out.
+            self.waiters.retain(|waiter| !waiter.send.is_closed());
+            if let Some(index) = self
+                .waiters
+                .iter()
+                .position(|waiter| waiter.matches(&vpci_device.hw_ids))
+            {
+                self.waiters.remove(index).send.send(vpci_device);
+            } else {
+                self.send_devices.send(vpci_device);
+            }
+        }
+    }
+
+    /// Hands out a new description of each device on the bus that is not in
+    /// use or being ejected.
+    ///
+    /// Each new description supersedes any that was handed out before for the
+    /// same device, so that only one of them can be used to initialize it.
+    fn rescan(&mut self) {
+        for slot_index in 0..self.slots.len() {
+            let Some(slot) = &mut self.slots[slot_index] else {
+                continue;
+            };
+            if slot.in_use || slot.ejected {
+                continue;
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let (eject_send, eject_recv) = mesh::channel();
+            slot.seq = seq;
+            slot.eject = eject_send;
+            let vpci_device = VpciDeviceDescription {
+                hw_ids: slot.hw_ids,
+                config_space: self.config_space.clone(),
+                id: DeviceId {
+                    slot: (slot_index as u32).into(),
+                    seq,
+                },
+                numa_node: slot.numa_node.clone(),
+                serial_num: slot.serial_num,
+                req: self.req.sender(),
+                eject: eject_recv,
+                max_bar_size: self.max_bar_size,
+            };
+            self.offer_device(vpci_device);
+        }
+    }
+
     fn handle_completion<M: RingMem>(
         &mut self,
         p: &vmbus_async::queue::CompletionPacket<'_, M>,
     ) -> Result<(), anyhow::Error> {
         let tx_id = p.transaction_id();
-        let entry = self
-            .tx
-            .try_remove(tx_id_to_index(tx_id))
+        let entry = tx_id_to_index(self.tx_id_base, tx_id)
+            .and_then(|index| self.tx.try_remove(index))
             .context("failed to find tx entry")?;
         let status = p
             .reader()
@@ -1247,18 +2172,78 @@ async fn handle_req<M: RingMem>(
                     reply.fail(anyhow::anyhow!("device is gone"));
                     return Ok(None);
                 }
-                self.send_tx(
-                    write,
-                    Tx::CreateInterrupt(reply),
-                    vpci_protocol::CreateInterrupt2 {
-                        message_type: protocol::MessageType::CREATE_INTERRUPT2,
-                        slot: id.slot,
-                        interrupt,
-                    },
-                    &[],
-                )
-                .await
-                .context("failed to send create interrupt message")?;
+                if self.protocol_version >= protocol::ProtocolVersion::FE {
+                    self.send_tx(
+                        write,
+                        Tx::CreateInterrupt(reply),
+                        vpci_protocol::CreateInterrupt3 {
+                            message_type: protocol::MessageType::CREATE_INTERRUPT3,
+                            slot: id.slot,
+                            interrupt,
+                        },
+                        &[],
+                    )
+                    .await
+                    .context("failed to send create interrupt message")?;
+                    return Ok(None);
+                }
+
+                // Older versions only have room for an 8-bit vector.
+                let Ok(vector) = u8::try_from(interrupt.vector) else {
+                    reply.fail(VectorTooLarge(interrupt.vector));
+                    return Ok(None);
+                };
+                if self.protocol_version >= protocol::ProtocolVersion::RS1 {
+                    self.send_tx(
+                        write,
+                        Tx::CreateInterrupt(reply),
+                        vpci_protocol::CreateInterrupt2 {
+                            message_type: protocol::MessageType::CREATE_INTERRUPT2,
+                            slot: id.slot,
+                            interrupt: protocol::MsiResourceDescriptor2 {
+                                vector,
+                                delivery_mode: interrupt.delivery_mode,
+                                vector_count: interrupt.vector_count,
+                                processor_count: interrupt.processor_count,
+                                processor_array: interrupt.processor_array,
+                                reserved: 0,
+                            },
+                        },
+                        &[],
+                    )
+                    .await
+                    .context("failed to send create interrupt message")?;
+                } else {
+                    // Older hosts take the target processors as a mask.
+                    let mut processor_mask = 0u64;
+                    for &processor in
+                        &interrupt.processor_array[..interrupt.processor_count as usize]
+                    {
+                        let Some(bit) = 1u64.checked_shl(processor.into()) else {
+                            reply.fail(InvalidProcessor(processor.into()));
+                            return Ok(None);
+                        };
+                        processor_mask |= bit;
+                    }
+                    self.send_tx(
+                        write,
+                        Tx::CreateInterrupt(reply),
+                        vpci_protocol::CreateInterrupt {
+                            message_type: protocol::MessageType::CREATE_INTERRUPT,
+                            slot: id.slot,
+                            interrupt: protocol::MsiResourceDescriptor {
+                                vector,
+                                delivery_mode: interrupt.delivery_mode,
+                                vector_count: interrupt.vector_count,
+                                reserved: [0; 2],
+                                processor_mask,
+                            },
+                        },
+                        &[],
+                    )
+                    .await
+                    .context("failed to send create interrupt message")?;
+                }
             }
             WorkerRequest::UnmapInterrupt(rpc) => {
                 let ((id, interrupt), reply) = rpc.split();
@@ -1280,12 +2265,13 @@ async fn handle_req<M: RingMem>(
                 .context("failed to send delete interrupt message")?;
             }
             WorkerRequest::Init(rpc) => {
-                let (id, reply) = rpc.split();
+                let ((id, shadows), reply) = rpc.split();
                 let Some(slot) = self.slot_mut(id) else {
                     reply.fail(anyhow::anyhow!("device is gone"));
                     return Ok(None);
                 };
                 slot.in_use = true;
+                slot.shadows = Some(shadows);
                 self.config_space.lock().enable_slot(id);
                 // Send space for one resource to satisfy the Hyper-V implementation.
                 self.send_tx(
@@ -1324,6 +2310,7 @@ async fn handle_req<M: RingMem>(
                     return Ok(None);
                 };
                 slot.in_use = false;
+                slot.shadows = None;
                 if slot.ejected {
                     send_eject_complete(write, id.slot).await?;
                 }
@@ -1339,6 +2326,10 @@ async fn handle_req<M: RingMem>(
                 .await
                 .context("failed to send tdisp command message")?;
             }
+            WorkerRequest::WaitForDevice(waiter) => {
+                self.waiters.push(waiter);
+            }
+            WorkerRequest::Rescan(rpc) => rpc.handle_sync(|()| self.rescan()),
         }
         Ok(None)
     }
@@ -1351,7 +2342,14 @@ async fn send_tx<S: IntoBytes + Immutable, M: RingMem>(
         extra: &[u8],
     ) -> anyhow::Result<()> {
         let entry = self.tx.vacant_entry();
-        let tx_id = index_to_tx_id(entry.key());
+        let tx_id = match index_to_tx_id(self.tx_id_base, entry.key()) {
+            Ok(tx_id) => tx_id,
+            Err(err) => {
+                // Fail just this request rather than the whole bus.
+                tx.fail(err);
+                return Ok(());
+            }
+        };
         tracing::trace!(
             tx_id,
             message = std::any::type_name_of_val(&msg),
@@ -1371,11 +2369,42 @@ async fn send_tx<S: IntoBytes + Immutable, M: RingMem>(
     }
 }
 
-fn index_to_tx_id(index: usize) -> u64 {
-    // Hyper-V VPCI doesn't like transaction IDs of 0, so we start at 1.
-    (index + 1) as u64
+/// Reads the devices from a bus relations message.
+///
+/// [`protocol::QueryBusRelations`] and [`protocol::QueryBusRelations2`] share
+/// the same header layout and differ only in the device description type `T`.
+fn read_bus_relations<T: FromBytes + Immutable + KnownLayout + Copy>(
+    buf: &[u8],
+) -> anyhow::Result<Vec<T>> {
+    let (bus_relations, devices) = protocol::QueryBusRelations::read_from_prefix(buf)
+        .ok()
+        .context("failed to read bus relations")?;
+
+    // Don't trust the host's device count.
+    let max_devices = devices.len() / size_of::<T>();
+    if bus_relations.device_count as usize > max_devices {
+        anyhow::bail!(
+            "bus relations device count {} exceeds the {} devices in the packet",
+            bus_relations.device_count,
+            max_devices
+        );
+    }
+
+    let (devices, _) =
+        <[Unalign<T>]>::ref_from_prefix_with_elems(devices, bus_relations.device_count as usize)
+            .ok()
+            .context("failed to read bus relation devices")?;
+
+    Ok(devices.iter().map(|device| device.get()).collect())
+}
+
+fn index_to_tx_id(base: u64, index: usize) -> anyhow::Result<u64> {
+    // Hyper-V VPCI doesn't like transaction IDs of 0, so the base is always
+    // at least 1.
+    base.checked_add(index as u64)
+        .context("out of transaction IDs")
 }
 
-fn tx_id_to_index(tx_id: u64) -> usize {
-    tx_id.saturating_sub(1) as usize
+fn tx_id_to_index(base: u64, tx_id: u64) -> Option<usize> {
+    tx_id.checked_sub(base)?.try_into().ok()
 }
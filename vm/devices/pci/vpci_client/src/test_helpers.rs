@@ -0,0 +1,117 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers for testing VPCI device consumers without a VPCI bus.
+
+use crate::ConfigSpaceAccessor;
+use crate::ConfigSpaceShadows;
+use crate::DeviceId;
+use crate::InUseDevice;
+use crate::MemoryAccess;
+use crate::RECENT_ACCESS_COUNT;
+use crate::VpciDevice;
+use crate::bar_rao;
+use parking_lot::Mutex;
+use pci_core::spec::cfg_space::Command;
+use pci_core::spec::hwid::HardwareIds;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU16;
+use vpci_protocol as protocol;
+
+/// The MMIO base address reported by [`TestConfigSpace`].
+const TEST_GPA: u64 = 0x1_0000_0000;
+
+/// The size of a device's config space.
+const CONFIG_SPACE_SIZE: usize = 0x1000;
+
+/// Creates an initialized device with `hw_ids` and BAR masks `bar_masks`,
+/// whose config space is accessed through `mmio` as if it were the only device
+/// on a bus.
+///
+/// There is no host, so operations that need one, such as registering
+/// interrupts, fail.
+pub fn test_device(
+    mut mmio: Box<dyn MemoryAccess>,
+    hw_ids: HardwareIds,
+    bar_masks: [u32; 6],
+) -> anyhow::Result<VpciDevice> {
+    let bar_rao = bar_rao(&bar_masks, u64::MAX)?;
+    let id = DeviceId {
+        slot: 0.into(),
+        seq: 1,
+    };
+    let mut config_space = ConfigSpaceAccessor {
+        base_gpa: mmio.gpa(),
+        mem: mmio,
+        current_slot: (!0).into(),
+        slot_seq: Vec::new(),
+    };
+    config_space.enable_slot(id);
+    Ok(VpciDevice {
+        hw_ids,
+        config_space: Arc::new(Mutex::new(config_space)),
+        numa_node: Arc::new(AtomicU16::new(0)),
+        serial_num: 0,
+        dev: InUseDevice {
+            req: mesh::channel().0,
+            id,
+        },
+        shadows: Arc::new(Mutex::new(ConfigSpaceShadows {
+            command: Command::new(),
+            bars: [0; 6],
+        })),
+        bar_masks,
+        bar_rao,
+        recent_accesses: Mutex::new(VecDeque::with_capacity(RECENT_ACCESS_COUNT)),
+    })
+}
+
+/// A [`MemoryAccess`] that backs the config space of a single device with
+/// memory, for use with [`test_device`].
+///
+/// Clones share the same config space, so a test can keep one to observe and
+/// modify what the device sees.
+#[derive(Clone)]
+pub struct TestConfigSpace(Arc<Mutex<Vec<u32>>>);
+
+impl Default for TestConfigSpace {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(vec![0; CONFIG_SPACE_SIZE / 4])))
+    }
+}
+
+impl TestConfigSpace {
+    /// Returns the dword at `offset`.
+    pub fn get(&self, offset: u16) -> u32 {
+        self.0.lock()[offset as usize / 4]
+    }
+
+    /// Sets the dword at `offset` to `value`.
+    pub fn set(&self, offset: u16, value: u32) {
+        self.0.lock()[offset as usize / 4] = value;
+    }
+
+    fn index(addr: u64) -> Option<usize> {
+        let offset = addr.checked_sub(TEST_GPA + protocol::MMIO_PAGE_CONFIG_SPACE)?;
+        ((offset as usize) < CONFIG_SPACE_SIZE).then_some(offset as usize / 4)
+    }
+}
+
+impl MemoryAccess for TestConfigSpace {
+    fn gpa(&mut self) -> u64 {
+        TEST_GPA
+    }
+
+    fn read(&mut self, addr: u64) -> u32 {
+        Self::index(addr).map_or(!0, |i| self.0.lock()[i])
+    }
+
+    fn write(&mut self, addr: u64, value: u32) {
+        // Writes outside config space, such as to the slot number, are
+        // ignored.
+        if let Some(i) = Self::index(addr) {
+            self.0.lock()[i] = value;
+        }
+    }
+}
@@ -12,21 +12,39 @@
 use chipset_device::pci::ByteEnabledDwordWrite;
 use chipset_device::pci::PciConfigSpace;
 use closeable_mutex::CloseableMutex;
+use futures::StreamExt;
 use guestmem::GuestMemory;
 use guid::Guid;
 use openhcl_tdisp::TdispVirtualDeviceInterface;
 use pal_async::DefaultDriver;
 use pal_async::async_test;
 use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pci_core::spec::caps::ExtendedCapabilityId;
+use pci_core::spec::caps::sriov::SriovExtendedCapabilityHeader;
+use pci_core::spec::cfg_space::Command;
+use pci_core::spec::cfg_space::HeaderType00;
+use pci_core::spec::hwid::HardwareIds;
 use std::sync::Arc;
+use std::time::Duration;
 use task_control::StopTask;
+use tdisp::TdispDeviceInterfaceInfo;
+use tdisp::TdispGuestProtocolType;
+use tdisp::TdispHostDeviceInterface;
 use tdisp::TdispHostDeviceTargetEmulator;
+use tdisp::TdispReportType;
 use tdisp::test_helpers::TDISP_MOCK_DEVICE_ID;
 use tdisp::test_helpers::TDISP_MOCK_GUEST_PROTOCOL;
 use tdisp::test_helpers::TDISP_MOCK_SUPPORTED_FEATURES;
 use tdisp::test_helpers::new_null_tdisp_interface;
 use test_with_tracing::test;
+use vmbus_async::queue::IncomingPacket;
+use vmbus_async::queue::OutgoingPacket;
+use vmbus_async::queue::Queue;
+use vmbus_channel::RawAsyncChannel;
 use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_ring::FlatRingMem;
+use vmbus_ring::OutgoingPacketType;
 use vmcore::vpci_msi::MapVpciInterrupt;
 use vmcore::vpci_msi::MsiAddressData;
 use vmcore::vpci_msi::VpciInterruptMapper;
@@ -34,6 +52,10 @@
 use vpci::bus::VpciBusConfig;
 use vpci::bus::VpciBusDevice;
 use vpci::test_helpers::TestVpciInterruptController;
+use vpci_protocol as protocol;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
 
 struct NoopDevice {
     tdisp_interface: TdispHostDeviceTargetEmulator,
@@ -60,6 +82,37 @@ fn pci_cfg_write(&mut self, _offset: u16, _value: ByteEnabledDwordWrite) -> IoRe
     }
 }
 
+/// A device with a single 32-bit memory BAR.
+struct BarDevice {
+    bar0: u32,
+}
+
+const BAR_DEVICE_BAR0_MASK: u32 = !0xfff;
+
+impl ChipsetDevice for BarDevice {
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+}
+
+impl PciConfigSpace for BarDevice {
+    fn pci_cfg_read(&mut self, offset: u16, mut value: ByteEnabledDwordRead<'_>) -> IoResult {
+        if offset == HeaderType00::BAR0.0 {
+            value.set(self.bar0);
+        } else {
+            value.set(0);
+        }
+        IoResult::Ok
+    }
+
+    fn pci_cfg_write(&mut self, offset: u16, value: ByteEnabledDwordWrite) -> IoResult {
+        if offset == HeaderType00::BAR0.0 {
+            self.bar0 = value.merge(self.bar0) & BAR_DEVICE_BAR0_MASK;
+        }
+        IoResult::Ok
+    }
+}
+
 struct BusWrapper(VpciBusDevice);
 
 impl super::MemoryAccess for BusWrapper {
@@ -86,12 +139,422 @@ fn write(&mut self, addr: u64, value: u32) {
     }
 }
 
+/// A device with a fixed config space.
+struct StaticCfgDevice {
+    cfg: Vec<u32>,
+}
+
+impl StaticCfgDevice {
+    fn new() -> Self {
+        Self {
+            cfg: vec![0; 0x1000 / 4],
+        }
+    }
+
+    fn set(&mut self, offset: u16, value: u32) {
+        self.cfg[offset as usize / 4] = value;
+    }
+}
+
+impl ChipsetDevice for StaticCfgDevice {
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+}
+
+impl PciConfigSpace for StaticCfgDevice {
+    fn pci_cfg_read(&mut self, offset: u16, mut value: ByteEnabledDwordRead<'_>) -> IoResult {
+        value.set(self.cfg.get(offset as usize / 4).copied().unwrap_or(!0));
+        IoResult::Ok
+    }
+
+    fn pci_cfg_write(&mut self, _offset: u16, _value: ByteEnabledDwordWrite) -> IoResult {
+        IoResult::Ok
+    }
+}
+
+/// A device whose status register bits are cleared by writing ones.
+struct StatusDevice {
+    status_command: u32,
+}
+
+impl ChipsetDevice for StatusDevice {
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+}
+
+impl PciConfigSpace for StatusDevice {
+    fn pci_cfg_read(&mut self, offset: u16, mut value: ByteEnabledDwordRead<'_>) -> IoResult {
+        if offset == HeaderType00::STATUS_COMMAND.0 {
+            value.set(self.status_command);
+        } else {
+            value.set(0);
+        }
+        IoResult::Ok
+    }
+
+    fn pci_cfg_write(&mut self, offset: u16, value: ByteEnabledDwordWrite) -> IoResult {
+        if offset == HeaderType00::STATUS_COMMAND.0 {
+            let value = value.merge(self.status_command);
+            let status = (self.status_command & !value) & 0xffff_0000;
+            self.status_command = status | (value & 0xffff);
+        }
+        IoResult::Ok
+    }
+}
+
+/// Config space access for tests that don't touch config space.
+struct NullMmio;
+
+impl super::MemoryAccess for NullMmio {
+    fn gpa(&mut self) -> u64 {
+        0x123456780000
+    }
+
+    fn read(&mut self, _addr: u64) -> u32 {
+        !0
+    }
+
+    fn write(&mut self, _addr: u64, _value: u32) {}
+}
+
+/// Config space access that reads a fixed value from every address.
+struct FixedMmio(u32);
+
+impl super::MemoryAccess for FixedMmio {
+    fn gpa(&mut self) -> u64 {
+        0x123456780000
+    }
+
+    fn read(&mut self, _addr: u64) -> u32 {
+        self.0
+    }
+
+    fn write(&mut self, _addr: u64, _value: u32) {}
+}
+
+/// Config space access that reads as zero and records config space writes.
+#[derive(Clone, Default)]
+struct RecordingMmio(Arc<parking_lot::Mutex<Vec<(u16, u32)>>>);
+
+impl super::MemoryAccess for RecordingMmio {
+    fn gpa(&mut self) -> u64 {
+        0x123456780000
+    }
+
+    fn read(&mut self, _addr: u64) -> u32 {
+        0
+    }
+
+    fn write(&mut self, addr: u64, value: u32) {
+        if let Some(offset) = addr.checked_sub(self.gpa() + protocol::MMIO_PAGE_CONFIG_SPACE) {
+            self.0.lock().push((offset as u16, value));
+        }
+    }
+}
+
+/// A scripted VPCI host, for sending packets the real server never would.
+struct MockHost {
+    queue: Queue<FlatRingMem>,
+}
+
+impl MockHost {
+    fn new(channel: RawAsyncChannel<FlatRingMem>) -> Self {
+        Self {
+            queue: Queue::new(channel).unwrap(),
+        }
+    }
+
+    /// Reads the next data packet, returning its transaction ID and payload.
+    async fn recv(&mut self) -> (u64, Vec<u8>) {
+        let (mut read, _) = self.queue.split();
+        let packet = read.read().await.unwrap();
+        let IncomingPacket::Data(packet) = &*packet else {
+            panic!("unexpected completion");
+        };
+        let mut reader = packet.reader();
+        let mut buf = vec![0; reader.len()];
+        reader.read(&mut buf).unwrap();
+        (packet.transaction_id().unwrap_or(0), buf)
+    }
+
+    /// Reads the next data packet, asserting it is of type `message_type`.
+    async fn expect(&mut self, message_type: protocol::MessageType) -> (u64, Vec<u8>) {
+        let (tx_id, buf) = self.recv().await;
+        let (actual, _) = protocol::MessageType::read_from_prefix(&buf).unwrap();
+        assert_eq!(actual, message_type);
+        (tx_id, buf)
+    }
+
+    async fn send(&mut self, payload: &[u8]) {
+        self.queue
+            .split()
+            .1
+            .write(OutgoingPacket {
+                transaction_id: 0,
+                packet_type: OutgoingPacketType::InBandNoCompletion,
+                payload: &[payload],
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn complete(&mut self, tx_id: u64, payload: &[u8]) {
+        self.queue
+            .split()
+            .1
+            .write(OutgoingPacket {
+                transaction_id: tx_id,
+                packet_type: OutgoingPacketType::Completion,
+                payload: &[payload],
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Accepts the client's version negotiation.
+    async fn accept_version(&mut self) {
+        let (tx_id, buf) = self
+            .expect(protocol::MessageType::QUERY_PROTOCOL_VERSION)
+            .await;
+        let (query, _) = protocol::QueryProtocolVersion::read_from_prefix(&buf).unwrap();
+        self.complete(
+            tx_id,
+            protocol::QueryProtocolVersionReply {
+                status: protocol::Status::SUCCESS,
+                protocol_version: query.protocol_version,
+            }
+            .as_bytes(),
+        )
+        .await;
+    }
+
+    /// Rejects the client's protocol versions newer than `max`, then accepts
+    /// the next one, returning it.
+    async fn accept_version_up_to(
+        &mut self,
+        max: protocol::ProtocolVersion,
+    ) -> protocol::ProtocolVersion {
+        loop {
+            let (tx_id, buf) = self
+                .expect(protocol::MessageType::QUERY_PROTOCOL_VERSION)
+                .await;
+            let (query, _) = protocol::QueryProtocolVersion::read_from_prefix(&buf).unwrap();
+            let status = if query.protocol_version > max {
+                protocol::Status::REVISION_MISMATCH
+            } else {
+                protocol::Status::SUCCESS
+            };
+            self.complete(
+                tx_id,
+                protocol::QueryProtocolVersionReply {
+                    status,
+                    protocol_version: max,
+                }
+                .as_bytes(),
+            )
+            .await;
+            if status == protocol::Status::SUCCESS {
+                break query.protocol_version;
+            }
+        }
+    }
+
+    /// Sends a bus relations message listing `devices`.
+    async fn send_bus_relations(&mut self, devices: &[protocol::DeviceDescription2]) {
+        let mut buf = protocol::QueryBusRelations2 {
+            message_type: protocol::MessageType::BUS_RELATIONS2,
+            device_count: devices.len() as u32,
+            device: [],
+        }
+        .as_bytes()
+        .to_vec();
+        buf.extend_from_slice(devices.as_bytes());
+        self.send(&buf).await;
+    }
+
+    /// Serves the host side of connecting: negotiates a protocol version no
+    /// newer than `max_version`, then reports `devices` on the bus and
+    /// completes the FDO D0 entry.
+    async fn serve_connect(
+        &mut self,
+        max_version: protocol::ProtocolVersion,
+        devices: &[protocol::DeviceDescription2],
+    ) {
+        self.accept_version_up_to(max_version).await;
+        let (fdo_tx_id, _) = self.expect(protocol::MessageType::FDO_D0_ENTRY).await;
+        if !devices.is_empty() {
+            self.send_bus_relations(devices).await;
+        }
+        self.complete(fdo_tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    }
+
+    /// Replies to the client's resource requirements query with `bars`.
+    async fn serve_resource_requirements(&mut self, bars: [u32; 6]) {
+        let (tx_id, _) = self
+            .expect(protocol::MessageType::CURRENT_RESOURCE_REQUIREMENTS)
+            .await;
+        self.complete(
+            tx_id,
+            protocol::QueryResourceRequirementsReply {
+                status: protocol::Status::SUCCESS,
+                bars,
+            }
+            .as_bytes(),
+        )
+        .await;
+    }
+
+    /// Serves the host side of initializing a device with BAR masks `bars`.
+    async fn serve_init(&mut self, bars: [u32; 6]) {
+        self.serve_resource_requirements(bars).await;
+        let (tx_id, _) = self.expect(protocol::MessageType::ASSIGNED_RESOURCES).await;
+        self.complete(tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    }
+}
+
+fn mock_device(slot: u32) -> protocol::DeviceDescription2 {
+    protocol::DeviceDescription2 {
+        slot: slot.into(),
+        serial_num: slot + 1,
+        ..FromZeros::new_zeroed()
+    }
+}
+
+/// How [`connect_mock`] and [`init_mock_device_with`] set up the bus.
+struct MockBus {
+    /// The newest protocol version the host accepts.
+    max_version: protocol::ProtocolVersion,
+    options: super::VpciClientOptions,
+    mmio: Box<dyn super::MemoryAccess>,
+    /// Receives devices added after connecting.
+    devices: mesh::Sender<super::VpciDeviceDescription>,
+    /// The devices on the bus when the client connects.
+    relations: Vec<protocol::DeviceDescription2>,
+    /// The BAR masks the host reports for the device being initialized.
+    bars: [u32; 6],
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self {
+            max_version: protocol::ProtocolVersion::FE,
+            options: Default::default(),
+            mmio: Box::new(NullMmio),
+            devices: mesh::channel().0,
+            relations: vec![mock_device(0)],
+            bars: [0; 6],
+        }
+    }
+}
+
+/// Connects a client to `host` as described by `bus`, returning the client and
+/// the devices on the bus. `bus.bars` is not used.
+async fn connect_mock(
+    driver: &DefaultDriver,
+    host: &mut MockHost,
+    guest: RawAsyncChannel<FlatRingMem>,
+    bus: MockBus,
+) -> (super::VpciClient, Vec<super::VpciDeviceDescription>) {
+    let MockBus {
+        max_version,
+        options,
+        mmio,
+        devices,
+        relations,
+        bars: _,
+    } = bus;
+    let connect = super::VpciClient::connect_with_options(driver, guest, mmio, devices, options);
+    let run_host = host.serve_connect(max_version, &relations);
+    let (r, ()) = futures::future::join(connect, run_host).await;
+    r.unwrap()
+}
+
+/// Initializes `description`, with `host` reporting BAR masks `bars`.
+async fn init_mock(
+    host: &mut MockHost,
+    description: super::VpciDeviceDescription,
+    bars: [u32; 6],
+) -> (super::VpciDevice, super::VpciDeviceEject) {
+    let (r, ()) = futures::future::join(description.init(), host.serve_init(bars)).await;
+    r.unwrap()
+}
+
+/// Connects to `host` as described by `bus` and initializes the first device
+/// on the bus.
+async fn init_mock_device_with(
+    driver: &DefaultDriver,
+    host: &mut MockHost,
+    guest: RawAsyncChannel<FlatRingMem>,
+    bus: MockBus,
+) -> (super::VpciClient, super::VpciDevice, super::VpciDeviceEject) {
+    let bars = bus.bars;
+    let (client, devices) = connect_mock(driver, host, guest, bus).await;
+    let (device, eject) = init_mock(host, devices.into_iter().next().unwrap(), bars).await;
+    (client, device, eject)
+}
+
+/// Connects to `host`, negotiating a protocol version no newer than
+/// `max_version`, and initializes the single device on the bus.
+async fn init_mock_device(
+    driver: &DefaultDriver,
+    host: &mut MockHost,
+    guest: RawAsyncChannel<FlatRingMem>,
+    max_version: protocol::ProtocolVersion,
+) -> (super::VpciClient, super::VpciDevice, super::VpciDeviceEject) {
+    init_mock_device_with(
+        driver,
+        host,
+        guest,
+        MockBus {
+            max_version,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
 fn make_noop_device() -> Arc<CloseableMutex<NoopDevice>> {
     Arc::new(CloseableMutex::new(NoopDevice {
         tdisp_interface: new_null_tdisp_interface("vpci-unit-test"),
     }))
 }
 
+/// Starts a VPCI bus server for `device`, returning the config space accessor
+/// for the bus, the guest end of the channel, and the server task.
+fn start_server(
+    driver: &DefaultDriver,
+    device: Arc<CloseableMutex<dyn ChipsetDevice>>,
+) -> (BusWrapper, RawAsyncChannel<FlatRingMem>, Task<()>) {
+    let msi_controller = TestVpciInterruptController::new();
+    let (bus, mut channel) = VpciBusDevice::new(
+        VpciBusConfig {
+            instance_id: Guid::new_random(),
+            vtom: None,
+            vnode: None,
+        },
+        device,
+        &mut ExternallyManagedMmioIntercepts,
+        VpciInterruptMapper::new(msi_controller),
+    )
+    .unwrap();
+
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+
+    let mut runner = channel.open(host, GuestMemory::empty()).unwrap();
+    let task = driver.spawn("server", async move {
+        StopTask::run_with(std::future::pending(), async |stop| {
+            let _ = channel.run(stop, &mut runner).await;
+        })
+        .await
+    });
+
+    (BusWrapper(bus), guest, task)
+}
+
 #[async_test]
 async fn test_negotiate_version(driver: DefaultDriver) {
     let device = make_noop_device();
@@ -194,3 +657,1198 @@ async fn test_tdisp_interface_get_device_interface_info(driver: DefaultDriver) {
         Err(err) => panic!("unexpected error: {err}"),
     }
 }
+
+/// A TDISP host interface that returns a fixed TDI report and counts unbinds.
+struct TestTdispHost {
+    fail_start: bool,
+    unbinds: usize,
+}
+
+/// The MSI-X message control value in the TDI report from [`TestTdispHost`].
+const TEST_TDI_REPORT_MSI_X_MESSAGE_CONTROL: u16 = 0x1234;
+
+impl TdispHostDeviceInterface for TestTdispHost {
+    fn tdisp_negotiate_protocol(
+        &mut self,
+        _requested_guest_protocol: TdispGuestProtocolType,
+    ) -> anyhow::Result<TdispDeviceInterfaceInfo> {
+        Ok(TdispDeviceInterfaceInfo {
+            guest_protocol_type: TDISP_MOCK_GUEST_PROTOCOL as i32,
+            supported_features: TDISP_MOCK_SUPPORTED_FEATURES,
+            tdisp_device_id: TDISP_MOCK_DEVICE_ID,
+        })
+    }
+
+    fn tdisp_bind_device(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn tdisp_start_device(&mut self) -> anyhow::Result<()> {
+        if self.fail_start {
+            anyhow::bail!("start failed");
+        }
+        Ok(())
+    }
+
+    fn tdisp_unbind_device(&mut self) -> anyhow::Result<()> {
+        self.unbinds += 1;
+        Ok(())
+    }
+
+    fn tdisp_get_device_report(
+        &mut self,
+        _report_type: TdispReportType,
+    ) -> anyhow::Result<Vec<u8>> {
+        // A report header with no MMIO ranges.
+        let mut report = vec![0; 16];
+        report[4..6].copy_from_slice(&TEST_TDI_REPORT_MSI_X_MESSAGE_CONTROL.to_le_bytes());
+        Ok(report)
+    }
+}
+
+/// Runs [`super::VpciDevice::tdisp_bring_up`] against a [`TestTdispHost`],
+/// returning the result and the number of unbinds the host saw.
+async fn run_tdisp_bring_up(
+    driver: &DefaultDriver,
+    fail_start: bool,
+) -> (anyhow::Result<tdisp::devicereport::TdiReportStruct>, usize) {
+    let host = Arc::new(parking_lot::Mutex::new(TestTdispHost {
+        fail_start,
+        unbinds: 0,
+    }));
+    let device = NoopDevice {
+        tdisp_interface: TdispHostDeviceTargetEmulator::new(host.clone(), "vpci-unit-test"),
+    };
+    let (bus, guest, _task) = start_server(driver, Arc::new(CloseableMutex::new(device)));
+    let (_client, devices) =
+        super::VpciClient::connect(driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+    let (device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+    device.tdisp_get_device_interface_info().await.unwrap();
+
+    let result = device.tdisp_bring_up().await;
+    let unbinds = host.lock().unbinds;
+    (result, unbinds)
+}
+
+#[async_test]
+async fn test_tdisp_bring_up(driver: DefaultDriver) {
+    let (result, unbinds) = run_tdisp_bring_up(&driver, false).await;
+    let report = result.unwrap();
+    assert_eq!(
+        report.msi_x_message_control,
+        TEST_TDI_REPORT_MSI_X_MESSAGE_CONTROL
+    );
+    assert!(report.mmio_interface_info.is_empty());
+    assert_eq!(unbinds, 0);
+}
+
+#[async_test]
+async fn test_tdisp_bring_up_start_fails(driver: DefaultDriver) {
+    let (result, unbinds) = run_tdisp_bring_up(&driver, true).await;
+    result.unwrap_err();
+    // The interface was unbound rather than left in the locked state.
+    assert_eq!(unbinds, 1);
+}
+
+#[test]
+fn test_tx_id_round_trip() {
+    for base in [1, 0x1000, u32::MAX as u64 + 1] {
+        for index in [0, 1, 57] {
+            let tx_id = super::index_to_tx_id(base, index).unwrap();
+            assert!(tx_id >= base);
+            assert_eq!(super::tx_id_to_index(base, tx_id), Some(index));
+        }
+        assert_eq!(super::tx_id_to_index(base, base - 1), None);
+    }
+
+    // A base near the top of the range leaves room for only a few
+    // transactions.
+    assert_eq!(super::index_to_tx_id(u64::MAX, 0).unwrap(), u64::MAX);
+    super::index_to_tx_id(u64::MAX, 1).unwrap_err();
+}
+
+#[async_test]
+async fn test_tx_id_base(driver: DefaultDriver) {
+    let (bus, guest, _task) = start_server(&driver, make_noop_device());
+
+    let (_client, devices) = super::VpciClient::connect_with_options(
+        &driver,
+        guest,
+        Box::new(bus),
+        mesh::channel().0,
+        super::VpciClientOptions {
+            tx_id_base: 0x1000,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    // Each of these requires the completion to be matched back to the
+    // originating transaction.
+    let (device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+    let MsiAddressData { address, data } = device
+        .register_interrupt(
+            1,
+            &VpciInterruptParameters {
+                vector: 5,
+                multicast: false,
+                target_processors: &[1],
+            },
+        )
+        .await
+        .unwrap();
+
+    device.unregister_interrupt(address, data).await;
+}
+
+#[async_test]
+async fn test_read_cfg_raw(driver: DefaultDriver) {
+    let device = Arc::new(CloseableMutex::new(BarDevice { bar0: 0 }));
+    let (bus, guest, _task) = start_server(&driver, device.clone());
+
+    let (_client, devices) =
+        super::VpciClient::connect(&driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+
+    let (vpci_device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+
+    // The BAR write is shadowed and not flushed to the host until MMIO
+    // decoding is enabled.
+    vpci_device.write_cfg(HeaderType00::BAR0.0, 0xfeed_0000);
+    let host_bar0 = device.lock().bar0;
+    assert_ne!(host_bar0, 0xfeed_0000);
+    assert_eq!(vpci_device.read_cfg(HeaderType00::BAR0.0), 0xfeed_0000);
+    assert_eq!(vpci_device.read_cfg_raw(HeaderType00::BAR0.0), host_bar0);
+}
+
+#[async_test]
+async fn test_recent_accesses(driver: DefaultDriver) {
+    let mut device = StaticCfgDevice::new();
+    for i in 0..super::RECENT_ACCESS_COUNT as u16 {
+        device.set(0x40 + i * 4, 0x1000 + i as u32);
+    }
+    let (bus, guest, _task) = start_server(&driver, Arc::new(CloseableMutex::new(device)));
+    let (_client, devices) =
+        super::VpciClient::connect(&driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+    let (device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+
+    // Make one more access than is recorded, so the first one is evicted.
+    device.write_cfg(0x3c, 0xaa);
+    let mut expected = Vec::new();
+    for i in 0..super::RECENT_ACCESS_COUNT as u16 - 1 {
+        let offset = 0x40 + i * 4;
+        let value = device.read_cfg(offset);
+        assert_eq!(value, 0x1000 + i as u32);
+        expected.push(super::ConfigAccess {
+            offset,
+            value,
+            is_write: false,
+        });
+    }
+    device.write_cfg(0x3c, 0xbb);
+    expected.push(super::ConfigAccess {
+        offset: 0x3c,
+        value: 0xbb,
+        is_write: true,
+    });
+
+    let recent = device
+        .recent_accesses
+        .lock()
+        .iter()
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(recent, expected);
+}
+
+#[async_test]
+async fn test_malformed_packet_is_not_fatal(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let connect = super::VpciClient::connect(&driver, guest, Box::new(NullMmio), mesh::channel().0);
+    let run_host = async {
+        host.accept_version().await;
+        let (fdo_tx_id, _) = host.expect(protocol::MessageType::FDO_D0_ENTRY).await;
+
+        // A bus relations message that is too short to contain its header.
+        host.send(protocol::MessageType::BUS_RELATIONS2.as_bytes())
+            .await;
+        // A bus relations message with a bogus slot number.
+        host.send_bus_relations(&[mock_device(0), mock_device(0x1000)])
+            .await;
+        // A valid bus relations message.
+        host.send_bus_relations(&[mock_device(0)]).await;
+
+        host.complete(fdo_tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+
+    let (r, ()) = futures::future::join(connect, run_host).await;
+    let (_client, devices) = r.unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].serial_num(), 1);
+}
+
+#[async_test]
+async fn test_sriov_info(driver: DefaultDriver) {
+    let mut device = StaticCfgDevice::new();
+    // An unrelated extended capability, pointing to the SR-IOV capability.
+    device.set(0x100, (0x140 << 20) | (1 << 16) | 0x0001);
+    device.set(0x140, (1 << 16) | u32::from(ExtendedCapabilityId::SRIOV.0));
+    device.set(
+        0x140 + SriovExtendedCapabilityHeader::INITIAL_TOTAL_VFS.0,
+        (64 << 16) | 8,
+    );
+    device.set(0x140 + SriovExtendedCapabilityHeader::NUM_VFS.0, 4);
+    device.set(
+        0x140 + SriovExtendedCapabilityHeader::VF_OFFSET_STRIDE.0,
+        (2 << 16) | 0x80,
+    );
+    device.set(
+        0x140 + SriovExtendedCapabilityHeader::VF_DEVICE_ID.0,
+        0x1234 << 16,
+    );
+
+    let (bus, guest, _task) = start_server(&driver, Arc::new(CloseableMutex::new(device)));
+    let (_client, devices) =
+        super::VpciClient::connect(&driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+    let (device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+
+    assert_eq!(
+        device.sriov_info(),
+        Some(super::SriovInfo {
+            cap_offset: 0x140,
+            initial_vfs: 8,
+            total_vfs: 64,
+            num_vfs: 4,
+            first_vf_offset: 0x80,
+            vf_stride: 2,
+            vf_device_id: 0x1234,
+        })
+    );
+}
+
+#[async_test]
+async fn test_sriov_info_absent(driver: DefaultDriver) {
+    let (bus, guest, _task) = start_server(&driver, make_noop_device());
+    let (_client, devices) =
+        super::VpciClient::connect(&driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+    let (device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+
+    assert_eq!(device.sriov_info(), None);
+}
+
+#[async_test]
+async fn test_device_identity_survives_init(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, devices) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            relations: vec![protocol::DeviceDescription2 {
+                pnp_id: protocol::PnpId {
+                    sub_vendor_id: 0x1414,
+                    sub_system_id: 0xabcd,
+                    ..FromZeros::new_zeroed()
+                },
+                serial_num: 0x1234,
+                numa_node: 3,
+                ..mock_device(0)
+            }],
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let description = devices.into_iter().next().unwrap();
+    assert_eq!(description.serial_num(), 0x1234);
+    assert_eq!(description.numa_node(), 3);
+
+    let (device, _eject) = init_mock(&mut host, description, [0; 6]).await;
+    assert_eq!(device.serial_num(), 0x1234);
+    assert_eq!(device.subsystem_ids(), (0x1414, 0xabcd));
+    assert_eq!(device.read_cfg(HeaderType00::SUBSYSTEM_ID.0), 0xabcd_1414);
+    assert_eq!(device.numa_node(), 3);
+}
+
+#[async_test]
+async fn test_can_register_interrupt(driver: DefaultDriver) {
+    const STATUS_INSUFFICIENT_RESOURCES: protocol::Status = protocol::Status(0xC000009A);
+
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, device, _eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    let params = VpciInterruptParameters {
+        vector: 5,
+        multicast: false,
+        target_processors: &[1],
+    };
+
+    // The host is out of interrupt resources.
+    let probe = device.can_register_interrupt(32, &params);
+    let run_host = async {
+        let (tx_id, _) = host.expect(protocol::MessageType::CREATE_INTERRUPT3).await;
+        host.complete(
+            tx_id,
+            protocol::CreateInterruptReply {
+                status: STATUS_INSUFFICIENT_RESOURCES,
+                rsvd: 0,
+                interrupt: FromZeros::new_zeroed(),
+            }
+            .as_bytes(),
+        )
+        .await;
+    };
+    let (can_register, ()) = futures::future::join(probe, run_host).await;
+    assert!(!can_register);
+
+    // A successful probe deletes the interrupt it created.
+    let probe = device.can_register_interrupt(1, &params);
+    let run_host = async {
+        let (tx_id, _) = host.expect(protocol::MessageType::CREATE_INTERRUPT3).await;
+        host.complete(
+            tx_id,
+            protocol::CreateInterruptReply {
+                status: protocol::Status::SUCCESS,
+                rsvd: 0,
+                interrupt: protocol::MsiResourceRemapped {
+                    reserved: 0,
+                    message_count: 1,
+                    data_payload: 0x45,
+                    address: 0xfee0_0000,
+                },
+            }
+            .as_bytes(),
+        )
+        .await;
+        let (tx_id, buf) = host.expect(protocol::MessageType::DELETE_INTERRUPT).await;
+        let (delete, _) = protocol::DeleteInterrupt::read_from_prefix(&buf).unwrap();
+        assert_eq!(delete.interrupt.address, 0xfee0_0000);
+        assert_eq!(delete.interrupt.data_payload, 0x45);
+        host.complete(tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+    let (can_register, ()) = futures::future::join(probe, run_host).await;
+    assert!(can_register);
+}
+
+#[async_test]
+async fn test_reconnect(driver: DefaultDriver) {
+    const CFG_VALUE: u32 = 0x12345678;
+
+    // The second end of the channel closes the first when dropped, which lets
+    // the mock host simulate a channel reset.
+    let (guest, host) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let (client, mut devices) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            mmio: Box::new(FixedMmio(CFG_VALUE)),
+            relations: vec![mock_device(0), mock_device(1)],
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(devices.len(), 2);
+    let vanished = devices.pop().unwrap();
+    let survivor = devices.pop().unwrap();
+
+    let (device, _eject) = init_mock(&mut host, survivor, [0; 6]).await;
+    assert_eq!(device.read_cfg_raw(0), CFG_VALUE);
+
+    // Reset the channel, then reconnect with only the first device present.
+    drop(host);
+    let (guest, host) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let reconnect = client.reconnect(&driver, guest);
+    let run_host = async {
+        host.serve_connect(protocol::ProtocolVersion::FE, &[mock_device(0)])
+            .await;
+
+        // The device in use is re-enabled.
+        let (tx_id, buf) = host.expect(protocol::MessageType::ASSIGNED_RESOURCES).await;
+        let (assigned, _) = protocol::DeviceTranslate::read_from_prefix(&buf).unwrap();
+        assert_eq!(u32::from(assigned.slot), 0);
+        host.complete(tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+
+    let (r, ()) = futures::future::join(reconnect, run_host).await;
+    let (_client, new_devices) = r.unwrap();
+    assert!(new_devices.is_empty());
+
+    // The surviving device is still usable, but the vanished one is gone.
+    assert_eq!(device.read_cfg_raw(0), CFG_VALUE);
+    assert!(vanished.init().await.is_err());
+}
+
+#[async_test]
+async fn test_reconnect_restores_config(driver: DefaultDriver) {
+    let mmio = RecordingMmio::default();
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (client, device, _eject) = init_mock_device_with(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            mmio: Box::new(mmio.clone()),
+            bars: [BAR_DEVICE_BAR0_MASK, 0, 0, 0, 0, 0],
+            ..Default::default()
+        },
+    )
+    .await;
+
+    device.write_cfg(HeaderType00::BAR0.0, 0xfeed_0000);
+    device.enable_mmio().unwrap();
+    mmio.0.lock().clear();
+
+    // Reconnect while the previous channel is still open.
+    let (new_host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut new_host = MockHost::new(new_host);
+    let reconnect = client.reconnect(&driver, guest);
+    let run_host = async {
+        new_host
+            .serve_connect(protocol::ProtocolVersion::FE, &[mock_device(0)])
+            .await;
+        let (tx_id, _) = new_host
+            .expect(protocol::MessageType::ASSIGNED_RESOURCES)
+            .await;
+        new_host
+            .complete(tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+    let (r, ()) = futures::future::join(reconnect, run_host).await;
+    r.unwrap();
+
+    // The BAR is written back before memory decoding is enabled again.
+    let writes = mmio.0.lock().clone();
+    let command = u16::from(Command::new().with_mmio_enabled(true)).into();
+    assert!(
+        writes.contains(&(HeaderType00::BAR0.0, 0xfeed_0000)),
+        "{writes:x?}"
+    );
+    assert_eq!(
+        writes.last(),
+        Some(&(HeaderType00::STATUS_COMMAND.0, command)),
+        "{writes:x?}"
+    );
+    drop(host);
+}
+
+#[async_test]
+async fn test_wait_for_device(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (devices_send, mut devices_recv) = mesh::channel();
+    let (client, _) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            devices: devices_send,
+            relations: Vec::new(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let mut matching = mock_device(1);
+    matching.pnp_id.vendor_id = 0x1414;
+    matching.pnp_id.device_id = 0xb111;
+
+    let wait = client.wait_for_device(&[(0x1414, 0xb111)], Duration::from_secs(60));
+    let run_host = async {
+        // Round trip through the worker so that the waiter is registered
+        // before the devices are hot-added.
+        inspect::inspect("", &client).resolve().await;
+        host.send_bus_relations(&[mock_device(0), matching]).await;
+    };
+
+    let (r, ()) = futures::future::join(wait, run_host).await;
+    let device = r.unwrap();
+    assert_eq!(device.serial_num(), matching.serial_num);
+
+    // The device that did not match is still reported normally.
+    let other = devices_recv.next().await.unwrap();
+    assert_eq!(other.serial_num(), mock_device(0).serial_num);
+}
+
+#[async_test]
+async fn test_wait_for_device_timeout(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (devices_send, mut devices_recv) = mesh::channel();
+    let (client, _) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            devices: devices_send,
+            relations: Vec::new(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let err = client
+        .wait_for_device(&[(0x1414, 0xb111)], Duration::from_millis(10))
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<mesh::CancelReason>().is_some());
+
+    // A matching device added after the timeout goes to the devices sender.
+    let mut matching = mock_device(0);
+    matching.pnp_id.vendor_id = 0x1414;
+    matching.pnp_id.device_id = 0xb111;
+    host.send_bus_relations(&[matching]).await;
+    let device = devices_recv.next().await.unwrap();
+    assert_eq!(device.serial_num(), matching.serial_num);
+}
+
+#[async_test]
+async fn test_numa_node_update(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (devices_send, mut devices_recv) = mesh::channel();
+
+    let (_client, devices) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            devices: devices_send,
+            relations: vec![protocol::DeviceDescription2 {
+                numa_node: 1,
+                ..mock_device(0)
+            }],
+            ..Default::default()
+        },
+    )
+    .await;
+    let description = devices.into_iter().next().unwrap();
+    assert_eq!(description.numa_node(), 1);
+
+    // Move the device to another node. Also add a second device, whose arrival
+    // shows that the update has been processed.
+    host.send_bus_relations(&[
+        protocol::DeviceDescription2 {
+            numa_node: 2,
+            ..mock_device(0)
+        },
+        mock_device(1),
+    ])
+    .await;
+    let added = devices_recv.next().await.unwrap();
+    assert_eq!(added.serial_num(), mock_device(1).serial_num);
+    assert_eq!(description.numa_node(), 2);
+}
+
+#[async_test]
+async fn test_oversized_device_count(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let connect = super::VpciClient::connect(&driver, guest, Box::new(NullMmio), mesh::channel().0);
+    let run_host = async {
+        host.accept_version().await;
+        let (fdo_tx_id, _) = host.expect(protocol::MessageType::FDO_D0_ENTRY).await;
+
+        // A bus relations message claiming more devices than it contains.
+        let mut buf = protocol::QueryBusRelations2 {
+            message_type: protocol::MessageType::BUS_RELATIONS2,
+            device_count: 4,
+            device: [],
+        }
+        .as_bytes()
+        .to_vec();
+        buf.extend_from_slice(mock_device(0).as_bytes());
+        host.send(&buf).await;
+
+        // The bus keeps running and accepts a valid message.
+        host.send_bus_relations(&[mock_device(1)]).await;
+        host.complete(fdo_tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+
+    let (r, ()) = futures::future::join(connect, run_host).await;
+    let (_client, devices) = r.unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].serial_num(), mock_device(1).serial_num);
+}
+
+/// Returns the inspected state of the device in `slot`.
+async fn slot_state(client: &super::VpciClient, slot: u32) -> String {
+    let path = format!("slots/{slot}/state");
+    let mut inspection = inspect::inspect(&path, client);
+    inspection.resolve().await;
+    match inspection.results() {
+        inspect::Node::Value(inspect::Value {
+            kind: inspect::ValueKind::String(state),
+            ..
+        }) => state,
+        other => panic!("unexpected inspect node for '{path}': {other:?}"),
+    }
+}
+
+#[async_test]
+async fn test_inspect_slot_state(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let (client, devices) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            relations: vec![mock_device(0), mock_device(1)],
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(devices.len(), 2);
+    assert_eq!(slot_state(&client, 0).await, "available");
+    assert_eq!(slot_state(&client, 1).await, "available");
+
+    // Eject the first device. It is not in use, so the client completes the
+    // eject immediately.
+    host.send(
+        protocol::PdoMessage {
+            message_type: protocol::MessageType::EJECT,
+            slot: 0.into(),
+        }
+        .as_bytes(),
+    )
+    .await;
+    host.expect(protocol::MessageType::EJECT_COMPLETE).await;
+
+    assert_eq!(slot_state(&client, 0).await, "ejected");
+    assert_eq!(slot_state(&client, 1).await, "available");
+}
+
+// ARM64 clients only offer versions that support ARM64 interrupts.
+#[cfg(guest_arch = "x86_64")]
+#[async_test]
+async fn test_negotiate_older_version(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let connect = super::VpciClient::connect(&driver, guest, Box::new(NullMmio), mesh::channel().0);
+    let run_host = async {
+        let version = host
+            .accept_version_up_to(protocol::ProtocolVersion::WIN10)
+            .await;
+        assert_eq!(version, protocol::ProtocolVersion::WIN10);
+        let (fdo_tx_id, _) = host.expect(protocol::MessageType::FDO_D0_ENTRY).await;
+
+        // Older hosts report devices with the original bus relations message.
+        let device = protocol::DeviceDescription {
+            slot: 2u32.into(),
+            serial_num: 3,
+            ..FromZeros::new_zeroed()
+        };
+        let mut buf = protocol::QueryBusRelations {
+            message_type: protocol::MessageType::BUS_RELATIONS,
+            device_count: 1,
+            device: [],
+        }
+        .as_bytes()
+        .to_vec();
+        buf.extend_from_slice(device.as_bytes());
+        host.send(&buf).await;
+        host.complete(fdo_tx_id, protocol::Status::SUCCESS.as_bytes())
+            .await;
+    };
+
+    let (r, ()) = futures::future::join(connect, run_host).await;
+    let (_client, devices) = r.unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].serial_num(), 3);
+    assert_eq!(devices[0].numa_node(), 0);
+
+    let (device, _eject) = init_mock(&mut host, devices.into_iter().next().unwrap(), [0; 6]).await;
+
+    // Older hosts take the interrupt's target processors as a mask.
+    let params = VpciInterruptParameters {
+        vector: 5,
+        multicast: false,
+        target_processors: &[1, 3],
+    };
+    let register = device.register_interrupt(1, &params);
+    let run_host = async {
+        let (tx_id, buf) = host.expect(protocol::MessageType::CREATE_INTERRUPT).await;
+        let (create, _) = protocol::CreateInterrupt::read_from_prefix(&buf).unwrap();
+        assert_eq!(create.interrupt.vector, 5);
+        assert_eq!(create.interrupt.vector_count, 1);
+        assert_eq!(create.interrupt.processor_mask, 0b1010);
+        host.complete(
+            tx_id,
+            protocol::CreateInterruptReply {
+                status: protocol::Status::SUCCESS,
+                rsvd: 0,
+                interrupt: protocol::MsiResourceRemapped {
+                    reserved: 0,
+                    message_count: 1,
+                    data_payload: 0x45,
+                    address: 0xfee0_0000,
+                },
+            }
+            .as_bytes(),
+        )
+        .await;
+    };
+    let (r, ()) = futures::future::join(register, run_host).await;
+    let address_data = r.unwrap();
+    assert_eq!(address_data.address, 0xfee0_0000);
+    assert_eq!(address_data.data, 0x45);
+}
+
+/// Completes a create interrupt request from `host` with `address` and
+/// `data`.
+async fn complete_create_interrupt(host: &mut MockHost, tx_id: u64, address: u64, data: u32) {
+    host.complete(
+        tx_id,
+        protocol::CreateInterruptReply {
+            status: protocol::Status::SUCCESS,
+            rsvd: 0,
+            interrupt: protocol::MsiResourceRemapped {
+                reserved: 0,
+                message_count: 1,
+                data_payload: data,
+                address,
+            },
+        }
+        .as_bytes(),
+    )
+    .await;
+}
+
+#[async_test]
+async fn test_create_interrupt_descriptor3(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, device, _eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    // The vector does not fit in the older descriptor layouts.
+    let params = VpciInterruptParameters {
+        vector: 0x1234,
+        multicast: false,
+        target_processors: &[2, 7],
+    };
+    let register = device.register_interrupt(4, &params);
+    let run_host = async {
+        let (tx_id, buf) = host.expect(protocol::MessageType::CREATE_INTERRUPT3).await;
+        let (create, _) = protocol::CreateInterrupt3::read_from_prefix(&buf).unwrap();
+        assert_eq!(create.interrupt.vector, 0x1234);
+        assert_eq!(
+            create.interrupt.delivery_mode,
+            protocol::DeliveryMode::FIXED
+        );
+        assert_eq!(create.interrupt.vector_count, 4);
+        assert_eq!(create.interrupt.processor_count, 2);
+        assert_eq!(create.interrupt.processor_array[..2], [2, 7]);
+        complete_create_interrupt(&mut host, tx_id, 0x0800_0040, 0x1234).await;
+    };
+    let (r, ()) = futures::future::join(register, run_host).await;
+    let address_data = r.unwrap();
+    assert_eq!(address_data.address, 0x0800_0040);
+    assert_eq!(address_data.data, 0x1234);
+}
+
+// ARM64 clients never negotiate a version without descriptor3.
+#[cfg(guest_arch = "x86_64")]
+#[async_test]
+async fn test_create_interrupt_descriptor2(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, device, _eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::VB).await;
+
+    let params = VpciInterruptParameters {
+        vector: 0x45,
+        multicast: true,
+        target_processors: &[2, 7],
+    };
+    let register = device.register_interrupt(4, &params);
+    let run_host = async {
+        let (tx_id, buf) = host.expect(protocol::MessageType::CREATE_INTERRUPT2).await;
+        let (create, _) = protocol::CreateInterrupt2::read_from_prefix(&buf).unwrap();
+        assert_eq!(create.interrupt.vector, 0x45);
+        assert_eq!(
+            create.interrupt.delivery_mode,
+            protocol::DeliveryMode::LOWEST_PRIORITY
+        );
+        assert_eq!(create.interrupt.vector_count, 4);
+        assert_eq!(create.interrupt.processor_count, 2);
+        assert_eq!(create.interrupt.processor_array[..2], [2, 7]);
+        complete_create_interrupt(&mut host, tx_id, 0xfee0_1000, 0x45).await;
+    };
+    let (r, ()) = futures::future::join(register, run_host).await;
+    let address_data = r.unwrap();
+    assert_eq!(address_data.address, 0xfee0_1000);
+    assert_eq!(address_data.data, 0x45);
+
+    // A vector that does not fit in descriptor2 fails without a request to the
+    // host.
+    let params = VpciInterruptParameters {
+        vector: 0x1234,
+        multicast: false,
+        target_processors: &[2],
+    };
+    device.register_interrupt(1, &params).await.unwrap_err();
+}
+
+#[async_test]
+async fn test_surprise_removal(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, _device, mut eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    // Drop the device from the bus without ejecting it first.
+    host.send_bus_relations(&[]).await;
+    let ejected = eject.next().await.unwrap();
+    assert_eq!(ejected.kind, super::RemovalKind::SurpriseRemove);
+}
+
+#[async_test]
+async fn test_device_replaced(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, _device, mut eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    // Report a different device in the same slot.
+    host.send_bus_relations(&[protocol::DeviceDescription2 {
+        serial_num: 99,
+        ..mock_device(0)
+    }])
+    .await;
+    let ejected = eject.next().await.unwrap();
+    assert_eq!(ejected.kind, super::RemovalKind::SurpriseRemove);
+}
+
+#[async_test]
+async fn test_eject_in_use(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, _device, mut eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    host.send(
+        protocol::PdoMessage {
+            message_type: protocol::MessageType::EJECT,
+            slot: 0.into(),
+        }
+        .as_bytes(),
+    )
+    .await;
+    let ejected = eject.next().await.unwrap();
+    assert_eq!(ejected.kind, super::RemovalKind::Eject);
+}
+
+#[async_test]
+async fn test_eject_timeout(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, device, mut eject) = init_mock_device_with(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            options: super::VpciClientOptions {
+                eject_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .await;
+
+    host.send(
+        protocol::PdoMessage {
+            message_type: protocol::MessageType::EJECT,
+            slot: 0.into(),
+        }
+        .as_bytes(),
+    )
+    .await;
+    eject.next().await.unwrap();
+
+    // The device is never dropped, but the eject completes anyway.
+    host.expect(protocol::MessageType::EJECT_COMPLETE).await;
+    drop(device);
+}
+
+#[async_test]
+async fn test_fdo_entry_timeout(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    let connect = super::VpciClient::connect_with_options(
+        &driver,
+        guest,
+        Box::new(NullMmio),
+        mesh::channel().0,
+        super::VpciClientOptions {
+            fdo_entry_timeout: Duration::from_millis(100),
+            ..Default::default()
+        },
+    );
+    // Never complete the FDO D0 entry.
+    let run_host = async {
+        host.accept_version().await;
+        host.expect(protocol::MessageType::FDO_D0_ENTRY).await;
+    };
+    let (r, ()) = futures::future::join(connect, run_host).await;
+    assert!(r.is_err());
+}
+
+#[async_test]
+async fn test_register_interrupts_batch(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, device, _eject) =
+        init_mock_device(&driver, &mut host, guest, protocol::ProtocolVersion::FE).await;
+
+    let requests = (0..8)
+        .map(|i| {
+            (
+                1,
+                VpciInterruptParameters {
+                    vector: 0x40 + i,
+                    multicast: false,
+                    target_processors: &[0],
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    let register = device.register_interrupts_batch(&requests);
+    let run_host = async {
+        // All the requests arrive before any is completed.
+        let mut tx_ids = Vec::new();
+        for _ in 0..requests.len() {
+            let (tx_id, buf) = host.expect(protocol::MessageType::CREATE_INTERRUPT3).await;
+            let (create, _) = protocol::CreateInterrupt3::read_from_prefix(&buf).unwrap();
+            tx_ids.push((tx_id, create.interrupt.vector));
+        }
+        // Complete them in reverse to check that results stay in order.
+        for &(tx_id, vector) in tx_ids.iter().rev() {
+            complete_create_interrupt(&mut host, tx_id, 0xfee0_0000, vector).await;
+        }
+    };
+    let (results, ()) = futures::future::join(register, run_host).await;
+    assert_eq!(results.len(), 8);
+    for (result, (_, params)) in results.into_iter().zip(&requests) {
+        let address_data = result.unwrap();
+        assert_eq!(address_data.address, 0xfee0_0000);
+        assert_eq!(address_data.data, params.vector);
+    }
+}
+
+#[async_test]
+async fn test_bar_too_large(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (_client, devices) = connect_mock(&driver, &mut host, guest, Default::default()).await;
+
+    // A 64-bit memory BAR of 1TB, far above the default limit. The device is
+    // rejected before its resources are assigned.
+    let init = devices.into_iter().next().unwrap().init();
+    let run_host = host.serve_resource_requirements([0x0000_000c, 0xffff_ff00, 0, 0, 0, 0]);
+    let (r, ()) = futures::future::join(init, run_host).await;
+    let err = r.err().unwrap();
+    assert!(
+        err.to_string().contains("BAR 0 size 0x10000000000 exceeds"),
+        "{err:#}"
+    );
+}
+
+#[async_test]
+async fn test_unimplemented_bars_ignored(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+
+    // The mock device has no BARs, so any limit is satisfied.
+    let (_client, _device, _eject) = init_mock_device_with(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            options: super::VpciClientOptions {
+                max_bar_size: 1 << 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_bar_rao_unimplemented() {
+    // An unimplemented 32-bit BAR, then a 1MB 64-bit BAR, then an
+    // unimplemented 64-bit BAR.
+    let bars = [0, 0xfff0_000c, 0xffff_ffff, 0x0000_000c, 0, 0];
+    assert_eq!(
+        super::bar_rao(&bars, 1 << 20).unwrap(),
+        [0, 0xc, 0, 0, 0, 0]
+    );
+    super::bar_rao(&bars, 1 << 19).unwrap_err();
+}
+
+#[async_test]
+async fn test_rescan(driver: DefaultDriver) {
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+    let mut host = MockHost::new(host);
+    let (devices_send, mut devices_recv) = mesh::channel();
+
+    let (client, devices) = connect_mock(
+        &driver,
+        &mut host,
+        guest,
+        MockBus {
+            devices: devices_send,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Lose the description, then ask for it again.
+    drop(devices);
+    client.rescan().await.unwrap();
+    let description = devices_recv.next().await.unwrap();
+    assert_eq!(description.serial_num(), mock_device(0).serial_num);
+
+    // The rediscovered device can be initialized.
+    let (_device, _eject) = init_mock(&mut host, description, [0; 6]).await;
+
+    // Devices in use are not handed out again.
+    client.rescan().await.unwrap();
+    assert!(devices_recv.try_recv().is_err());
+}
+
+#[async_test]
+async fn test_cfg_byte_write_preserves_status(driver: DefaultDriver) {
+    // Detected parity error (write one to clear) and capabilities list in the
+    // status register, and interrupt disable in the command register.
+    let device = Arc::new(CloseableMutex::new(StatusDevice {
+        status_command: 0x8010_0400,
+    }));
+    let (bus, guest, _task) = start_server(&driver, device.clone());
+    let (_client, devices) =
+        super::VpciClient::connect(&driver, guest, Box::new(bus), mesh::channel().0)
+            .await
+            .unwrap();
+    let (vpci_device, _removed) = devices.into_iter().next().unwrap().init().await.unwrap();
+
+    // Enable memory decoding and bus mastering via the command register's low
+    // byte.
+    vpci_device.write_cfg_u8(HeaderType00::STATUS_COMMAND.0, 0x06);
+    assert_eq!(device.lock().status_command, 0x8010_0406);
+    assert_eq!(
+        vpci_device.read_cfg_u8(HeaderType00::STATUS_COMMAND.0),
+        0x06
+    );
+    assert_eq!(
+        vpci_device.read_cfg_u8(HeaderType00::STATUS_COMMAND.0 + 1),
+        0x04
+    );
+    assert_eq!(
+        vpci_device.read_cfg_u16(HeaderType00::STATUS_COMMAND.0 + 2),
+        0x8010
+    );
+
+    // A word write to the status register clears the bits written as ones and
+    // leaves the command register alone.
+    vpci_device.write_cfg_u16(HeaderType00::STATUS_COMMAND.0 + 2, 0x8000);
+    assert_eq!(device.lock().status_command, 0x0010_0406);
+}
+
+#[test]
+fn test_helper_device() {
+    let config_space = super::test_helpers::TestConfigSpace::default();
+    config_space.set(0x40, 0x1234_5678);
+    let hw_ids = HardwareIds {
+        vendor_id: 0x1414,
+        device_id: 0xb111,
+        revision_id: 0,
+        prog_if: 0.into(),
+        sub_class: 0.into(),
+        base_class: 0.into(),
+        type0_sub_vendor_id: 0,
+        type0_sub_system_id: 0,
+    };
+    let device = super::test_helpers::test_device(
+        Box::new(config_space.clone()),
+        hw_ids,
+        [BAR_DEVICE_BAR0_MASK, 0, 0, 0, 0, 0],
+    )
+    .unwrap();
+
+    assert_eq!(device.read_cfg(HeaderType00::DEVICE_VENDOR.0), 0xb111_1414);
+    assert_eq!(device.read_cfg(0x40), 0x1234_5678);
+    device.write_cfg(0x40, 0xfeed);
+    assert_eq!(config_space.get(0x40), 0xfeed);
+
+    // BARs are shadowed until memory decoding is enabled.
+    device.write_cfg(HeaderType00::BAR0.0, !0);
+    assert_eq!(config_space.get(HeaderType00::BAR0.0), 0);
+    assert_eq!(device.read_cfg(HeaderType00::BAR0.0), BAR_DEVICE_BAR0_MASK);
+    device.write_cfg(
+        HeaderType00::STATUS_COMMAND.0,
+        u16::from(Command::new().with_mmio_enabled(true)).into(),
+    );
+    assert_eq!(config_space.get(HeaderType00::BAR0.0), BAR_DEVICE_BAR0_MASK);
+}
+
+#[test]
+fn test_enable_mmio() {
+    let config_space = super::test_helpers::TestConfigSpace::default();
+    let hw_ids = HardwareIds {
+        vendor_id: 0x1414,
+        device_id: 0xb111,
+        revision_id: 0,
+        prog_if: 0.into(),
+        sub_class: 0.into(),
+        base_class: 0.into(),
+        type0_sub_vendor_id: 0,
+        type0_sub_system_id: 0,
+    };
+    let device = super::test_helpers::test_device(
+        Box::new(config_space.clone()),
+        hw_ids,
+        [BAR_DEVICE_BAR0_MASK, 0, 0, 0, 0, 0],
+    )
+    .unwrap();
+
+    device.write_cfg(HeaderType00::BAR0.0, !0);
+    device.enable_mmio().unwrap();
+    let command = Command::from(device.read_cfg_u16(HeaderType00::STATUS_COMMAND.0));
+    assert!(command.mmio_enabled());
+    assert!(Command::from(config_space.get(HeaderType00::STATUS_COMMAND.0) as u16).mmio_enabled());
+    assert_eq!(config_space.get(HeaderType00::BAR0.0), BAR_DEVICE_BAR0_MASK);
+
+    // Enabling again is a no-op.
+    device.enable_mmio().unwrap();
+}
@@ -988,6 +988,7 @@ pub struct ModeCachingPage {
     pub maximum_prefetch_ceiling: [u8; 2],
 }
 
+pub const MODE_CACHING_READ_DISABLE_CACHE: u8 = 0x1;
 pub const MODE_CACHING_WRITE_CACHE_ENABLE: u8 = 0x4;
 pub const WRITE_CACHE_ENABLE_BYTE_OFFSET: usize = 3;
 
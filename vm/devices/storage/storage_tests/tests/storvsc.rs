@@ -10,13 +10,18 @@
 use scsi_defs::ScsiOp;
 use scsi_defs::ScsiStatus;
 use std::sync::Arc;
+use storvsc_driver::CachingInfo;
+use storvsc_driver::StorvscDriver;
 use storvsc_driver::test_helpers::TestStorvscWorker;
 use storvsp::ScsiController;
 use storvsp::ScsiControllerDisk;
 use storvsp::test_helpers::TestWorker;
 use storvsp_resources::ScsiPath;
 use test_with_tracing::test;
+use user_driver_emulated_mock::DeviceTestMemory;
 use vmbus_channel::connected_async_channels;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
 use zerocopy::FromZeros;
 use zerocopy::IntoBytes;
 
@@ -125,8 +130,8 @@ async fn test_request_response(driver: DefaultDriver) {
         None,
     );
 
-    let mut storvsc = TestStorvscWorker::new();
-    storvsc.start(driver.clone(), guest);
+    let mut storvsc = TestStorvscWorker::new(driver.clone());
+    storvsc.start(guest);
 
     // Wait for negotiation or panic.
     let mut timer = PolledTimer::new(&driver);
@@ -154,3 +159,162 @@ async fn test_request_response(driver: DefaultDriver) {
     storvsc.teardown().await;
     storvsp.teardown_or_panic().await;
 }
+
+#[async_test]
+async fn test_read_write_dma(driver: DefaultDriver) {
+    let (host, guest) = connected_async_channels(16 * 1024);
+
+    // storvsc allocates its buffers from the first half of this memory.
+    let test_mem = DeviceTestMemory::new(64, false, "test_read_write_dma");
+    let controller = ScsiController::new();
+    let disk = scsidisk::SimpleScsiDisk::new(
+        disklayer_ram::ram_disk(0x10000, false).unwrap(),
+        Default::default(),
+    );
+    controller
+        .attach(
+            ScsiPath {
+                path: 0,
+                target: 0,
+                lun: 0,
+            },
+            ScsiControllerDisk::new(Arc::new(disk)),
+        )
+        .unwrap();
+
+    let storvsp = TestWorker::start(
+        controller,
+        driver.clone(),
+        test_mem.guest_memory(),
+        host,
+        None,
+    );
+
+    let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+    let mut storvsc = StorvscDriver::new(
+        &driver_source,
+        storvsp_protocol::ProtocolVersion {
+            major_minor: storvsp_protocol::VERSION_BLUE,
+            reserved: 0,
+        },
+    )
+    .with_dma_client(test_mem.dma_client());
+    storvsc.run(guest, 0).await.unwrap();
+
+    let pattern = (0..4096).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+    storvsc.write(0, 2, &pattern).await.unwrap();
+
+    let mem = storvsc.read(0, 2, 8).await.unwrap();
+    assert_eq!(mem.len(), pattern.len());
+    let mut read_data = vec![0; pattern.len()];
+    mem.read_at(0, &mut read_data);
+    assert_eq!(read_data, pattern);
+
+    storvsc.stop().await;
+    storvsp.teardown_or_panic().await;
+}
+
+#[async_test]
+async fn test_report_luns(driver: DefaultDriver) {
+    let (host, guest) = connected_async_channels(16 * 1024);
+
+    let test_mem = DeviceTestMemory::new(64, false, "test_report_luns");
+    let controller = ScsiController::new();
+    for (target, lun) in [(0, 0), (0, 3), (0, 5), (1, 2)] {
+        let disk = scsidisk::SimpleScsiDisk::new(
+            disklayer_ram::ram_disk(0x10000, false).unwrap(),
+            Default::default(),
+        );
+        controller
+            .attach(
+                ScsiPath {
+                    path: 0,
+                    target,
+                    lun,
+                },
+                ScsiControllerDisk::new(Arc::new(disk)),
+            )
+            .unwrap();
+    }
+
+    let storvsp = TestWorker::start(
+        controller,
+        driver.clone(),
+        test_mem.guest_memory(),
+        host,
+        None,
+    );
+
+    let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+    let mut storvsc = StorvscDriver::new(
+        &driver_source,
+        storvsp_protocol::ProtocolVersion {
+            major_minor: storvsp_protocol::VERSION_BLUE,
+            reserved: 0,
+        },
+    )
+    .with_dma_client(test_mem.dma_client());
+    storvsc.run(guest, 0).await.unwrap();
+
+    assert_eq!(storvsc.report_luns(0, 0).await.unwrap(), [0, 3, 5]);
+    assert_eq!(storvsc.report_luns(0, 1).await.unwrap(), [2]);
+    assert!(storvsc.report_luns(0, 2).await.unwrap().is_empty());
+
+    storvsc.stop().await;
+    storvsp.teardown_or_panic().await;
+}
+
+#[async_test]
+async fn test_mode_sense_caching(driver: DefaultDriver) {
+    let (host, guest) = connected_async_channels(16 * 1024);
+
+    let test_mem = DeviceTestMemory::new(64, false, "test_mode_sense_caching");
+    let controller = ScsiController::new();
+    let disk = scsidisk::SimpleScsiDisk::new(
+        disklayer_ram::ram_disk(0x10000, false).unwrap(),
+        Default::default(),
+    );
+    controller
+        .attach(
+            ScsiPath {
+                path: 0,
+                target: 0,
+                lun: 0,
+            },
+            ScsiControllerDisk::new(Arc::new(disk)),
+        )
+        .unwrap();
+
+    let storvsp = TestWorker::start(
+        controller,
+        driver.clone(),
+        test_mem.guest_memory(),
+        host,
+        None,
+    );
+
+    let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+    let mut storvsc = StorvscDriver::new(
+        &driver_source,
+        storvsp_protocol::ProtocolVersion {
+            major_minor: storvsp_protocol::VERSION_BLUE,
+            reserved: 0,
+        },
+    )
+    .with_dma_client(test_mem.dma_client());
+    storvsc.run(guest, 0).await.unwrap();
+
+    // The disk defaults to a write-back cache.
+    let expected = CachingInfo {
+        write_cache_enabled: true,
+        read_cache_disabled: false,
+    };
+    assert_eq!(storvsc.mode_sense_caching(0).await.unwrap(), expected);
+
+    // Once storvsc is stopped, requests fail, so the second query must be
+    // answered from the cache.
+    storvsc.stop().await;
+    assert_eq!(storvsc.mode_sense_caching(0).await.unwrap(), expected);
+
+    storvsp.teardown_or_panic().await;
+}
@@ -12,20 +12,38 @@
 mod test_helpers;
 
 use futures::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
 use futures_concurrency::future::Race;
 use guestmem::AccessError;
 use guestmem::MemoryRead;
 use guestmem::ranges::PagedRange;
+use inspect::Inspect;
 use mesh_channel::Receiver;
 use mesh_channel::RecvError;
 use mesh_channel::Sender;
+use mesh_channel::rpc::Rpc;
+use mesh_channel::rpc::RpcSend;
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use scsi_defs::ScsiOp;
+use scsi_defs::ScsiStatus;
+use scsi_defs::srb::SrbStatus;
 use slab::Slab;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
 use task_control::AsyncRun;
 use task_control::InspectTask;
 use task_control::StopTask;
 use task_control::TaskControl;
 use thiserror::Error;
 use tracing_helpers::ErrorValueExt;
+use user_driver::DmaClient;
+use user_driver::memory::MemoryBlock;
 use vmbus_async::queue;
 use vmbus_async::queue::CompletionPacket;
 use vmbus_async::queue::DataPacket;
@@ -38,8 +56,10 @@
 use vmbus_ring::OutgoingPacketType;
 use vmbus_ring::PAGE_SIZE;
 use vmbus_ring::RingMem;
+use vmcore::vm_task::VmTaskDriver;
 use vmcore::vm_task::VmTaskDriverSource;
 use zerocopy::FromBytes;
+use zerocopy::FromZeros;
 use zerocopy::Immutable;
 use zerocopy::IntoBytes;
 use zerocopy::KnownLayout;
@@ -49,9 +69,279 @@ pub struct StorvscDriver<T: Send + Sync + RingMem> {
     storvsc: TaskControl<StorvscState, Storvsc<T>>,
     version: storvsp_protocol::ProtocolVersion,
     driver_source: VmTaskDriverSource,
-    new_request_sender: Option<Sender<StorvscRequest>>,
+    driver: Option<VmTaskDriver>,
+    new_request_sender: Option<Sender<WorkerRequest>>,
+    submissions: Submissions,
+    transactions: Arc<Mutex<Slab<PendingOperation>>>,
+    config: StorvscConfig,
+    dma_client: Option<Arc<dyn DmaClient>>,
+    rescan_sender: Sender<()>,
+    rescan_receiver: Receiver<()>,
+    caching: LunCaching,
 }
 
+/// Settings for a [`StorvscDriver`], applied when it is started.
+#[derive(Debug, Clone)]
+pub struct StorvscConfig {
+    queue_depth: usize,
+    negotiation_retries: u32,
+    enumerate_bus_debounce: Duration,
+    completion_budget: usize,
+    recent_ops: usize,
+    transaction_timeout: Option<Duration>,
+    reap_interval: Duration,
+}
+
+impl Default for StorvscConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: 0,
+            negotiation_retries: 0,
+            enumerate_bus_debounce: DEFAULT_ENUMERATE_BUS_DEBOUNCE,
+            completion_budget: DEFAULT_COMPLETION_BUDGET,
+            recent_ops: 0,
+            transaction_timeout: None,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+        }
+    }
+}
+
+impl StorvscConfig {
+    /// Retries protocol negotiation up to `retries` times, with exponential
+    /// backoff, if it fails transiently, for example because storvsp is busy.
+    pub fn with_negotiation_retries(mut self, retries: u32) -> Self {
+        self.negotiation_retries = retries;
+        self
+    }
+
+    /// Coalesces ENUMERATE_BUS notifications received within `window` of the
+    /// first one into a single item on [`StorvscDriver::rescans`]. Defaults to
+    /// 100ms.
+    pub fn with_enumerate_bus_debounce(mut self, window: Duration) -> Self {
+        self.enumerate_bus_debounce = window;
+        self
+    }
+
+    /// Processes at most `budget` consecutive completions (or other packets)
+    /// from storvsp before sending any pending new request, so that a steady
+    /// stream of completions cannot starve submissions. Defaults to 64.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `budget` is zero.
+    pub fn with_completion_budget(mut self, budget: usize) -> Self {
+        assert!(budget > 0, "completion budget must be non-zero");
+        self.completion_budget = budget;
+        self
+    }
+
+    /// Keeps a log of the last `capacity` SRBs sent to storvsp and their
+    /// completion status, visible via inspect as `recent_ops`. Disabled by
+    /// default.
+    pub fn with_recent_ops(mut self, capacity: usize) -> Self {
+        self.recent_ops = capacity;
+        self
+    }
+
+    /// Cancels requests that storvsp has not completed within `timeout`, so
+    /// that a hung host does not block callers forever. Disabled by default.
+    ///
+    /// The transaction stays allocated until storvsp completes it, so that a
+    /// late completion cannot be mistaken for a newer request's.
+    pub fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = Some(timeout);
+        self
+    }
+
+    /// Checks for requests exceeding the timeout set by
+    /// [`Self::with_transaction_timeout`] every `interval`. Defaults to 1s.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn with_reap_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "reap interval must be non-zero");
+        self.reap_interval = interval;
+        self
+    }
+
+    /// Reserves space for `queue_depth` outstanding transactions when the
+    /// driver is started, so that submitting up to that many requests at once
+    /// does not reallocate the transaction table.
+    pub fn with_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+}
+
+/// The size of a logical block for [`StorvscDriver::read`] and
+/// [`StorvscDriver::write`].
+pub const SECTOR_SIZE: usize = 512;
+
+/// State for requests submitted via `submit`, whose completions are reaped
+/// from a single shared stream.
+struct Submissions {
+    next_transaction_id: u64,
+    completion_sender: Sender<StorvscCompletion>,
+    completion_receiver: Receiver<StorvscCompletion>,
+}
+
+impl Submissions {
+    fn new() -> Self {
+        let (completion_sender, completion_receiver) = mesh_channel::channel();
+        Self {
+            next_transaction_id: 0,
+            completion_sender,
+            completion_receiver,
+        }
+    }
+
+    fn next_transaction_id(&mut self) -> u64 {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        transaction_id
+    }
+
+    /// Builds a request whose completion is sent to the shared completion
+    /// stream, returning the request and its transaction ID.
+    fn request(
+        &mut self,
+        request: &storvsp_protocol::ScsiRequest,
+        buf_gpa: u64,
+        byte_len: usize,
+    ) -> (StorvscRequest, u64) {
+        let transaction_id = self.next_transaction_id();
+        let storvsc_request = StorvscRequest {
+            request: *request,
+            buffer: DataBuffer::contiguous(buf_gpa, byte_len),
+            transaction_id,
+            completion_sender: self.completion_sender.clone(),
+        };
+        (storvsc_request, transaction_id)
+    }
+
+    /// Returns the stream of completions, updating `caching` with each one.
+    fn completions<'a>(
+        &'a mut self,
+        caching: &'a mut LunCaching,
+    ) -> impl Stream<Item = (u64, Result<storvsp_protocol::ScsiRequest, StorvscError>)> + 'a {
+        (&mut self.completion_receiver).map(|resp| {
+            caching.observe(&resp);
+            (resp.transaction_id, resp.into_result())
+        })
+    }
+}
+
+/// A request that has been sent to storvsp but not yet completed, as
+/// returned by [`StorvscDriver::outstanding`].
+#[derive(Debug, Clone)]
+pub struct OutstandingRequest {
+    /// The caller-visible transaction ID, as returned by
+    /// [`StorvscDriver::submit`].
+    pub transaction_id: u64,
+    /// The path ID of the device targeted by the request.
+    pub path_id: u8,
+    /// The target ID of the device targeted by the request.
+    pub target_id: u8,
+    /// The LUN targeted by the request.
+    pub lun: u8,
+    /// The SCSI operation code of the request.
+    pub op: ScsiOp,
+    /// How long ago the request was sent.
+    pub age: Duration,
+    /// The request was cancelled, but storvsp has not completed it yet.
+    pub cancelled: bool,
+}
+
+/// The caching mode page state of a LUN, as reported by MODE SENSE.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CachingInfo {
+    /// The write cache is enabled (WCE), so completed writes may not be
+    /// durable until the cache is flushed.
+    pub write_cache_enabled: bool,
+    /// The read cache is disabled (RCD).
+    pub read_cache_disabled: bool,
+}
+
+/// Cached [`CachingInfo`] for the LUNs on path 0, target 0.
+struct LunCaching {
+    luns: HashMap<u8, CachingInfo>,
+}
+
+impl LunCaching {
+    fn new() -> Self {
+        Self {
+            luns: HashMap::new(),
+        }
+    }
+
+    fn get(&self, lun: u8) -> Option<CachingInfo> {
+        self.luns.get(&lun).copied()
+    }
+
+    fn insert(&mut self, lun: u8, info: CachingInfo) {
+        self.luns.insert(lun, info);
+    }
+
+    /// Drops the cached state for the LUN targeted by the request `completion`
+    /// is for if the response reports that the LUN was reset, since the reset
+    /// may have restored the mode pages to their defaults.
+    fn observe(&mut self, completion: &StorvscCompletion) {
+        if completion.path_id != 0 || completion.target_id != 0 {
+            return;
+        }
+        let Some(response) = &completion.completion else {
+            return;
+        };
+        let Some((sense, _)) = sense_data(response)
+            .and_then(|sense| scsi_defs::SenseData::read_from_prefix(sense).ok())
+        else {
+            return;
+        };
+        if scsi_defs::SenseKey(sense.header.sense_key.0 & 0xf)
+            == scsi_defs::SenseKey::UNIT_ATTENTION
+            && sense.additional_sense_code == scsi_defs::AdditionalSenseCode::BUS_RESET
+        {
+            self.luns.remove(&completion.lun);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.luns.clear();
+    }
+}
+
+/// Protocol versions to fall back to, newest first, when storvsp rejects the
+/// requested version.
+///
+/// Older versions use a smaller SCSI request, which is not supported.
+const FALLBACK_VERSIONS: &[u16] = &[
+    storvsp_protocol::VERSION_THRESHOLD,
+    storvsp_protocol::VERSION_BLUE,
+    storvsp_protocol::VERSION_WIN8,
+];
+
+/// The delay before the first negotiation retry. Doubled for each subsequent
+/// retry, up to [`MAX_NEGOTIATION_RETRY_DELAY`].
+const NEGOTIATION_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_NEGOTIATION_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The default window over which ENUMERATE_BUS notifications are coalesced
+/// into a single rescan.
+const DEFAULT_ENUMERATE_BUS_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The default number of consecutive VMBus packets processed before checking
+/// for new requests.
+const DEFAULT_COMPLETION_BUDGET: usize = 64;
+
+/// The default interval at which transactions are checked against the
+/// transaction timeout.
+const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most LUNs accepted from REPORT LUNS, which bounds the buffer allocated
+/// for a target's reported list length.
+const MAX_REPORT_LUNS: usize = 16384;
+
 /// Storvsc backend for SCSI devices.
 struct Storvsc<T: Send + Sync + RingMem> {
     inner: StorvscInner,
@@ -59,43 +349,364 @@ struct Storvsc<T: Send + Sync + RingMem> {
     queue: Queue<T>,
     num_sub_channels: Option<u16>,
     has_negotiated: bool,
+    negotiation_retry: Option<NegotiationRetry>,
+}
+
+/// How to retry negotiation after a transient failure.
+struct NegotiationRetry {
+    retries: u32,
+    timer: PolledTimer,
+}
+
+impl NegotiationRetry {
+    fn new(driver: &(impl ?Sized + Driver), retries: u32) -> Option<Self> {
+        (retries != 0).then(|| Self {
+            retries,
+            timer: PolledTimer::new(driver),
+        })
+    }
+}
+
+/// Coalesces bursts of ENUMERATE_BUS notifications from storvsp into a single
+/// rescan.
+struct EnumerateBusDebounce {
+    window: Duration,
+    timer: PolledTimer,
+    deadline: Option<Instant>,
+    rescan_sender: Sender<()>,
+}
+
+impl EnumerateBusDebounce {
+    fn new(driver: &(impl ?Sized + Driver), window: Duration, rescan_sender: Sender<()>) -> Self {
+        Self {
+            window,
+            timer: PolledTimer::new(driver),
+            deadline: None,
+            rescan_sender,
+        }
+    }
+
+    /// Records a notification, opening the debounce window if it is not
+    /// already open.
+    fn notify(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now().saturating_add(self.window));
+        }
+    }
+
+    /// Waits for the debounce window to close, then requests a rescan.
+    async fn wait(&mut self) {
+        let Some(deadline) = self.deadline else {
+            return std::future::pending().await;
+        };
+        self.timer.sleep_until(deadline).await;
+        self.deadline = None;
+        self.rescan_sender.send(());
+    }
+}
+
+/// Periodically cancels transactions that storvsp has not completed in time,
+/// so that a hung request does not block its caller forever.
+struct TransactionReaper {
+    interval: Duration,
+    max_age: Duration,
+    timer: PolledTimer,
+    deadline: Instant,
+}
+
+impl TransactionReaper {
+    fn new(driver: &(impl ?Sized + Driver), interval: Duration, max_age: Duration) -> Self {
+        Self {
+            interval,
+            max_age,
+            timer: PolledTimer::new(driver),
+            deadline: Instant::now().saturating_add(interval),
+        }
+    }
+
+    /// Waits until the next reap is due.
+    async fn wait(&mut self) {
+        self.timer.sleep_until(self.deadline).await;
+        self.deadline = Instant::now().saturating_add(self.interval);
+    }
 }
 
 struct StorvscInner {
-    new_request_receiver: Receiver<StorvscRequest>,
-    transactions: Slab<PendingOperation>,
+    new_request_receiver: Receiver<WorkerRequest>,
+    /// Shared with [`StorvscDriver`], which lists the outstanding requests
+    /// from it without stopping the worker.
+    transactions: Arc<Mutex<Slab<PendingOperation>>>,
+    enumerate_bus: Option<EnumerateBusDebounce>,
+    reaper: Option<TransactionReaper>,
+    completion_budget: usize,
+    last_error: Option<LastError>,
+    recent_ops: RecentOps,
+}
+
+/// The most recent error encountered by storvsc, kept for diagnostics since
+/// some errors are recovered from without being reported to the client.
+#[derive(Inspect)]
+struct LastError {
+    error: String,
+    #[inspect(
+        rename = "age_ms",
+        with = "|&time| Instant::now().saturating_sub(time).as_millis() as u64"
+    )]
+    time: Instant,
+}
+
+/// A bounded log of the most recently sent SRBs and how they completed, for
+/// diagnosing stuck I/O.
+#[derive(Inspect)]
+struct RecentOps {
+    capacity: usize,
+    #[inspect(iter_by_index)]
+    ops: VecDeque<RecentOp>,
+}
+
+#[derive(Inspect)]
+struct RecentOp {
+    #[inspect(debug)]
+    op: ScsiOp,
+    lun: u8,
+    lba: Option<u64>,
+    #[inspect(rename = "length")]
+    data_transfer_length: u32,
+    transaction_id: u64,
+    /// The slab index the request was sent with, used to match the
+    /// completion.
+    #[inspect(skip)]
+    slot: usize,
+    #[inspect(
+        rename = "age_ms",
+        with = "|&time| Instant::now().saturating_sub(time).as_millis() as u64"
+    )]
+    time: Instant,
+    #[inspect(debug)]
+    status: RecentOpStatus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RecentOpStatus {
+    Pending,
+    Completed {
+        srb_status: SrbStatus,
+        scsi_status: ScsiStatus,
+    },
+    Cancelled,
+}
+
+impl RecentOps {
+    /// Creates a log of the last `capacity` operations. A capacity of zero
+    /// disables logging.
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ops: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records that `request` was sent in transaction `slot`.
+    fn record(
+        &mut self,
+        slot: usize,
+        transaction_id: u64,
+        request: &storvsp_protocol::ScsiRequest,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ops.len() == self.capacity {
+            self.ops.pop_front();
+        }
+        let op = ScsiOp(request.payload[0]);
+        let lba = match op {
+            ScsiOp::READ | ScsiOp::WRITE => {
+                scsi_defs::Cdb10::read_from_prefix(&request.payload[..])
+                    .ok()
+                    .map(|(cdb, _)| u64::from(cdb.logical_block.get()))
+            }
+            ScsiOp::READ16 | ScsiOp::WRITE16 => {
+                scsi_defs::Cdb16::read_from_prefix(&request.payload[..])
+                    .ok()
+                    .map(|(cdb, _)| cdb.logical_block.get())
+            }
+            _ => None,
+        };
+        self.ops.push_back(RecentOp {
+            op,
+            lun: request.lun,
+            lba,
+            data_transfer_length: request.data_transfer_length,
+            transaction_id,
+            slot,
+            time: Instant::now(),
+            status: RecentOpStatus::Pending,
+        });
+    }
+
+    /// Updates the status of the pending operation sent in transaction `slot`,
+    /// if it is still in the log.
+    fn set_status(&mut self, slot: usize, status: RecentOpStatus) {
+        if let Some(op) = self
+            .ops
+            .iter_mut()
+            .rev()
+            .find(|op| op.slot == slot && op.status == RecentOpStatus::Pending)
+        {
+            op.status = status;
+        }
+    }
+
+    /// Marks every pending operation as cancelled.
+    fn cancel_all(&mut self) {
+        for op in &mut self.ops {
+            if op.status == RecentOpStatus::Pending {
+                op.status = RecentOpStatus::Cancelled;
+            }
+        }
+    }
+}
+
+/// A message from the client to the worker.
+enum WorkerRequest {
+    /// A SCSI request to send to storvsp.
+    Scsi(StorvscRequest),
+    /// Cancels the in-flight requests to a `(path, target, lun)`, returning
+    /// how many were cancelled.
+    CancelLun(Rpc<(u8, u8, u8, String), usize>),
 }
 
 struct StorvscRequest {
     request: storvsp_protocol::ScsiRequest,
-    buf_gpa: u64,
-    byte_len: usize,
+    buffer: DataBuffer,
+    /// Caller-visible ID, echoed back in the completion.
+    transaction_id: u64,
     completion_sender: Sender<StorvscCompletion>,
 }
 
+/// The guest pages backing the data buffer of a request.
+struct DataBuffer {
+    gpns: Vec<u64>,
+    offset: usize,
+    len: usize,
+}
+
+impl DataBuffer {
+    /// Describes a buffer that is contiguous in guest physical memory.
+    fn contiguous(gpa: u64, len: usize) -> Self {
+        let start_page: u64 = gpa / PAGE_SIZE as u64;
+        let end_page: u64 = (gpa + (len + PAGE_SIZE - 1) as u64) / PAGE_SIZE as u64;
+        Self {
+            gpns: (start_page..end_page).collect(),
+            offset: gpa as usize % PAGE_SIZE,
+            len,
+        }
+    }
+
+    /// Describes a DMA buffer, which need not be physically contiguous.
+    fn dma(mem: &MemoryBlock) -> Self {
+        Self {
+            gpns: mem.pfns().to_vec(),
+            offset: mem.offset_in_page() as usize,
+            len: mem.len(),
+        }
+    }
+}
+
 /// Result of a Storvsc operation. If None, then operation was cancelled.
 pub struct StorvscCompletion {
+    transaction_id: u64,
+    /// The address of the device targeted by the request.
+    path_id: u8,
+    target_id: u8,
+    lun: u8,
     completion: Option<storvsp_protocol::ScsiRequest>,
+    /// Why the operation was cancelled, if a reason was given.
+    cancel_reason: Option<String>,
+}
+
+impl StorvscCompletion {
+    fn into_result(self) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
+        match (self.completion, self.cancel_reason) {
+            (Some(completion), _) => Ok(completion),
+            (None, Some(reason)) => {
+                Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+            }
+            (None, None) => Err(StorvscError(StorvscErrorInner::Cancelled)),
+        }
+    }
 }
 
 struct PendingOperation {
     sender: Sender<StorvscCompletion>,
+    transaction_id: u64,
+    /// The address of the device targeted by the request.
+    path_id: u8,
+    target_id: u8,
+    lun: u8,
+    op: ScsiOp,
+    /// When the operation was created, for the transaction timeout.
+    time: Instant,
+    /// Set once the caller has been told the operation was cancelled, while
+    /// storvsp may still complete it.
+    cancelled: bool,
 }
 
 impl PendingOperation {
-    fn new(sender: Sender<StorvscCompletion>) -> Self {
-        Self { sender }
+    fn new(
+        sender: Sender<StorvscCompletion>,
+        transaction_id: u64,
+        request: &storvsp_protocol::ScsiRequest,
+    ) -> Self {
+        Self {
+            sender,
+            transaction_id,
+            path_id: request.path_id,
+            target_id: request.target_id,
+            lun: request.lun,
+            op: ScsiOp(request.payload[0]),
+            time: Instant::now(),
+            cancelled: false,
+        }
     }
 
     fn complete(&mut self, result: storvsp_protocol::ScsiRequest) {
+        if self.cancelled {
+            return;
+        }
         self.sender.send(StorvscCompletion {
+            transaction_id: self.transaction_id,
+            path_id: self.path_id,
+            target_id: self.target_id,
+            lun: self.lun,
             completion: Some(result),
+            cancel_reason: None,
         })
     }
 
     fn cancel(&mut self) {
+        self.cancel_inner(None);
+    }
+
+    fn cancel_with_reason(&mut self, reason: &str) {
+        self.cancel_inner(Some(reason.to_owned()));
+    }
+
+    fn cancel_inner(&mut self, cancel_reason: Option<String>) {
+        if self.cancelled {
+            return;
+        }
+        self.cancelled = true;
         // Sending completion with an empty result indicates cancellation or other error.
-        self.sender.send(StorvscCompletion { completion: None });
+        self.sender.send(StorvscCompletion {
+            transaction_id: self.transaction_id,
+            path_id: self.path_id,
+            target_id: self.target_id,
+            lun: self.lun,
+            completion: None,
+            cancel_reason,
+        });
     }
 }
 
@@ -134,9 +745,172 @@ pub(crate) enum StorvscErrorInner {
     /// Operation cancelled.
     #[error("pending operation cancelled")]
     Cancelled,
+    /// Pending operation cancelled, for example because its LUN was removed
+    /// or the driver shut down.
+    #[error("pending operation cancelled: {0}")]
+    CancelledWithReason(String),
     /// Storvsc driver not fully initialized.
     #[error("driver not initialized")]
     Uninitialized,
+    /// Requested sense buffer is larger than the protocol supports.
+    #[error("sense length {0} exceeds maximum of {max}", max = storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE)]
+    InvalidSenseLength(usize),
+    /// No DMA client was provided to allocate buffers.
+    #[error("no DMA client available")]
+    NoDmaClient,
+    /// Failed to allocate a DMA buffer.
+    #[error("failed to allocate DMA buffer")]
+    DmaAllocation(#[source] anyhow::Error),
+    /// The MODE SENSE data did not contain a valid caching mode page.
+    #[error("invalid caching mode page")]
+    InvalidCachingPage,
+    /// Transfer is too large for a single request.
+    #[error("transfer of {0} blocks is too large")]
+    TransferTooLarge(u64),
+    /// The request completed with a failure status.
+    #[error("request failed with srb status {0:?}, scsi status {1:?}")]
+    RequestFailed(SrbStatus, ScsiStatus),
+    /// Request payload is larger than the protocol supports.
+    #[error("payload of {0} bytes exceeds maximum of {max}", max = storvsp_protocol::SCSI_REQUEST_LEN_MAX)]
+    PayloadTooLarge(usize),
+}
+
+/// Returns a copy of `request` that asks for up to `sense_len` bytes of
+/// autosense data.
+fn with_sense_len(
+    request: &storvsp_protocol::ScsiRequest,
+    sense_len: usize,
+) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
+    if sense_len > storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE {
+        return Err(StorvscError(StorvscErrorInner::InvalidSenseLength(
+            sense_len,
+        )));
+    }
+    Ok(storvsp_protocol::ScsiRequest {
+        sense_info_ex_length: sense_len as u8,
+        ..*request
+    })
+}
+
+/// Builds a READ(16) or WRITE(16) request for `blocks` blocks at `lba`.
+fn rw_request(
+    op: ScsiOp,
+    lun: u8,
+    lba: u64,
+    blocks: u32,
+    byte_len: u32,
+) -> storvsp_protocol::ScsiRequest {
+    let cdb = scsi_defs::Cdb16 {
+        operation_code: op,
+        logical_block: lba.into(),
+        transfer_blocks: blocks.into(),
+        ..FromZeros::new_zeroed()
+    };
+
+    let mut request = storvsp_protocol::ScsiRequest {
+        lun,
+        length: storvsp_protocol::SCSI_REQUEST_LEN_V2 as u16,
+        cdb_length: size_of::<scsi_defs::Cdb16>() as u8,
+        data_in: (op == ScsiOp::READ16).into(),
+        data_transfer_length: byte_len,
+        ..FromZeros::new_zeroed()
+    };
+    request.payload[..size_of::<scsi_defs::Cdb16>()].copy_from_slice(cdb.as_bytes());
+    request
+}
+
+/// Builds a REPORT LUNS request for `path`/`target` with a data buffer of
+/// `byte_len` bytes.
+fn report_luns_request(path: u8, target: u8, byte_len: usize) -> storvsp_protocol::ScsiRequest {
+    let cdb = scsi_defs::ReportLuns {
+        operation_code: ScsiOp::REPORT_LUNS,
+        allocation_length: (byte_len as u32).into(),
+        ..FromZeros::new_zeroed()
+    };
+
+    let mut request = storvsp_protocol::ScsiRequest {
+        path_id: path,
+        target_id: target,
+        length: storvsp_protocol::SCSI_REQUEST_LEN_V2 as u16,
+        cdb_length: size_of::<scsi_defs::ReportLuns>() as u8,
+        data_in: 1,
+        data_transfer_length: byte_len as u32,
+        ..FromZeros::new_zeroed()
+    };
+    request.payload[..size_of::<scsi_defs::ReportLuns>()].copy_from_slice(cdb.as_bytes());
+    request
+}
+
+/// The allocation length for MODE SENSE(6), which is enough for the header,
+/// a block descriptor, and the caching mode page.
+const MODE_SENSE_LEN: usize = u8::MAX as usize;
+
+/// Builds a MODE SENSE(6) request for the current values of the caching mode
+/// page of `lun` (on path 0, target 0), asking for autosense data so that a
+/// LUN reset can be detected.
+fn mode_sense_caching_request(lun: u8) -> storvsp_protocol::ScsiRequest {
+    let cdb = scsi_defs::ModeSense {
+        operation_code: ScsiOp::MODE_SENSE,
+        flags2: scsi_defs::ModeSenseFlags::new().with_page_code(scsi_defs::MODE_PAGE_CACHING),
+        allocation_length: MODE_SENSE_LEN as u8,
+        ..FromZeros::new_zeroed()
+    };
+
+    let mut request = storvsp_protocol::ScsiRequest {
+        lun,
+        length: storvsp_protocol::SCSI_REQUEST_LEN_V2 as u16,
+        cdb_length: size_of::<scsi_defs::ModeSense>() as u8,
+        data_in: 1,
+        data_transfer_length: MODE_SENSE_LEN as u32,
+        sense_info_ex_length: storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE as u8,
+        ..FromZeros::new_zeroed()
+    };
+    request.payload[..size_of::<scsi_defs::ModeSense>()].copy_from_slice(cdb.as_bytes());
+    request
+}
+
+/// Parses the caching mode page from MODE SENSE(6) `data`.
+fn parse_caching_page(data: &[u8]) -> Result<CachingInfo, StorvscError> {
+    let (header, rest) = scsi_defs::ModeParameterHeader::read_from_prefix(data)
+        .map_err(|_| StorvscError(StorvscErrorInner::InvalidCachingPage))?;
+    let page = rest
+        .get(header.block_descriptor_length as usize..)
+        .and_then(|rest| scsi_defs::ModeCachingPage::read_from_prefix(rest).ok())
+        .map(|(page, _)| page)
+        .filter(|page| page.page_code & 0x3f == scsi_defs::MODE_PAGE_CACHING)
+        .ok_or(StorvscError(StorvscErrorInner::InvalidCachingPage))?;
+    Ok(CachingInfo {
+        write_cache_enabled: page.flags & scsi_defs::MODE_CACHING_WRITE_CACHE_ENABLE != 0,
+        read_cache_disabled: page.flags & scsi_defs::MODE_CACHING_READ_DISABLE_CACHE != 0,
+    })
+}
+
+/// Returns an error if `response` does not indicate success.
+fn check_response(response: &storvsp_protocol::ScsiRequest) -> Result<(), StorvscError> {
+    if response.srb_status.status() != SrbStatus::SUCCESS
+        || response.scsi_status != ScsiStatus::GOOD
+    {
+        return Err(StorvscError(StorvscErrorInner::RequestFailed(
+            response.srb_status.status(),
+            response.scsi_status,
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the autosense data from a completed request, if the host provided
+/// any.
+///
+/// The sense data shares the request payload with the CDB, so this is limited
+/// to the length the host reported, up to
+/// [`storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE`].
+pub fn sense_data(response: &storvsp_protocol::ScsiRequest) -> Option<&[u8]> {
+    if !response.srb_status.autosense_valid() {
+        return None;
+    }
+    let len =
+        (response.sense_info_ex_length as usize).min(storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE);
+    Some(&response.payload[..len])
 }
 
 /// Errors with packet parsing between storvsc and storvsp.
@@ -168,20 +942,60 @@ pub(crate) enum PacketError {
     Range(#[source] ExternalDataError),
 }
 
+impl StorvscErrorInner {
+    /// Returns whether the operation may succeed if retried, as opposed to
+    /// having been definitively rejected.
+    ///
+    /// Transport errors are transient unless the channel has been closed, in
+    /// which case retrying on the same channel cannot succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            StorvscErrorInner::NotEnoughSpace
+            | StorvscErrorInner::PacketError(PacketError::UnexpectedStatus(
+                storvsp_protocol::NtStatus::DEVICE_BUSY,
+            )) => true,
+            StorvscErrorInner::Queue(err) => !err.is_closed_error(),
+            _ => false,
+        }
+    }
+}
+
 impl<T: 'static + Send + Sync + RingMem> StorvscDriver<T> {
     /// Create a new driver instance connected to storvsp over VMBus.
     pub fn new(
         driver_source: &VmTaskDriverSource,
         version: storvsp_protocol::ProtocolVersion,
     ) -> Self {
+        let (rescan_sender, rescan_receiver) = mesh_channel::channel();
         Self {
             storvsc: TaskControl::new(StorvscState),
             version,
             driver_source: driver_source.clone(),
+            driver: None,
             new_request_sender: None,
+            submissions: Submissions::new(),
+            transactions: Arc::new(Mutex::new(Slab::new())),
+            config: StorvscConfig::default(),
+            dma_client: None,
+            rescan_sender,
+            rescan_receiver,
+            caching: LunCaching::new(),
         }
     }
 
+    /// Applies `config` when the driver is started.
+    pub fn with_config(mut self, config: StorvscConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Uses `dma_client` to allocate the data buffers for [`Self::read`] and
+    /// [`Self::write`].
+    pub fn with_dma_client(mut self, dma_client: Arc<dyn DmaClient>) -> Self {
+        self.dma_client = Some(dma_client);
+        self
+    }
+
     /// Start Storvsc.
     pub async fn run(
         &mut self,
@@ -194,10 +1008,81 @@ pub async fn run(
             .target_vp(target_vp)
             .run_on_target(true)
             .build("storvsc");
-        let (new_request_sender, new_request_receiver) = mesh_channel::channel::<StorvscRequest>();
-        let mut storvsc = Storvsc::new(channel, self.version, new_request_receiver)?;
-        storvsc.negotiate().await.unwrap();
+        let (mut storvsc, new_request_sender) = self.new_worker(&driver, channel)?;
+        storvsc.negotiate().await?;
+        self.start_worker(driver, storvsc, new_request_sender);
+        Ok(())
+    }
+
+    /// Creates a worker for `channel` with the driver's configuration,
+    /// returning it along with the sender for its new requests.
+    fn new_worker(
+        &self,
+        driver: &VmTaskDriver,
+        channel: RawAsyncChannel<T>,
+    ) -> Result<(Storvsc<T>, Sender<WorkerRequest>), StorvscError> {
+        let (new_request_sender, new_request_receiver) = mesh_channel::channel::<WorkerRequest>();
+        let mut storvsc = Storvsc::new(
+            channel,
+            self.version,
+            new_request_receiver,
+            self.config.queue_depth,
+        )?;
+        storvsc.negotiation_retry = NegotiationRetry::new(driver, self.config.negotiation_retries);
+        storvsc.inner.enumerate_bus = Some(EnumerateBusDebounce::new(
+            driver,
+            self.config.enumerate_bus_debounce,
+            self.rescan_sender.clone(),
+        ));
+        storvsc.inner.completion_budget = self.config.completion_budget;
+        storvsc.inner.recent_ops = RecentOps::new(self.config.recent_ops);
+        storvsc.inner.reaper = self
+            .config
+            .transaction_timeout
+            .map(|timeout| TransactionReaper::new(driver, self.config.reap_interval, timeout));
+        Ok((storvsc, new_request_sender))
+    }
+
+    /// Runs `storvsc` on `driver`, sending it new requests via
+    /// `new_request_sender`.
+    fn start_worker(
+        &mut self,
+        driver: VmTaskDriver,
+        storvsc: Storvsc<T>,
+        new_request_sender: Sender<WorkerRequest>,
+    ) {
         self.new_request_sender = Some(new_request_sender);
+        self.transactions = storvsc.inner.transactions.clone();
+        self.storvsc.insert(&driver, "storvsc", storvsc);
+        self.storvsc.start();
+        self.driver = Some(driver);
+    }
+
+    /// Renegotiates the protocol with storvsp over `channel`, for example
+    /// after the previous channel was reset and reopened.
+    ///
+    /// Requests that were already sent over the previous channel are
+    /// cancelled, since storvsp will never complete them. Requests that had
+    /// not yet been sent are kept, and are sent over the new channel once
+    /// negotiation succeeds. If negotiation fails, they are cancelled too, and
+    /// the driver must be started again with [`Self::run`].
+    ///
+    /// The cached caching mode page state is dropped, since the LUNs may have
+    /// been reset along with the channel.
+    pub async fn renegotiate(&mut self, channel: RawAsyncChannel<T>) -> Result<(), StorvscError> {
+        let driver = match &self.driver {
+            Some(driver) if self.storvsc.has_state() => driver.clone(),
+            _ => return Err(StorvscError(StorvscErrorInner::Uninitialized)),
+        };
+        self.caching.clear();
+        self.storvsc.stop().await;
+        let mut storvsc = self.storvsc.remove();
+        storvsc.replace_channel(channel)?;
+        if let Err(err) = storvsc.negotiate().await {
+            self.new_request_sender = None;
+            self.driver = None;
+            return Err(err);
+        }
 
         self.storvsc.insert(&driver, "storvsc", storvsc);
         self.storvsc.start();
@@ -205,9 +1090,60 @@ pub async fn run(
     }
 
     /// Stop Storvsc.
+    ///
+    /// Any requests that have not yet completed are cancelled, with a
+    /// "shutdown" reason. The same happens if the driver is dropped, but
+    /// without waiting for the task to stop.
     pub async fn stop(&mut self) {
         self.storvsc.stop().await;
         self.storvsc.remove();
+        self.driver = None;
+    }
+
+    /// Cancels the outstanding requests to `lun` on `target` and `path`, for
+    /// example because the disk is being removed, returning how many were
+    /// cancelled.
+    ///
+    /// The cancelled requests fail with an error carrying `reason`. The
+    /// cancellation is queued behind the requests already submitted, so those
+    /// are cancelled too; requests submitted afterwards, and requests to other
+    /// LUNs, are not affected.
+    pub async fn cancel_lun(&self, path: u8, target: u8, lun: u8, reason: &str) -> usize {
+        let Some(request_sender) = &self.new_request_sender else {
+            return 0;
+        };
+        request_sender
+            .call(
+                WorkerRequest::CancelLun,
+                (path, target, lun, reason.to_owned()),
+            )
+            .await
+            .unwrap_or(0)
+    }
+
+    /// Returns the requests that have been sent to storvsp and not yet
+    /// completed, for diagnosing stuck I/O.
+    ///
+    /// Requests that have not yet been sent to storvsp are not included.
+    pub fn outstanding(&self) -> Vec<OutstandingRequest> {
+        self.outstanding_at(Instant::now())
+    }
+
+    /// Returns the outstanding requests, with their ages as of `now`.
+    fn outstanding_at(&self, now: Instant) -> Vec<OutstandingRequest> {
+        self.transactions
+            .lock()
+            .iter()
+            .map(|(_, transaction)| OutstandingRequest {
+                transaction_id: transaction.transaction_id,
+                path_id: transaction.path_id,
+                target_id: transaction.target_id,
+                lun: transaction.lun,
+                op: transaction.op,
+                age: now.saturating_sub(transaction.time),
+                cancelled: transaction.cancelled,
+            })
+            .collect()
     }
 
     /// Send a SCSI request to storvsp over VMBus.
@@ -216,17 +1152,26 @@ pub async fn send_request(
         request: &storvsp_protocol::ScsiRequest,
         buf_gpa: u64,
         byte_len: usize,
+    ) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
+        self.send_request_buffer(request, DataBuffer::contiguous(buf_gpa, byte_len))
+            .await
+    }
+
+    async fn send_request_buffer(
+        &mut self,
+        request: &storvsp_protocol::ScsiRequest,
+        buffer: DataBuffer,
     ) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
         let (sender, mut receiver) = mesh_channel::channel::<StorvscCompletion>();
         let storvsc_request = StorvscRequest {
             request: *request,
-            buf_gpa,
-            byte_len,
+            buffer,
+            transaction_id: self.submissions.next_transaction_id(),
             completion_sender: sender,
         };
         match &self.new_request_sender {
             Some(request_sender) => {
-                request_sender.send(storvsc_request);
+                request_sender.send(WorkerRequest::Scsi(storvsc_request));
                 Ok(())
             }
             None => Err(StorvscError(StorvscErrorInner::Uninitialized)),
@@ -237,51 +1182,251 @@ pub async fn send_request(
             .await
             .map_err(|err| StorvscError(StorvscErrorInner::CompletionError(err)))?;
 
-        if let Some(completion) = resp.completion {
-            Ok(completion)
-        } else {
-            Err(StorvscError(StorvscErrorInner::Cancelled))
-        }
+        self.caching.observe(&resp);
+        resp.into_result()
     }
-}
 
-struct StorvscState;
+    /// Send a SCSI request to storvsp over VMBus, asking for up to `sense_len`
+    /// bytes of autosense data on failure.
+    ///
+    /// Use [`sense_data`] to retrieve the sense data from the completion.
+    pub async fn send_request_with_sense(
+        &mut self,
+        request: &storvsp_protocol::ScsiRequest,
+        buf_gpa: u64,
+        byte_len: usize,
+        sense_len: usize,
+    ) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
+        let request = with_sense_len(request, sense_len)?;
+        self.send_request(&request, buf_gpa, byte_len).await
+    }
 
-impl<T: 'static + Send + Sync + RingMem> AsyncRun<Storvsc<T>> for StorvscState {
-    async fn run(
+    /// Reads `blocks` logical blocks starting at `lba` from `lun` (on path 0,
+    /// target 0) into a newly allocated DMA buffer, which is returned.
+    ///
+    /// Blocks are assumed to be [`SECTOR_SIZE`] bytes. Requires a DMA client,
+    /// provided via [`Self::with_dma_client`].
+    pub async fn read(
         &mut self,
-        stop: &mut StopTask<'_>,
-        worker: &mut Storvsc<T>,
-    ) -> Result<(), task_control::Cancelled> {
-        let fut = async {
-            if !worker.has_negotiated {
-                worker.negotiate().await?;
-            }
-            worker.process_main().await
-        };
+        lun: u8,
+        lba: u64,
+        blocks: u32,
+    ) -> Result<MemoryBlock, StorvscError> {
+        let len = blocks.checked_mul(SECTOR_SIZE as u32).ok_or(StorvscError(
+            StorvscErrorInner::TransferTooLarge(blocks.into()),
+        ))?;
+        let mem = self.allocate_dma_buffer(len as usize)?;
+        self.send_rw(ScsiOp::READ16, lun, lba, blocks, &mem).await?;
+        Ok(mem)
+    }
 
-        match stop.until_stopped(fut).await? {
-            Ok(_) => {}
-            Err(err) => tracing::error!(error = err.as_error(), "storvsc run error"),
+    /// Writes `data` to `lun` (on path 0, target 0) starting at `lba`, via a
+    /// DMA buffer.
+    ///
+    /// `data` must be a multiple of [`SECTOR_SIZE`] bytes. Requires a DMA
+    /// client, provided via [`Self::with_dma_client`].
+    pub async fn write(&mut self, lun: u8, lba: u64, data: &[u8]) -> Result<(), StorvscError> {
+        if data.len() % SECTOR_SIZE != 0 {
+            return Err(StorvscError(StorvscErrorInner::PacketError(
+                PacketError::InvalidDataTransferLength,
+            )));
         }
-        Ok(())
+        let blocks = (data.len() / SECTOR_SIZE) as u64;
+        let blocks = u32::try_from(blocks)
+            .map_err(|_| StorvscError(StorvscErrorInner::TransferTooLarge(blocks)))?;
+        u32::try_from(data.len())
+            .map_err(|_| StorvscError(StorvscErrorInner::TransferTooLarge(blocks.into())))?;
+        let mem = self.allocate_dma_buffer(data.len())?;
+        mem.write_at(0, data);
+        self.send_rw(ScsiOp::WRITE16, lun, lba, blocks, &mem).await
     }
-}
 
-impl<T: 'static + Send + Sync + RingMem> InspectTask<Storvsc<T>> for StorvscState {
-    fn inspect(&self, req: inspect::Request<'_>, worker: Option<&Storvsc<T>>) {
-        if let Some(worker) = worker {
-            let mut resp = req.respond();
-            resp.field("has_negotiated", worker.has_negotiated);
-        }
+    fn allocate_dma_buffer(&self, len: usize) -> Result<MemoryBlock, StorvscError> {
+        let dma_client = self
+            .dma_client
+            .as_ref()
+            .ok_or(StorvscError(StorvscErrorInner::NoDmaClient))?;
+        let mem = dma_client
+            .allocate_dma_buffer(len.next_multiple_of(PAGE_SIZE))
+            .map_err(|err| StorvscError(StorvscErrorInner::DmaAllocation(err)))?;
+        Ok(mem.subblock(0, len))
     }
-}
 
-impl<T: 'static + Send + Sync + RingMem> Storvsc<T> {
+    async fn send_rw(
+        &mut self,
+        op: ScsiOp,
+        lun: u8,
+        lba: u64,
+        blocks: u32,
+        mem: &MemoryBlock,
+    ) -> Result<(), StorvscError> {
+        let byte_len = u32::try_from(mem.len())
+            .map_err(|_| StorvscError(StorvscErrorInner::TransferTooLarge(blocks.into())))?;
+        let request = rw_request(op, lun, lba, blocks, byte_len);
+        let response = self
+            .send_request_buffer(&request, DataBuffer::dma(mem))
+            .await?;
+        check_response(&response)
+    }
+
+    /// Issues REPORT LUNS to `target` on `path` and returns the LUNs it
+    /// reports.
+    ///
+    /// Fails if the target reports more than 16384 LUNs. Requires a DMA client,
+    /// provided via [`Self::with_dma_client`].
+    pub async fn report_luns(&mut self, path: u8, target: u8) -> Result<Vec<u64>, StorvscError> {
+        const HEADER_SIZE: usize = size_of::<scsi_defs::LunList>();
+
+        let mut len = PAGE_SIZE;
+        // If the list does not fit, retry once with a buffer of the size the
+        // target reported.
+        for _ in 0..2 {
+            let mem = self.allocate_dma_buffer(len)?;
+            let request = report_luns_request(path, target, len);
+            let response = self
+                .send_request_buffer(&request, DataBuffer::dma(&mem))
+                .await?;
+            check_response(&response)?;
+
+            let header: scsi_defs::LunList = mem.read_obj(0);
+            let list_len = header.length.get() as usize;
+            if list_len > MAX_REPORT_LUNS * size_of::<u64>() {
+                break;
+            }
+            if HEADER_SIZE + list_len > len {
+                len = (HEADER_SIZE + list_len).next_multiple_of(PAGE_SIZE);
+                continue;
+            }
+
+            let mut entries =
+                vec![scsi_defs::LunListEntry::new_zeroed(); list_len / size_of::<u64>()];
+            mem.read_at(HEADER_SIZE, entries.as_mut_bytes());
+            return Ok(entries
+                .iter()
+                // Only single level LUNs with peripheral or flat addressing
+                // are expected, which put the LUN in the low 14 bits of the
+                // first two bytes.
+                .map(|entry| (u16::from_be_bytes([entry.0[0], entry.0[1]]) & 0x3fff).into())
+                .collect());
+        }
+        Err(StorvscError(StorvscErrorInner::PacketError(
+            PacketError::InvalidDataTransferLength,
+        )))
+    }
+
+    /// Returns the caching mode page state of `lun` (on path 0, target 0), for
+    /// example to decide whether writes need to be followed by a flush.
+    ///
+    /// The result of the first MODE SENSE is cached until a request to the LUN
+    /// reports that it was reset, or the driver renegotiates. Requires a DMA client, provided via
+    /// [`Self::with_dma_client`].
+    pub async fn mode_sense_caching(&mut self, lun: u8) -> Result<CachingInfo, StorvscError> {
+        if let Some(info) = self.caching.get(lun) {
+            return Ok(info);
+        }
+        let mem = self.allocate_dma_buffer(MODE_SENSE_LEN)?;
+        let request = mode_sense_caching_request(lun);
+        let response = self
+            .send_request_buffer(&request, DataBuffer::dma(&mem))
+            .await?;
+        check_response(&response)?;
+
+        let mut data = [0; MODE_SENSE_LEN];
+        mem.read_at(0, &mut data);
+        let info = parse_caching_page(&data)?;
+        self.caching.insert(lun, info);
+        Ok(info)
+    }
+
+    /// Submit a SCSI request to storvsp over VMBus without waiting for it to
+    /// complete.
+    ///
+    /// Returns a transaction ID identifying the request. The result is
+    /// delivered via [`Self::completions`].
+    pub fn submit(
+        &mut self,
+        request: &storvsp_protocol::ScsiRequest,
+        buf_gpa: u64,
+        byte_len: usize,
+    ) -> Result<u64, StorvscError> {
+        let request_sender = self
+            .new_request_sender
+            .as_ref()
+            .ok_or(StorvscError(StorvscErrorInner::Uninitialized))?;
+        let (storvsc_request, transaction_id) =
+            self.submissions.request(request, buf_gpa, byte_len);
+        request_sender.send(WorkerRequest::Scsi(storvsc_request));
+        Ok(transaction_id)
+    }
+
+    /// Returns a stream of `(transaction_id, result)` for requests sent via
+    /// [`Self::submit`], in the order they complete.
+    pub fn completions(
+        &mut self,
+    ) -> impl Stream<Item = (u64, Result<storvsp_protocol::ScsiRequest, StorvscError>)> + '_ {
+        self.submissions.completions(&mut self.caching)
+    }
+
+    /// Returns a stream that yields whenever storvsp reports that the devices
+    /// on the bus may have changed, at which point the caller should rescan,
+    /// for example with [`Self::report_luns`].
+    ///
+    /// Bursts of notifications are coalesced as configured by
+    /// [`StorvscConfig::with_enumerate_bus_debounce`].
+    pub fn rescans(&mut self) -> impl Stream<Item = ()> + '_ {
+        &mut self.rescan_receiver
+    }
+}
+
+impl<T: 'static + Send + Sync + RingMem> Inspect for StorvscDriver<T> {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        self.storvsc.inspect(req)
+    }
+}
+
+struct StorvscState;
+
+impl<T: 'static + Send + Sync + RingMem> AsyncRun<Storvsc<T>> for StorvscState {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        worker: &mut Storvsc<T>,
+    ) -> Result<(), task_control::Cancelled> {
+        let fut = async {
+            if !worker.has_negotiated {
+                worker.negotiate().await?;
+            }
+            worker.process_main().await
+        };
+
+        match stop.until_stopped(fut).await? {
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!(error = err.as_error(), "storvsc run error");
+                worker.inner.record_error(&err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: 'static + Send + Sync + RingMem> InspectTask<Storvsc<T>> for StorvscState {
+    fn inspect(&self, req: inspect::Request<'_>, worker: Option<&Storvsc<T>>) {
+        if let Some(worker) = worker {
+            let mut resp = req.respond();
+            resp.field("has_negotiated", worker.has_negotiated)
+                .field("last_error", &worker.inner.last_error)
+                .field("recent_ops", &worker.inner.recent_ops);
+        }
+    }
+}
+
+impl<T: 'static + Send + Sync + RingMem> Storvsc<T> {
     pub(crate) fn new(
         channel: RawAsyncChannel<T>,
         version: storvsp_protocol::ProtocolVersion,
-        new_request_receiver: Receiver<StorvscRequest>,
+        new_request_receiver: Receiver<WorkerRequest>,
+        queue_depth: usize,
     ) -> Result<Self, StorvscError> {
         let queue =
             Queue::new(channel).map_err(|err| StorvscError(StorvscErrorInner::Queue(err)))?;
@@ -289,18 +1434,73 @@ pub(crate) fn new(
         Ok(Self {
             inner: StorvscInner {
                 new_request_receiver,
-                transactions: Slab::new(),
+                transactions: Arc::new(Mutex::new(Slab::with_capacity(queue_depth))),
+                enumerate_bus: None,
+                reaper: None,
+                completion_budget: DEFAULT_COMPLETION_BUDGET,
+                last_error: None,
+                recent_ops: RecentOps::new(0),
             },
             version,
             queue,
             num_sub_channels: None,
             has_negotiated: false,
+            negotiation_retry: None,
         })
     }
+
+    /// Switches to a new channel to storvsp, which must then be negotiated.
+    ///
+    /// Transactions sent over the old channel are cancelled. Requests that
+    /// have not yet been sent are left queued.
+    pub(crate) fn replace_channel(
+        &mut self,
+        channel: RawAsyncChannel<T>,
+    ) -> Result<(), StorvscError> {
+        self.queue =
+            Queue::new(channel).map_err(|err| StorvscError(StorvscErrorInner::Queue(err)))?;
+        self.num_sub_channels = None;
+        self.has_negotiated = false;
+        for (_, mut transaction) in self.inner.transactions.lock().drain() {
+            transaction.cancel();
+        }
+        self.inner.recent_ops.cancel_all();
+        Ok(())
+    }
 }
 
 impl<T: Send + Sync + RingMem> Storvsc<T> {
+    /// Negotiates the protocol, retrying the whole handshake after transient
+    /// failures as configured by `negotiation_retry`.
     async fn negotiate(&mut self) -> Result<(), StorvscError> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.negotiate_once().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let Some(retry) = &mut self.negotiation_retry else {
+                return Err(err);
+            };
+            if !err.0.is_transient() || attempt >= retry.retries {
+                return Err(err);
+            }
+            self.inner.record_error(&err);
+            let delay = NEGOTIATION_RETRY_DELAY
+                .saturating_mul(1 << attempt.min(16))
+                .min(MAX_NEGOTIATION_RETRY_DELAY);
+            attempt += 1;
+            tracing::warn!(
+                attempt,
+                ?delay,
+                error = err.as_error(),
+                "transient negotiation failure, retrying"
+            );
+            retry.timer.sleep(delay).await;
+        }
+    }
+
+    async fn negotiate_once(&mut self) -> Result<(), StorvscError> {
         // Negotiate protocol with storvsp instance on the other end of VMBus
         // Step 1: BEGIN_INITIALIZATION
         self.inner
@@ -312,21 +1512,51 @@ async fn negotiate(&mut self) -> Result<(), StorvscError> {
             )
             .await?;
 
-        // Step 2: QUERY_PROTOCOL_VERSION - request latest version
-        self.inner
-            .send_packet_and_expect_completion(
-                &mut self.queue,
-                storvsp_protocol::Operation::QUERY_PROTOCOL_VERSION,
-                2,
-                &self.version,
-            )
-            .await
-            .map_err(|err| match err {
-                StorvscError(StorvscErrorInner::PacketError(PacketError::UnexpectedStatus(
-                    storvsp_protocol::NtStatus::INVALID_DEVICE_STATE,
-                ))) => StorvscError(StorvscErrorInner::UnsupportedProtocolVersion),
-                _ => err,
-            })?;
+        // Step 2: QUERY_PROTOCOL_VERSION - request the configured version,
+        // falling back to older ones if storvsp rejects it.
+        let requested = self.version.major_minor;
+        let versions = std::iter::once(requested).chain(
+            FALLBACK_VERSIONS
+                .iter()
+                .copied()
+                .filter(|&version| version < requested),
+        );
+        let mut negotiated = None;
+        for major_minor in versions {
+            let version = storvsp_protocol::ProtocolVersion {
+                major_minor,
+                reserved: 0,
+            };
+            match self
+                .inner
+                .send_packet_and_expect_completion(
+                    &mut self.queue,
+                    storvsp_protocol::Operation::QUERY_PROTOCOL_VERSION,
+                    2,
+                    &version,
+                )
+                .await
+            {
+                Ok(_) => {
+                    negotiated = Some(version);
+                    break;
+                }
+                // storvsp rejects versions it does not support with
+                // REVISION_MISMATCH, so treat that as a definitive rejection
+                // of the version too, not just INVALID_DEVICE_STATE.
+                Err(StorvscError(StorvscErrorInner::PacketError(
+                    PacketError::UnexpectedStatus(
+                        storvsp_protocol::NtStatus::INVALID_DEVICE_STATE
+                        | storvsp_protocol::NtStatus::REVISION_MISMATCH,
+                    ),
+                ))) => {
+                    tracing::debug!(version = major_minor, "protocol version rejected");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.version =
+            negotiated.ok_or(StorvscError(StorvscErrorInner::UnsupportedProtocolVersion))?;
 
         // Step 3: QUERY_PROPERTIES
         let properties_packet = self
@@ -372,55 +1602,87 @@ async fn negotiate(&mut self) -> Result<(), StorvscError> {
     async fn process_main(&mut self) -> Result<(), StorvscError> {
         match self.inner.process_main(&mut self.queue).await {
             Ok(_) => Ok(()),
-            Err(StorvscError(StorvscErrorInner::Queue(err2))) => {
-                if err2.is_closed_error() {
-                    // This is expected, cancel any pending completions
-                    self.inner.cancel_pending_completions().await;
-                    Ok(())
-                } else {
-                    Err(StorvscError(StorvscErrorInner::Queue(err2)))
-                }
+            Err(err) if matches!(&err.0, StorvscErrorInner::Queue(err) if err.is_closed_error()) => {
+                // This is expected, cancel any pending completions
+                self.inner.record_error(&err);
+                self.inner.cancel_pending_completions().await;
+                Ok(())
             }
             Err(err) => Err(err),
         }
     }
 }
 
+impl Drop for StorvscInner {
+    fn drop(&mut self) {
+        // Complete anything outstanding so that callers observe a shutdown
+        // rather than a closed channel when the driver is stopped or dropped.
+        self.cancel_all("shutdown");
+    }
+}
+
 impl StorvscInner {
+    /// Records `err` as the most recent error, for inspect.
+    fn record_error(&mut self, err: &StorvscError) {
+        let error = std::iter::successors(Some(err as &dyn std::error::Error), |err| err.source())
+            .map(|err| err.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        self.last_error = Some(LastError {
+            error,
+            time: Instant::now(),
+        });
+    }
+
     async fn process_main<M: RingMem>(&mut self, queue: &mut Queue<M>) -> Result<(), StorvscError> {
+        // The number of packets processed since a new request was last sent.
+        let mut packets_processed = 0;
         loop {
             enum Event<'a, M: RingMem> {
-                NewRequestReceived(Result<StorvscRequest, RecvError>),
+                NewRequestReceived(Result<WorkerRequest, RecvError>),
                 VmbusPacketReceived(Result<PacketRef<'a, M>, queue::Error>),
+                RescanRequested,
+                ReapRequested,
             }
             let (mut reader, mut writer) = queue.split();
+            // Once the completion budget is exhausted, give any pending request
+            // a chance to be sent before reading more packets.
+            if packets_processed >= self.completion_budget {
+                packets_processed = 0;
+                if let Ok(request) = self.new_request_receiver.try_recv() {
+                    self.handle_request(request, &mut writer)?;
+                    continue;
+                }
+            }
+            let enumerate_bus = &mut self.enumerate_bus;
+            let rescan = async move {
+                match enumerate_bus {
+                    Some(enumerate_bus) => enumerate_bus.wait().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let reaper = &mut self.reaper;
+            let reap = async move {
+                match reaper {
+                    Some(reaper) => reaper.wait().await,
+                    None => std::future::pending().await,
+                }
+            };
             match (
                 self.new_request_receiver
                     .recv()
                     .map(Event::NewRequestReceived),
                 reader.read().map(Event::VmbusPacketReceived),
+                rescan.map(|()| Event::RescanRequested),
+                reap.map(|()| Event::ReapRequested),
             )
                 .race()
                 .await
             {
                 Event::NewRequestReceived(result) => match result {
                     Ok(request) => {
-                        match self.send_request(
-                            &request.request,
-                            request.buf_gpa,
-                            request.byte_len,
-                            &mut writer,
-                            request.completion_sender,
-                        ) {
-                            Ok(()) => Ok(()),
-                            Err(err) => {
-                                tracing::error!(
-                                    "Unable to send new request to VMBus, err={:?}",
-                                    err
-                                );
-                                Err(err)
-                            }
-                        }
+                        packets_processed = 0;
+                        self.handle_request(request, &mut writer)
                     }
                     Err(err) => {
                         tracing::error!("Unable to receive new request, err={:?}", err);
@@ -428,28 +1690,75 @@ enum Event<'a, M: RingMem> {
                     }
                 },
                 Event::VmbusPacketReceived(result) => match result {
-                    Ok(packet_ref) => self.handle_packet(packet_ref.as_ref()),
+                    Ok(packet_ref) => {
+                        packets_processed += 1;
+                        self.handle_packet(packet_ref.as_ref())
+                    }
                     Err(err) => {
                         tracing::error!("Error receiving VMBus packet, err={:?}", err);
                         Err(StorvscError(StorvscErrorInner::Queue(err)))
                     }
                 },
+                Event::RescanRequested => {
+                    tracing::debug!("bus rescan requested");
+                    Ok(())
+                }
+                Event::ReapRequested => {
+                    self.reap_transactions();
+                    Ok(())
+                }
             }?;
         }
     }
 
+    /// Handles a message received from the client.
+    fn handle_request<M: RingMem>(
+        &mut self,
+        request: WorkerRequest,
+        writer: &mut queue::WriteHalf<'_, M>,
+    ) -> Result<(), StorvscError> {
+        match request {
+            WorkerRequest::Scsi(request) => self.start_request(request, writer),
+            WorkerRequest::CancelLun(rpc) => {
+                rpc.handle_sync(|(path_id, target_id, lun, reason)| {
+                    self.cancel_lun(path_id, target_id, lun, &reason)
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a request received from the client to storvsp.
+    fn start_request<M: RingMem>(
+        &mut self,
+        request: StorvscRequest,
+        writer: &mut queue::WriteHalf<'_, M>,
+    ) -> Result<(), StorvscError> {
+        self.send_request(
+            &request.request,
+            &request.buffer,
+            writer,
+            PendingOperation::new(
+                request.completion_sender,
+                request.transaction_id,
+                &request.request,
+            ),
+        )
+        .inspect_err(|err| {
+            tracing::error!("Unable to send new request to VMBus, err={:?}", err);
+        })
+    }
+
     fn send_request<M: RingMem>(
         &mut self,
         request: &storvsp_protocol::ScsiRequest,
-        buf_gpa: u64,
-        byte_len: usize,
+        buffer: &DataBuffer,
         writer: &mut queue::WriteHalf<'_, M>,
-        completion_sender: Sender<StorvscCompletion>,
+        pending: PendingOperation,
     ) -> Result<(), StorvscError> {
         // Create pending transaction record
-        let transaction_id = self
-            .transactions
-            .insert(PendingOperation::new(completion_sender));
+        let caller_transaction_id = pending.transaction_id;
+        let transaction_id = self.transactions.lock().insert(pending);
 
         self.send_gpa_direct_packet(
             writer,
@@ -457,16 +1766,88 @@ fn send_request<M: RingMem>(
             storvsp_protocol::NtStatus::SUCCESS,
             transaction_id as u64,
             request,
-            buf_gpa,
-            byte_len,
-        )
+            buffer,
+        )?;
+        self.recent_ops
+            .record(transaction_id, caller_transaction_id, request);
+        Ok(())
     }
 
     async fn cancel_pending_completions(&mut self) {
-        for transaction in self.transactions.iter_mut() {
+        let mut transactions = self.transactions.lock();
+        for transaction in transactions.iter_mut() {
             transaction.1.cancel();
         }
-        self.transactions.clear();
+        transactions.clear();
+        self.recent_ops.cancel_all();
+    }
+
+    /// Cancels both in-flight transactions and requests that were queued but
+    /// never sent to storvsp, with an error carrying `reason`.
+    fn cancel_all(&mut self, reason: &str) {
+        for (_, mut transaction) in self.transactions.lock().drain() {
+            transaction.cancel_with_reason(reason);
+        }
+        self.recent_ops.cancel_all();
+        while let Ok(request) = self.new_request_receiver.try_recv() {
+            // Pending cancellations are dropped, which their callers treat as
+            // nothing cancelled.
+            let WorkerRequest::Scsi(request) = request else {
+                continue;
+            };
+            PendingOperation::new(
+                request.completion_sender,
+                request.transaction_id,
+                &request.request,
+            )
+            .cancel_with_reason(reason);
+        }
+    }
+
+    /// Cancels the in-flight transactions targeting `lun` on `target` and
+    /// `path`, returning how many were cancelled.
+    ///
+    /// The transactions stay allocated until storvsp completes them, so that a
+    /// late completion cannot be mistaken for a newer request's.
+    fn cancel_lun(&mut self, path_id: u8, target_id: u8, lun: u8, reason: &str) -> usize {
+        let mut cancelled = 0;
+        for (slot, transaction) in self.transactions.lock().iter_mut() {
+            if !transaction.cancelled
+                && transaction.path_id == path_id
+                && transaction.target_id == target_id
+                && transaction.lun == lun
+            {
+                transaction.cancel_with_reason(reason);
+                self.recent_ops.set_status(slot, RecentOpStatus::Cancelled);
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+
+    /// Cancels the in-flight transactions that are older than the reaper's
+    /// maximum age.
+    ///
+    /// As with [`Self::cancel_lun`], the transactions stay allocated until
+    /// storvsp completes them.
+    fn reap_transactions(&mut self) {
+        let Some(reaper) = &self.reaper else {
+            return;
+        };
+        let max_age = reaper.max_age;
+        let now = Instant::now();
+        for (slot, transaction) in self.transactions.lock().iter_mut() {
+            if !transaction.cancelled && now.saturating_sub(transaction.time) >= max_age {
+                tracing::warn!(
+                    transaction_id = transaction.transaction_id,
+                    lun = transaction.lun,
+                    ?max_age,
+                    "transaction timed out, cancelling"
+                );
+                transaction.cancel_with_reason(&format!("timed out after {max_age:?}"));
+                self.recent_ops.set_status(slot, RecentOpStatus::Cancelled);
+            }
+        }
     }
 
     fn handle_packet<M: RingMem>(
@@ -478,8 +1859,12 @@ fn handle_packet<M: RingMem>(
             Packet::Data(data) => {
                 match data.operation {
                     storvsp_protocol::Operation::ENUMERATE_BUS => {
-                        // Nothing to do here, and no completion is required.
-                        // This may be needed in the future for hot add.
+                        // No completion is required. Hosts may send these in
+                        // bursts, so debounce them before asking the client to
+                        // rescan.
+                        if let Some(enumerate_bus) = &mut self.enumerate_bus {
+                            enumerate_bus.notify();
+                        }
                         Ok(())
                     }
                     _ => Err(StorvscError(StorvscErrorInner::UnexpectedOperation)),
@@ -492,17 +1877,32 @@ fn handle_packet<M: RingMem>(
                         .map_err(|_err| StorvscError(StorvscErrorInner::DecodeError))?
                         .to_owned();
 
-                // Match completion against pending transactions
-                match self
+                // Match completion against pending transactions. A completion
+                // for an unknown transaction is likely a late completion for a
+                // request that was already cancelled, so drop it rather than
+                // failing the channel.
+                let transaction = self
                     .transactions
-                    .get_mut(completion.transaction_id as usize)
-                {
-                    Some(t) => Ok(t),
-                    None => Err(StorvscError(StorvscErrorInner::PacketError(
-                        PacketError::UnexpectedTransaction(completion.transaction_id),
-                    ))),
-                }?
-                .complete(result);
+                    .lock()
+                    .try_remove(completion.transaction_id as usize);
+                match transaction {
+                    Some(mut t) => {
+                        self.recent_ops.set_status(
+                            completion.transaction_id as usize,
+                            RecentOpStatus::Completed {
+                                srb_status: result.srb_status.status(),
+                                scsi_status: result.scsi_status,
+                            },
+                        );
+                        t.complete(result)
+                    }
+                    None => {
+                        tracelimit::warn_ratelimited!(
+                            transaction_id = completion.transaction_id,
+                            "completion for unknown transaction"
+                        );
+                    }
+                }
 
                 Ok(())
             }
@@ -550,15 +1950,10 @@ fn send_gpa_direct_packet<M: RingMem, P: IntoBytes + Immutable + KnownLayout>(
         status: storvsp_protocol::NtStatus,
         transaction_id: u64,
         payload: &P,
-        gpa_start: u64,
-        byte_len: usize,
+        buffer: &DataBuffer,
     ) -> Result<(), StorvscError> {
         let payload_bytes = payload.as_bytes();
-        let start_page: u64 = gpa_start / PAGE_SIZE as u64;
-        let end_page: u64 = (gpa_start + (byte_len + PAGE_SIZE - 1) as u64) / PAGE_SIZE as u64;
-        let gpas: Vec<u64> = (start_page..end_page).collect();
-        let pages =
-            PagedRange::new(gpa_start as usize % PAGE_SIZE, byte_len, gpas.as_slice()).unwrap();
+        let pages = PagedRange::new(buffer.offset, buffer.len, &buffer.gpns).unwrap();
         self.send_vmbus_packet(
             &mut writer.batched(),
             OutgoingPacketType::GpaDirect(&[pages]),
@@ -588,16 +1983,14 @@ fn send_vmbus_packet<M: RingMem>(
 
         // storvsp limits the size of the completion packet to the size of the request packet,
         // so we need to pad the payload to the maximum size to ensure we get a complete response.
+        // A larger payload could get a completion too large to parse.
+        let padding_len = storvsp_protocol::SCSI_REQUEST_LEN_MAX
+            .checked_sub(payload.len())
+            .ok_or(StorvscError(StorvscErrorInner::PayloadTooLarge(
+                payload.len(),
+            )))?;
         let padding = [0; storvsp_protocol::SCSI_REQUEST_LEN_MAX];
-        let padding_bytes = if size_of_val(payload) < storvsp_protocol::SCSI_REQUEST_LEN_MAX {
-            &padding[..storvsp_protocol::SCSI_REQUEST_LEN_MAX - size_of_val(payload)]
-        } else {
-            &[][..]
-        };
-        assert_eq!(
-            size_of_val(payload) + padding_bytes.len(),
-            storvsp_protocol::SCSI_REQUEST_LEN_MAX
-        );
+        let padding_bytes = &padding[..padding_len];
         writer
             .try_write(&OutgoingPacket {
                 transaction_id,
@@ -734,16 +2127,39 @@ fn parse_data<T: RingMem>(packet: &DataPacket<'_, T>) -> Result<Packet, PacketEr
 
 #[cfg(test)]
 mod tests {
+    use crate::CachingInfo;
+    use crate::DataBuffer;
+    use crate::LunCaching;
+    use crate::PendingOperation;
+    use crate::RecentOpStatus;
+    use crate::Storvsc;
+    use crate::StorvscCompletion;
+    use crate::StorvscConfig;
+    use crate::StorvscDriver;
+    use crate::StorvscError;
+    use crate::StorvscErrorInner;
+    use crate::StorvscRequest;
+    use crate::WorkerRequest;
+    use crate::parse_caching_page;
     use crate::test_helpers::TestStorvscWorker;
     use crate::test_helpers::TestStorvspWorker;
+    use futures::FutureExt;
+    use futures::StreamExt;
     use guestmem::GuestMemory;
     use pal_async::DefaultDriver;
     use pal_async::async_test;
+    use pal_async::timer::Instant;
     use pal_async::timer::PolledTimer;
     use scsi_defs::ScsiOp;
+    use scsi_defs::ScsiStatus;
+    use std::time::Duration;
     use test_with_tracing::test;
     use vmbus_async::queue::Queue;
     use vmbus_channel::connected_async_channels;
+    use vmbus_ring::FlatRingMem;
+    use vmbus_ring::OutgoingPacketType;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
     use zerocopy::FromZeros;
     use zerocopy::IntoBytes;
 
@@ -817,8 +2233,8 @@ async fn test_negotiation(driver: DefaultDriver) {
             host_queue,
             Vec::new(),
         );
-        let mut storvsc = TestStorvscWorker::new();
-        storvsc.start(driver.clone(), guest);
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
 
         // Wait for negotiation or panic.
         let mut timer = PolledTimer::new(&driver);
@@ -846,8 +2262,8 @@ async fn test_request_response(driver: DefaultDriver) {
             host_queue,
             Vec::new(),
         );
-        let mut storvsc = TestStorvscWorker::new();
-        storvsc.start(driver.clone(), guest);
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
 
         // Wait for negotiation or panic.
         let mut timer = PolledTimer::new(&driver);
@@ -881,19 +2297,73 @@ async fn test_request_response(driver: DefaultDriver) {
     }
 
     #[async_test]
-    async fn test_enumerate_bus(driver: DefaultDriver) {
+    async fn test_submit_completions(driver: DefaultDriver) {
         let (guest, host) = connected_async_channels(16 * 1024);
         let host_queue = Queue::new(host).unwrap();
         let test_guest_mem = GuestMemory::allocate(16384);
 
-        let mut storvsp = TestStorvspWorker::start(
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        // Submit several requests without waiting for any of them.
+        let mut transaction_ids = vec![
+            storvsc
+                .submit(&generate_write_packet(0, 1, 2, 0, 4096), 4096, 4096)
+                .unwrap(),
+            storvsc
+                .submit(&generate_read_packet(0, 1, 2, 8, 4096), 4096, 4096)
+                .unwrap(),
+            storvsc
+                .submit(&generate_write_packet(0, 1, 2, 16, 4096), 8192, 4096)
+                .unwrap(),
+        ];
+
+        // Reap all of them from the completion stream.
+        let mut completed = storvsc
+            .completions()
+            .take(3)
+            .map(|(transaction_id, result)| {
+                result.unwrap();
+                transaction_id
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        transaction_ids.sort();
+        completed.sort();
+        assert_eq!(transaction_ids, completed);
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_teardown_cancels_pending(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start(
             driver.clone(),
             test_guest_mem.clone(),
             host_queue,
             Vec::new(),
         );
-        let mut storvsc = TestStorvscWorker::new();
-        storvsc.start(driver.clone(), guest);
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
 
         // Wait for negotiation or panic.
         let mut timer = PolledTimer::new(&driver);
@@ -902,20 +2372,1023 @@ async fn test_enumerate_bus(driver: DefaultDriver) {
             .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
             .await;
 
+        // Submit a request while the task is stopped so that it is still
+        // outstanding when the task is torn down.
         storvsc.stop().await;
-        assert!(storvsc.get_mut().has_negotiated);
-        storvsc.resume().await;
+        let transaction_id = storvsc
+            .submit(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+            .unwrap();
+        storvsc.teardown().await;
 
-        // Inject an ENUMERATE_BUS command on the VMBus ring
-        let enumerate_bus_packet = storvsp_protocol::Packet {
-            operation: storvsp_protocol::Operation::ENUMERATE_BUS,
-            flags: 0,
-            status: storvsp_protocol::NtStatus::SUCCESS,
-        };
-        storvsp.send_vmbus_data_packet_no_completion(enumerate_bus_packet, 10, &());
-        // Nothing to evaluate here since this is a no-op without a completion for the time being.
+        let (completed_id, result) = storvsc.completions().next().await.unwrap();
+        assert_eq!(completed_id, transaction_id);
+        assert!(matches!(
+            result,
+            Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+                if reason == "shutdown"
+        ));
+
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_drop_cancels_in_flight(driver: DefaultDriver) {
+        const STALLED_LUN: u8 = 2;
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start_with_stalled_lun(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            STALLED_LUN,
+        );
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
+        let mut storvsc = StorvscDriver::new(
+            &driver_source,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+        );
+        storvsc.run(guest, 0).await.unwrap();
+
+        // Send a request that storvsp never completes, with a completion
+        // channel that outlives the driver.
+        let (completion_sender, mut completion_receiver) = mesh_channel::channel();
+        storvsc
+            .new_request_sender
+            .as_ref()
+            .unwrap()
+            .send(WorkerRequest::Scsi(StorvscRequest {
+                request: generate_write_packet(0, 1, STALLED_LUN, 8, 4096),
+                buffer: DataBuffer::contiguous(4096, 4096),
+                transaction_id: 7,
+                completion_sender,
+            }));
+
+        // Requests are sent in order, so once a later request completes the
+        // stalled one is known to be in flight.
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 1, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+        assert_eq!(storvsc.outstanding().len(), 1);
+
+        drop(storvsc);
+
+        let completion = completion_receiver.recv().await.unwrap();
+        assert_eq!(completion.transaction_id, 7);
+        assert!(matches!(
+            completion.into_result(),
+            Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+                if reason == "shutdown"
+        ));
+
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_sense_length(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        // The test storvsp returns as much sense data as requested.
+        let short = storvsc
+            .send_request_with_sense(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096, 8)
+            .await
+            .unwrap();
+        let long = storvsc
+            .send_request_with_sense(
+                &generate_read_packet(0, 1, 2, 0, 4096),
+                4096,
+                4096,
+                storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE,
+            )
+            .await
+            .unwrap();
+
+        let short = crate::sense_data(&short).unwrap();
+        let long = crate::sense_data(&long).unwrap();
+        assert_eq!(short.len(), 8);
+        assert_eq!(long.len(), storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE);
+        assert_eq!(short, &long[..8]);
+        assert!(long[8..].iter().all(|&b| b != 0));
+
+        assert!(
+            storvsc
+                .send_request_with_sense(
+                    &generate_read_packet(0, 1, 2, 0, 4096),
+                    4096,
+                    4096,
+                    storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE + 1,
+                )
+                .await
+                .is_err()
+        );
 
         storvsc.teardown().await;
         storvsp.teardown().await;
     }
+
+    #[async_test]
+    async fn test_negotiation_busy_retry(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start_with_busy_begin(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            2,
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_config(StorvscConfig::default().with_negotiation_retries(2));
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_negotiation_revision_mismatch(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        // The test storvsp rejects THRESHOLD with REVISION_MISMATCH, so
+        // storvsc falls back to BLUE.
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_version(storvsp_protocol::VERSION_THRESHOLD);
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        storvsc.stop().await;
+        assert_eq!(
+            storvsc.get_mut().version.major_minor,
+            storvsp_protocol::VERSION_BLUE
+        );
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_inspect_last_error(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        // The busy BEGIN_INITIALIZATION is retried, so negotiation succeeds,
+        // but the error is still recorded.
+        let storvsp = TestStorvspWorker::start_with_busy_begin(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            1,
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_config(StorvscConfig::default().with_negotiation_retries(1));
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        storvsc.stop().await;
+        let mut inspection = inspect::inspect("last_error/error", &storvsc);
+        inspection.resolve().await;
+        let error = match inspection.results() {
+            inspect::Node::Value(inspect::Value {
+                kind: inspect::ValueKind::String(error),
+                ..
+            }) => error,
+            other => panic!("unexpected inspect node: {other:?}"),
+        };
+        assert!(error.contains("DEVICE_BUSY"), "{error}");
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_recent_ops(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_config(StorvscConfig::default().with_recent_ops(2));
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        // Only the last two of these are kept.
+        for (lun, block) in [(1, 3), (2, 8), (3, 16)] {
+            storvsc
+                .send_request(&generate_write_packet(0, 1, lun, block, 4096), 4096, 4096)
+                .await
+                .unwrap();
+        }
+
+        storvsc.stop().await;
+        let ops = &storvsc.get_mut().inner.recent_ops.ops;
+        assert_eq!(ops.len(), 2);
+        for (op, (lun, lba)) in ops.iter().zip([(2, 8), (3, 16)]) {
+            assert_eq!(op.op, ScsiOp::WRITE);
+            assert_eq!(op.lun, lun);
+            assert_eq!(op.lba, Some(lba));
+            assert_eq!(op.data_transfer_length, 4096);
+            assert!(
+                matches!(
+                    op.status,
+                    RecentOpStatus::Completed {
+                        scsi_status: ScsiStatus::GOOD,
+                        ..
+                    }
+                ),
+                "{:?}",
+                op.status
+            );
+        }
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[test]
+    fn test_parse_caching_page() {
+        // A header, one block descriptor, and a caching page with the read
+        // cache disabled.
+        let mut data = vec![0; 4 + 8 + size_of::<scsi_defs::ModeCachingPage>()];
+        data[3] = 8;
+        data[12] = scsi_defs::MODE_PAGE_CACHING;
+        data[14] = scsi_defs::MODE_CACHING_READ_DISABLE_CACHE;
+        assert_eq!(
+            parse_caching_page(&data).unwrap(),
+            CachingInfo {
+                write_cache_enabled: false,
+                read_cache_disabled: true,
+            }
+        );
+
+        // Some other mode page.
+        data[12] = scsi_defs::MODE_PAGE_CONTROL;
+        assert!(parse_caching_page(&data).is_err());
+        // Truncated data.
+        assert!(parse_caching_page(&data[..13]).is_err());
+    }
+
+    /// Builds the completion of a request to `lun` whose response carries
+    /// `sense` as autosense data.
+    fn completion_with_sense(
+        transaction_id: u64,
+        lun: u8,
+        sense: scsi_defs::SenseData,
+    ) -> StorvscCompletion {
+        let mut response = storvsp_protocol::ScsiRequest {
+            sense_info_ex_length: size_of::<scsi_defs::SenseData>() as u8,
+            ..FromZeros::new_zeroed()
+        };
+        response.srb_status = response.srb_status.with_autosense_valid(true);
+        response.payload[..size_of::<scsi_defs::SenseData>()].copy_from_slice(sense.as_bytes());
+        StorvscCompletion {
+            transaction_id,
+            path_id: 0,
+            target_id: 0,
+            lun,
+            completion: Some(response),
+            cancel_reason: None,
+        }
+    }
+
+    #[test]
+    fn test_caching_invalidated_on_reset() {
+        let info = CachingInfo {
+            write_cache_enabled: true,
+            read_cache_disabled: false,
+        };
+        let mut caching = LunCaching::new();
+        caching.insert(1, info);
+        caching.insert(2, info);
+
+        // Other unit attentions do not invalidate the cache.
+        caching.observe(&completion_with_sense(
+            0,
+            1,
+            scsi_defs::SenseData::new(
+                scsi_defs::SenseKey::UNIT_ATTENTION,
+                scsi_defs::AdditionalSenseCode::PARAMETERS_CHANGED,
+                scsi_defs::SCSI_SENSEQ_CAPACITY_DATA_CHANGED,
+            ),
+        ));
+        assert_eq!(caching.get(1), Some(info));
+
+        // A reset of LUN 1 only invalidates LUN 1.
+        caching.observe(&completion_with_sense(
+            0,
+            1,
+            scsi_defs::SenseData::new(
+                scsi_defs::SenseKey::UNIT_ATTENTION,
+                scsi_defs::AdditionalSenseCode::BUS_RESET,
+                0,
+            ),
+        ));
+        assert_eq!(caching.get(1), None);
+        assert_eq!(caching.get(2), Some(info));
+    }
+
+    #[async_test]
+    async fn test_completions_invalidate_caching(driver: DefaultDriver) {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
+        let mut storvsc = StorvscDriver::<FlatRingMem>::new(
+            &driver_source,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+        );
+        let info = CachingInfo {
+            write_cache_enabled: true,
+            read_cache_disabled: false,
+        };
+        storvsc.caching.insert(1, info);
+
+        // A request reaped from the completion stream reports that the LUN was
+        // reset.
+        storvsc
+            .submissions
+            .completion_sender
+            .send(completion_with_sense(
+                7,
+                1,
+                scsi_defs::SenseData::new(
+                    scsi_defs::SenseKey::UNIT_ATTENTION,
+                    scsi_defs::AdditionalSenseCode::BUS_RESET,
+                    0,
+                ),
+            ));
+        let (transaction_id, result) = storvsc.completions().next().await.unwrap();
+        assert_eq!(transaction_id, 7);
+        result.unwrap();
+        assert_eq!(storvsc.caching.get(1), None);
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        const QUEUE_DEPTH: usize = 64;
+
+        let (guest, _host) = connected_async_channels(16 * 1024);
+        let (_new_request_sender, new_request_receiver) = mesh_channel::channel();
+        let mut storvsc = Storvsc::new(
+            guest,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+            new_request_receiver,
+            QUEUE_DEPTH,
+        )
+        .unwrap();
+
+        let mut transactions = storvsc.inner.transactions.lock();
+        let capacity = transactions.capacity();
+        assert!(capacity >= QUEUE_DEPTH);
+
+        // Filling the transaction table up to the reserved depth must not
+        // reallocate it.
+        let (completion_sender, _completion_receiver) = mesh_channel::channel();
+        for transaction_id in 0..QUEUE_DEPTH as u64 {
+            transactions.insert(PendingOperation::new(
+                completion_sender.clone(),
+                transaction_id,
+                &storvsp_protocol::ScsiRequest::new_zeroed(),
+            ));
+        }
+        assert_eq!(transactions.len(), QUEUE_DEPTH);
+        assert_eq!(transactions.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_cancel_lun() {
+        let (guest, _host) = connected_async_channels(16 * 1024);
+        let (_new_request_sender, new_request_receiver) = mesh_channel::channel();
+        let mut storvsc = Storvsc::new(
+            guest,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+            new_request_receiver,
+            0,
+        )
+        .unwrap();
+
+        // Send requests to two LUNs, which storvsp never completes.
+        let (completion_sender, mut completion_receiver) = mesh_channel::channel();
+        let (_, mut writer) = storvsc.queue.split();
+        for (transaction_id, lun) in [(0, 1), (1, 2), (2, 1)] {
+            storvsc
+                .inner
+                .send_request(
+                    &generate_read_packet(0, 0, lun, 0, 4096),
+                    &DataBuffer::contiguous(4096, 4096),
+                    &mut writer,
+                    PendingOperation::new(
+                        completion_sender.clone(),
+                        transaction_id,
+                        &generate_read_packet(0, 0, lun, 0, 4096),
+                    ),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(storvsc.inner.cancel_lun(0, 0, 1, "disk removed"), 2);
+        for expected_id in [0, 2] {
+            let completion = completion_receiver.try_recv().unwrap();
+            assert_eq!(completion.transaction_id, expected_id);
+            assert!(matches!(
+                completion.into_result(),
+                Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+                    if reason == "disk removed"
+            ));
+        }
+        assert!(completion_receiver.try_recv().is_err());
+
+        // Cancelling again finds nothing new to cancel.
+        assert_eq!(storvsc.inner.cancel_lun(0, 0, 1, "disk removed"), 0);
+
+        // Late completions for the cancelled requests are dropped, while the
+        // request to the other LUN completes normally.
+        let mut transactions = storvsc.inner.transactions.lock();
+        assert_eq!(transactions.len(), 3);
+        for (_, transaction) in transactions.iter_mut() {
+            transaction.complete(storvsp_protocol::ScsiRequest::new_zeroed());
+        }
+        let completion = completion_receiver.try_recv().unwrap();
+        assert_eq!(completion.transaction_id, 1);
+        completion.into_result().unwrap();
+        assert!(completion_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_oversized_payload() {
+        let (guest, _host) = connected_async_channels(16 * 1024);
+        let (_new_request_sender, new_request_receiver) = mesh_channel::channel();
+        let mut storvsc = Storvsc::new(
+            guest,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+            new_request_receiver,
+            0,
+        )
+        .unwrap();
+
+        let payload = [0; storvsp_protocol::SCSI_REQUEST_LEN_MAX + 1];
+        let (_, mut writer) = storvsc.queue.split();
+        let err = storvsc
+            .inner
+            .send_vmbus_packet(
+                &mut writer.batched(),
+                OutgoingPacketType::InBandWithCompletion,
+                0,
+                storvsp_protocol::Operation::EXECUTE_SRB,
+                storvsp_protocol::NtStatus::SUCCESS,
+                &payload,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            StorvscError(StorvscErrorInner::PayloadTooLarge(len))
+                if len == storvsp_protocol::SCSI_REQUEST_LEN_MAX + 1
+        ));
+    }
+
+    #[async_test]
+    async fn test_read_too_large(driver: DefaultDriver) {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
+        let mut storvsc = StorvscDriver::<FlatRingMem>::new(
+            &driver_source,
+            storvsp_protocol::ProtocolVersion {
+                major_minor: storvsp_protocol::VERSION_BLUE,
+                reserved: 0,
+            },
+        );
+
+        // 4 GiB of sectors does not fit in the request's transfer length.
+        let blocks = u32::MAX / crate::SECTOR_SIZE as u32 + 1;
+        let err = storvsc.read(0, 0, blocks).await.unwrap_err();
+        assert!(matches!(
+            err,
+            StorvscError(StorvscErrorInner::TransferTooLarge(n)) if n == blocks.into()
+        ));
+    }
+
+    #[async_test]
+    async fn test_enumerate_bus(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let mut storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        storvsc.stop().await;
+        assert!(storvsc.get_mut().has_negotiated);
+        storvsc.resume().await;
+
+        // Inject an ENUMERATE_BUS command on the VMBus ring
+        let enumerate_bus_packet = storvsp_protocol::Packet {
+            operation: storvsp_protocol::Operation::ENUMERATE_BUS,
+            flags: 0,
+            status: storvsp_protocol::NtStatus::SUCCESS,
+        };
+        storvsp.send_vmbus_data_packet_no_completion(enumerate_bus_packet, 10, &());
+        // Nothing to evaluate here since this is a no-op without a completion for the time being.
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_enumerate_bus_debounce(driver: DefaultDriver) {
+        const WINDOW: Duration = Duration::from_millis(200);
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let mut storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_config(StorvscConfig::default().with_enumerate_bus_debounce(WINDOW));
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        // A burst of notifications results in a single rescan.
+        for transaction_id in 0..3 {
+            storvsp.send_vmbus_data_packet_no_completion(
+                storvsp_protocol::Packet {
+                    operation: storvsp_protocol::Operation::ENUMERATE_BUS,
+                    flags: 0,
+                    status: storvsp_protocol::NtStatus::SUCCESS,
+                },
+                transaction_id,
+                &(),
+            );
+        }
+        storvsc.rescans().next().await.unwrap();
+        timer.sleep(WINDOW * 2).await;
+        assert!(storvsc.rescans().next().now_or_never().is_none());
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_unknown_transaction_completion(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let mut storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        // Complete a transaction that storvsc never sent.
+        storvsp.send_vmbus_completion_packet(
+            storvsp_protocol::NtStatus::SUCCESS,
+            1000,
+            &storvsp_protocol::ScsiRequest::new_zeroed(),
+        );
+
+        // The driver keeps processing requests.
+        for _ in 0..2 {
+            storvsc
+                .send_request(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+                .await
+                .unwrap();
+        }
+
+        storvsc.stop().await;
+        assert!(storvsc.get_mut().inner.transactions.lock().is_empty());
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_completion_budget(driver: DefaultDriver) {
+        const FLOOD_COUNT: u64 = 256;
+
+        let (guest, host) = connected_async_channels(64 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let mut storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone())
+            .with_config(StorvscConfig::default().with_completion_budget(4));
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+
+        // Flood storvsc with completions, then submit requests while they are
+        // still being drained.
+        for i in 0..FLOOD_COUNT {
+            storvsp.send_vmbus_completion_packet(
+                storvsp_protocol::NtStatus::SUCCESS,
+                1000 + i,
+                &storvsp_protocol::ScsiRequest::new_zeroed(),
+            );
+        }
+        for _ in 0..4 {
+            futures::select! {
+                result = storvsc
+                    .send_request(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+                    .fuse() => {
+                    result.unwrap();
+                }
+                _ = timer.sleep(Duration::from_secs(5)).fuse() => {
+                    panic!("request starved by completions");
+                }
+            }
+        }
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_transaction_timeout(driver: DefaultDriver) {
+        const STALLED_LUN: u8 = 2;
+        const TIMEOUT: Duration = Duration::from_millis(200);
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start_with_stalled_lun(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            STALLED_LUN,
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone()).with_config(
+            StorvscConfig::default()
+                .with_transaction_timeout(TIMEOUT)
+                .with_reap_interval(Duration::from_millis(20)),
+        );
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        // Requests to other LUNs are unaffected.
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 1, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        futures::select! {
+            result = storvsc
+                .send_request(&generate_read_packet(0, 1, STALLED_LUN, 0, 4096), 4096, 4096)
+                .fuse() => {
+                assert!(matches!(
+                    result,
+                    Err(StorvscError(StorvscErrorInner::CancelledWithReason(_)))
+                ));
+            }
+            _ = timer.sleep(Duration::from_secs(5)).fuse() => {
+                panic!("stalled request was not reaped");
+            }
+        }
+        assert!(start.elapsed() >= TIMEOUT);
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_outstanding(driver: DefaultDriver) {
+        const STALLED_LUN: u8 = 2;
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start_with_stalled_lun(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            STALLED_LUN,
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        // Completed requests are not outstanding.
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 1, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+        let transaction_id = storvsc
+            .submit(
+                &generate_write_packet(0, 1, STALLED_LUN, 8, 4096),
+                4096,
+                4096,
+            )
+            .unwrap();
+
+        // Requests are sent in order, so once a later request completes the
+        // stalled one is known to be in flight.
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 1, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+
+        let outstanding =
+            storvsc.outstanding_at(Instant::now().saturating_add(Duration::from_millis(100)));
+        assert_eq!(outstanding.len(), 1);
+        let request = &outstanding[0];
+        assert_eq!(request.transaction_id, transaction_id);
+        assert_eq!(request.lun, STALLED_LUN);
+        assert_eq!(request.op, ScsiOp::WRITE);
+        assert!(request.age >= Duration::from_millis(100));
+        assert!(!request.cancelled);
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_driver_cancel_lun(driver: DefaultDriver) {
+        const STALLED_LUN: u8 = 2;
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start_with_stalled_lun(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+            STALLED_LUN,
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        // The cancellation is queued behind the submitted request, so it is
+        // cancelled while the worker keeps running.
+        let transaction_id = storvsc
+            .submit(
+                &generate_write_packet(0, 1, STALLED_LUN, 8, 4096),
+                4096,
+                4096,
+            )
+            .unwrap();
+        assert_eq!(
+            storvsc.cancel_lun(1, 0, STALLED_LUN, "disk removed").await,
+            1
+        );
+
+        let (completed_id, result) = storvsc.completions().next().await.unwrap();
+        assert_eq!(completed_id, transaction_id);
+        assert!(matches!(
+            result,
+            Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+                if reason == "disk removed"
+        ));
+
+        // Other LUNs are unaffected.
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 1, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_renegotiate(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        // Wait for negotiation or panic.
+        let mut timer = PolledTimer::new(&driver);
+        let negotiation_timeout_millis = 1000;
+        storvsc
+            .wait_for_negotiation(&mut timer, negotiation_timeout_millis)
+            .await;
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+
+        // Reset the channel, queueing a request while it is down.
+        storvsc.stop().await;
+        storvsp.teardown().await;
+        let transaction_id = storvsc
+            .submit(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+            .unwrap();
+        storvsc.caching.insert(
+            2,
+            CachingInfo {
+                write_cache_enabled: true,
+                read_cache_disabled: false,
+            },
+        );
+
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        storvsc.renegotiate(guest).await.unwrap();
+
+        // The LUNs may have been reset along with the channel.
+        assert_eq!(storvsc.caching.get(2), None);
+
+        // The queued request is sent over the new channel.
+        let (completed_id, result) = storvsc.completions().next().await.unwrap();
+        assert_eq!(completed_id, transaction_id);
+        result.unwrap();
+
+        storvsc.stop().await;
+        assert!(storvsc.get_mut().has_negotiated);
+        storvsc.resume().await;
+        storvsc
+            .send_request(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+            .await
+            .unwrap();
+
+        storvsc.teardown().await;
+        storvsp.teardown().await;
+    }
+
+    #[async_test]
+    async fn test_renegotiate_failure(driver: DefaultDriver) {
+        let (guest, host) = connected_async_channels(16 * 1024);
+        let host_queue = Queue::new(host).unwrap();
+        let test_guest_mem = GuestMemory::allocate(16384);
+
+        let storvsp = TestStorvspWorker::start(
+            driver.clone(),
+            test_guest_mem.clone(),
+            host_queue,
+            Vec::new(),
+        );
+        let mut storvsc = TestStorvscWorker::new(driver.clone());
+        storvsc.start(guest);
+
+        let mut timer = PolledTimer::new(&driver);
+        storvsc.wait_for_negotiation(&mut timer, 1000).await;
+
+        // Reset the channel, queueing a request while it is down.
+        storvsc.stop().await;
+        storvsp.teardown().await;
+        let transaction_id = storvsc
+            .submit(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096)
+            .unwrap();
+
+        // The new channel is closed before storvsp answers, so negotiation
+        // fails.
+        let (guest, host) = connected_async_channels(16 * 1024);
+        drop(host);
+        storvsc.renegotiate(guest).await.unwrap_err();
+
+        // The queued request is cancelled as the worker shuts down, and the
+        // driver must be started again before it accepts new requests.
+        let (completed_id, result) = storvsc.completions().next().await.unwrap();
+        assert_eq!(completed_id, transaction_id);
+        assert!(matches!(
+            result,
+            Err(StorvscError(StorvscErrorInner::CancelledWithReason(reason)))
+                if reason == "shutdown"
+        ));
+        assert!(storvsc.new_request_sender.is_none());
+        assert!(storvsc.driver.is_none());
+        assert!(matches!(
+            storvsc.submit(&generate_read_packet(0, 1, 2, 0, 4096), 4096, 4096),
+            Err(StorvscError(StorvscErrorInner::Uninitialized))
+        ));
+        let (guest, _host) = connected_async_channels(16 * 1024);
+        assert!(matches!(
+            storvsc.renegotiate(guest).await,
+            Err(StorvscError(StorvscErrorInner::Uninitialized))
+        ));
+    }
 }
@@ -7,11 +7,10 @@
 
 use crate::PacketError;
 use crate::Storvsc;
-use crate::StorvscCompletion;
+use crate::StorvscConfig;
+use crate::StorvscDriver;
 use crate::StorvscError;
 use crate::StorvscErrorInner;
-use crate::StorvscRequest;
-use crate::StorvscState;
 use futures::FutureExt;
 use futures_concurrency::future::Race;
 use guestmem::GuestMemory;
@@ -21,16 +20,18 @@
 use mesh_channel::Receiver;
 use mesh_channel::RecvError;
 use mesh_channel::Sender;
+use pal_async::DefaultDriver;
 use pal_async::task::Spawn;
 use pal_async::task::Task;
 use pal_async::timer::PolledTimer;
 use scsi_buffers::RequestBuffers;
 use std::future::poll_fn;
+use std::ops::Deref;
+use std::ops::DerefMut;
 use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use std::time;
-use task_control::TaskControl;
 use thiserror::Error;
 use vmbus_async::queue;
 use vmbus_async::queue::IncomingPacket;
@@ -42,6 +43,8 @@
 use vmbus_ring::OutgoingPacketType;
 use vmbus_ring::RingMem;
 use vmbus_ring::gparange::MultiPagedRangeBuf;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
 use zerocopy::FromZeros;
 use zerocopy::Immutable;
 use zerocopy::IntoBytes;
@@ -215,56 +218,69 @@ fn parse_storvsp_packet<T: RingMem>(
 }
 
 /// Test worker for driving a storvsc instance in unit tests.
+///
+/// Dereferences to the [`StorvscDriver`] it wraps, for sending requests.
 pub struct TestStorvscWorker<T: Send + Sync + RingMem> {
-    task: TaskControl<StorvscState, Storvsc<T>>,
-    new_request_sender: Option<Sender<StorvscRequest>>,
+    driver: StorvscDriver<T>,
 }
 
 impl<T: 'static + Send + Sync + RingMem> TestStorvscWorker<T> {
-    /// Creates a storvsc test worker.
-    pub fn new() -> Self {
+    /// Creates a storvsc test worker that runs on `driver`.
+    pub fn new(driver: DefaultDriver) -> Self {
+        let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver));
         Self {
-            task: TaskControl::new(StorvscState),
-            new_request_sender: None,
+            driver: StorvscDriver::new(
+                &driver_source,
+                storvsp_protocol::ProtocolVersion {
+                    major_minor: storvsp_protocol::VERSION_BLUE,
+                    reserved: 0,
+                },
+            ),
         }
     }
 
-    /// Starts the storvsc task on `channel`.
-    pub fn start(&mut self, spawner: impl Spawn, channel: RawAsyncChannel<T>) {
-        let (new_request_sender, new_request_receiver) = mesh_channel::channel::<StorvscRequest>();
-        let storvsc = Storvsc::new(
-            channel,
-            storvsp_protocol::ProtocolVersion {
-                major_minor: storvsp_protocol::VERSION_BLUE,
-                reserved: 0,
-            },
-            new_request_receiver,
-        )
-        .unwrap();
-        self.new_request_sender = Some(new_request_sender);
+    /// Requests protocol version `major_minor` when negotiating, instead of
+    /// BLUE.
+    pub fn with_version(mut self, major_minor: u16) -> Self {
+        self.driver.version = storvsp_protocol::ProtocolVersion {
+            major_minor,
+            reserved: 0,
+        };
+        self
+    }
+
+    /// Applies `config` when the worker is started.
+    pub fn with_config(mut self, config: StorvscConfig) -> Self {
+        self.driver = self.driver.with_config(config);
+        self
+    }
 
-        self.task.insert(spawner, "storvsc", storvsc);
-        self.task.start();
+    /// Starts the storvsc task on `channel`, leaving it to negotiate in the
+    /// background.
+    pub fn start(&mut self, channel: RawAsyncChannel<T>) {
+        let driver = self.driver.driver_source.simple();
+        let (storvsc, new_request_sender) = self.driver.new_worker(&driver, channel).unwrap();
+        self.driver
+            .start_worker(driver, storvsc, new_request_sender);
     }
 
     /// Stops the storvsc task.
     pub async fn stop(&mut self) {
-        self.task.stop().await;
+        self.driver.storvsc.stop().await;
     }
 
     /// Resumes the storvsc task.
     pub async fn resume(&mut self) {
-        self.task.start();
+        self.driver.storvsc.start();
     }
 
     pub(crate) fn get_mut(&mut self) -> &Storvsc<T> {
-        self.task.get_mut().1.unwrap()
+        self.driver.storvsc.get_mut().1.unwrap()
     }
 
     /// Stops and removes the storvsc task.
     pub async fn teardown(&mut self) {
-        self.task.stop().await;
-        self.task.remove();
+        self.driver.stop().await;
     }
 
     /// Waits for negotiation to complete or panics.
@@ -280,9 +296,9 @@ pub async fn wait_for_negotiation(
             timer
                 .sleep(time::Duration::from_millis(interval_millis))
                 .await;
-            self.task.stop().await;
-            has_negotiated = self.task.state().unwrap().has_negotiated;
-            self.task.start();
+            self.driver.storvsc.stop().await;
+            has_negotiated = self.driver.storvsc.state().unwrap().has_negotiated;
+            self.driver.storvsc.start();
             if has_negotiated {
                 break;
             }
@@ -291,39 +307,25 @@ pub async fn wait_for_negotiation(
             panic!("storvsc negotiation did not complete within timeout");
         }
     }
+}
 
-    /// Send a SCSI request to storvsp over VMBus.
-    pub async fn send_request(
-        &mut self,
-        request: &storvsp_protocol::ScsiRequest,
-        buf_gpa: u64,
-        byte_len: usize,
-    ) -> Result<storvsp_protocol::ScsiRequest, StorvscError> {
-        let (sender, mut receiver) = mesh_channel::channel::<StorvscCompletion>();
-        let storvsc_request = StorvscRequest {
-            request: *request,
-            buf_gpa,
-            byte_len,
-            completion_sender: sender,
-        };
-        match &self.new_request_sender {
-            Some(request_sender) => {
-                request_sender.send(storvsc_request);
-                Ok(())
-            }
-            None => Err(StorvscError(StorvscErrorInner::Uninitialized)),
-        }?;
-
-        let resp = receiver
-            .recv()
-            .await
-            .map_err(|err| StorvscError(StorvscErrorInner::CompletionError(err)))?;
-
-        if let Some(completion) = resp.completion {
-            Ok(completion)
-        } else {
-            Err(StorvscError(StorvscErrorInner::Cancelled))
-        }
+impl<T: Send + Sync + RingMem> Deref for TestStorvscWorker<T> {
+    type Target = StorvscDriver<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.driver
+    }
+}
+
+impl<T: Send + Sync + RingMem> DerefMut for TestStorvscWorker<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.driver
+    }
+}
+
+impl<T: 'static + Send + Sync + RingMem> Inspect for TestStorvscWorker<T> {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        self.driver.inspect(req)
     }
 }
 
@@ -340,6 +342,10 @@ struct TestStorvsp {
     subchannel_count: u16,
     command_request_receiver: Receiver<TestStorvspCommandRequest>,
     inner: TestStorvspInner,
+    /// The number of BEGIN_INITIALIZATION requests to fail as busy.
+    busy_begin_count: usize,
+    /// A LUN whose SCSI requests are never completed.
+    stalled_lun: Option<u8>,
 }
 
 struct TestStorvspInner {
@@ -349,7 +355,7 @@ struct TestStorvspInner {
 pub(crate) struct TestStorvspCommandRequest {
     packet: storvsp_protocol::Packet,
     transaction_id: u64,
-    requires_completion: bool,
+    packet_type: OutgoingPacketType<'static>,
     payload: [u8; storvsp_protocol::SCSI_REQUEST_LEN_MAX],
     payload_size: usize,
 }
@@ -360,12 +366,60 @@ pub fn start(
         mem: GuestMemory,
         queue: Queue<FlatRingMem>,
         full_request_pool: Vec<Arc<ScsiRequestAndRange>>,
+    ) -> Self {
+        Self::start_with_busy_begin(spawner, mem, queue, full_request_pool, 0)
+    }
+
+    /// Starts a test storvsp that fails the first `busy_begin_count`
+    /// BEGIN_INITIALIZATION requests with `DEVICE_BUSY`.
+    pub fn start_with_busy_begin(
+        spawner: impl Spawn,
+        mem: GuestMemory,
+        queue: Queue<FlatRingMem>,
+        full_request_pool: Vec<Arc<ScsiRequestAndRange>>,
+        busy_begin_count: usize,
+    ) -> Self {
+        Self::start_inner(
+            spawner,
+            mem,
+            queue,
+            full_request_pool,
+            busy_begin_count,
+            None,
+        )
+    }
+
+    /// Starts a test storvsp that never completes SCSI requests to
+    /// `stalled_lun`, as if the host were hung.
+    pub fn start_with_stalled_lun(
+        spawner: impl Spawn,
+        mem: GuestMemory,
+        queue: Queue<FlatRingMem>,
+        full_request_pool: Vec<Arc<ScsiRequestAndRange>>,
+        stalled_lun: u8,
+    ) -> Self {
+        Self::start_inner(spawner, mem, queue, full_request_pool, 0, Some(stalled_lun))
+    }
+
+    fn start_inner(
+        spawner: impl Spawn,
+        mem: GuestMemory,
+        queue: Queue<FlatRingMem>,
+        full_request_pool: Vec<Arc<ScsiRequestAndRange>>,
+        busy_begin_count: usize,
+        stalled_lun: Option<u8>,
     ) -> Self {
         let (command_request_sender, command_request_receiver) =
             mesh_channel::channel::<TestStorvspCommandRequest>();
         let task = spawner.spawn("test_storvsp", async move {
-            let mut worker =
-                TestStorvsp::new(mem, queue, full_request_pool, command_request_receiver);
+            let mut worker = TestStorvsp::new(
+                mem,
+                queue,
+                full_request_pool,
+                command_request_receiver,
+                busy_begin_count,
+                stalled_lun,
+            );
             worker.run().await;
         });
 
@@ -391,7 +445,31 @@ pub fn send_vmbus_data_packet_no_completion<P: IntoBytes + Immutable + KnownLayo
         self.command_request_sender.send(TestStorvspCommandRequest {
             packet,
             transaction_id,
-            requires_completion: false,
+            packet_type: OutgoingPacketType::InBandNoCompletion,
+            payload: payload_bytes,
+            payload_size: payload_bytes_slice.len(),
+        })
+    }
+
+    /// Sends a completion packet for `transaction_id`, whether or not storvsc
+    /// has a request outstanding with that ID.
+    pub fn send_vmbus_completion_packet<P: IntoBytes + Immutable + KnownLayout>(
+        &mut self,
+        status: storvsp_protocol::NtStatus,
+        transaction_id: u64,
+        payload: &P,
+    ) {
+        let payload_bytes_slice = payload.as_bytes();
+        let mut payload_bytes = [0_u8; storvsp_protocol::SCSI_REQUEST_LEN_MAX];
+        payload_bytes[..payload_bytes_slice.len()].clone_from_slice(payload_bytes_slice);
+        self.command_request_sender.send(TestStorvspCommandRequest {
+            packet: storvsp_protocol::Packet {
+                operation: storvsp_protocol::Operation::COMPLETE_IO,
+                flags: 0,
+                status,
+            },
+            transaction_id,
+            packet_type: OutgoingPacketType::Completion,
             payload: payload_bytes,
             payload_size: payload_bytes_slice.len(),
         })
@@ -404,6 +482,8 @@ fn new(
         queue: Queue<FlatRingMem>,
         full_request_pool: Vec<Arc<ScsiRequestAndRange>>,
         command_request_receiver: Receiver<TestStorvspCommandRequest>,
+        busy_begin_count: usize,
+        stalled_lun: Option<u8>,
     ) -> Self {
         TestStorvsp {
             _mem: mem,
@@ -418,6 +498,8 @@ fn new(
             inner: TestStorvspInner {
                 request_size: storvsp_protocol::SCSI_REQUEST_LEN_V1,
             },
+            busy_begin_count,
+            stalled_lun,
         }
     }
 
@@ -452,7 +534,15 @@ async fn negotiate(&mut self) -> Result<(), StorvscError> {
                     // fail due to lack of ring space, to avoid keeping (and saving/restoring) interim states.
                     poll_fn(|cx| self.inner.poll_for_ring_space(cx, &mut writer)).await?;
 
-                    if !has_begin_initialization
+                    if self.busy_begin_count > 0 {
+                        self.busy_begin_count -= 1;
+                        self.inner.send_completion(
+                            &mut writer,
+                            &stor_packet,
+                            storvsp_protocol::NtStatus::DEVICE_BUSY,
+                            &(),
+                        )?;
+                    } else if !has_begin_initialization
                         && !has_query_protocol_version
                         && !has_query_properties
                         && !has_end_initialization
@@ -482,9 +572,8 @@ async fn negotiate(&mut self) -> Result<(), StorvscError> {
                         && !has_query_properties
                         && !has_end_initialization
                     {
-                        has_query_protocol_version = true;
-
                         if let Ok(version) = Version::parse(major_minor) {
+                            has_query_protocol_version = true;
                             self.inner.send_completion(
                                 &mut writer,
                                 &stor_packet,
@@ -629,11 +718,7 @@ enum Event<'a, M: RingMem> {
                 Event::NewCommandRequestReceived(result) => match result {
                     Ok(request) => self.inner.send_vmbus_packet(
                         &mut writer.batched(),
-                        if request.requires_completion {
-                            OutgoingPacketType::InBandWithCompletion
-                        } else {
-                            OutgoingPacketType::InBandNoCompletion
-                        },
+                        request.packet_type,
                         request.payload_size,
                         request.transaction_id,
                         request.packet.operation,
@@ -650,13 +735,18 @@ enum Event<'a, M: RingMem> {
                         tracing::info!("storvsp received request packet");
 
                         match stor_packet.data.clone() {
-                            StorvspPacketData::ExecuteScsi(_request) => {
+                            StorvspPacketData::ExecuteScsi(request)
+                                if Some(request.request.lun) == self.stalled_lun =>
+                            {
+                                tracing::info!("storvsp ignoring EXECUTE_SRB to stalled LUN");
+                            }
+                            StorvspPacketData::ExecuteScsi(request) => {
                                 tracing::info!("storvsp responding to EXECUTE_SRB");
                                 self.inner.send_completion(
                                     &mut writer,
                                     &stor_packet,
                                     storvsp_protocol::NtStatus::SUCCESS,
-                                    &(),
+                                    &scsi_response(&request.request),
                                 )?;
                             }
                             _ => {
@@ -678,6 +768,26 @@ enum Event<'a, M: RingMem> {
     }
 }
 
+/// Builds a successful response to `request`. If the request asks for sense
+/// data, the response carries that many bytes of (nonzero) sense data.
+fn scsi_response(request: &storvsp_protocol::ScsiRequest) -> storvsp_protocol::ScsiRequest {
+    let sense_len =
+        (request.sense_info_ex_length as usize).min(storvsp_protocol::VMSCSI_SENSE_BUFFER_SIZE);
+    let mut response = storvsp_protocol::ScsiRequest {
+        length: size_of::<storvsp_protocol::ScsiRequest>() as u16,
+        data_transfer_length: request.data_transfer_length,
+        sense_info_ex_length: sense_len as u8,
+        ..storvsp_protocol::ScsiRequest::new_zeroed()
+    };
+    if sense_len > 0 {
+        response.srb_status = response.srb_status.with_autosense_valid(true);
+        for (i, b) in response.payload[..sense_len].iter_mut().enumerate() {
+            *b = 0x70 + i as u8;
+        }
+    }
+    response
+}
+
 impl TestStorvspInner {
     fn send_completion<M: RingMem, P: IntoBytes + Immutable + KnownLayout>(
         &mut self,
@@ -26,8 +26,15 @@
 
 const PAGE_SIZE: u64 = 4096;
 
+/// Returns the length in bytes of `size_pages` pages, or `None` if it does not
+/// fit in a `usize`.
+fn pages_to_len(size_pages: u64) -> Option<usize> {
+    size_pages.checked_mul(PAGE_SIZE)?.try_into().ok()
+}
+
 /// Save restore suport for [`PagePool`].
 pub mod save_restore {
+    use super::DeviceId;
     use super::PAGE_SIZE;
     use super::PagePool;
     use super::Slot;
@@ -57,6 +64,8 @@ enum InnerSlotState {
             #[mesh(2)]
             tag: String,
         },
+        #[mesh(4)]
+        Carved,
     }
 
     #[derive(Protobuf)]
@@ -80,6 +89,16 @@ pub struct PagePoolState {
         ranges: Vec<MemoryRange>,
     }
 
+    /// Options for [`PagePool::restore_with_options`].
+    #[derive(Debug, Copy, Clone, Default)]
+    pub struct RestoreOptions {
+        /// Allow the saved state to describe only a prefix of the memory
+        /// mapped by the pool, such as when the pool was given more memory
+        /// across servicing. The memory not covered by the saved state is
+        /// restored as free.
+        pub allow_smaller_saved_state: bool,
+    }
+
     impl SaveRestore for PagePool {
         type SavedState = PagePoolState;
 
@@ -105,6 +124,7 @@ fn save(&mut self) -> Result<Self::SavedState, vmcore::save_restore::SaveError>
                                     tag: tag.to_string(),
                                 }
                             }
+                            ResolvedSlotState::Carved => InnerSlotState::Carved,
                             ResolvedSlotState::AllocatedPendingRestore { .. } => {
                                 panic!("should not save allocated pending restore")
                             }
@@ -117,18 +137,69 @@ fn save(&mut self) -> Result<Self::SavedState, vmcore::save_restore::SaveError>
                         }
                     })
                     .collect(),
-                ranges: self.ranges.clone(),
+                ranges: self.inner.ranges.clone(),
             })
         }
 
         fn restore(
             &mut self,
-            mut state: Self::SavedState,
+            state: Self::SavedState,
+        ) -> Result<(), vmcore::save_restore::RestoreError> {
+            self.restore_slots(state, false, RestoreOptions::default())
+        }
+    }
+
+    impl PagePool {
+        /// Restores the pool like [`SaveRestore::restore`], but allows
+        /// allocators to be created before the pool is restored, which eases
+        /// ordering constraints during servicing.
+        ///
+        /// Restored allocations are linked to allocators by device name, so an
+        /// existing allocator picks up the allocations saved under its name via
+        /// [`crate::PagePoolAllocator::restore_alloc`] or
+        /// [`crate::PagePoolAllocator::restore_pending_allocs`]. The pool must
+        /// not have any allocations, and each existing allocator must have
+        /// allocations in the saved state.
+        pub fn restore_with_allocators(
+            &mut self,
+            state: PagePoolState,
+        ) -> Result<(), vmcore::save_restore::RestoreError> {
+            self.restore_slots(state, true, RestoreOptions::default())
+        }
+
+        /// Restores the pool like [`SaveRestore::restore`], with the behavior
+        /// adjusted by `options`.
+        pub fn restore_with_options(
+            &mut self,
+            state: PagePoolState,
+            options: RestoreOptions,
+        ) -> Result<(), vmcore::save_restore::RestoreError> {
+            self.restore_slots(state, false, options)
+        }
+
+        fn restore_slots(
+            &mut self,
+            mut state: PagePoolState,
+            allow_existing_allocators: bool,
+            options: RestoreOptions,
         ) -> Result<(), vmcore::save_restore::RestoreError> {
             // Verify that the pool describes the same regions of memory as the
-            // saved state.
-            for (current, saved) in self.ranges.iter().zip(state.ranges.iter()) {
-                if current != saved {
+            // saved state. When a smaller saved state is allowed, the last
+            // saved range may end before the corresponding pool range.
+            let saved_range_count = state.ranges.len();
+            for (i, (current, saved)) in self
+                .inner
+                .ranges
+                .iter()
+                .zip(state.ranges.iter())
+                .enumerate()
+            {
+                let truncated = options.allow_smaller_saved_state
+                    && i + 1 == saved_range_count
+                    && current.start() == saved.start()
+                    && current.end() > saved.end();
+
+                if current != saved && !truncated {
                     // TODO: return unmatched range or vecs?
                     return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
                         anyhow::anyhow!("pool ranges do not match"),
@@ -136,18 +207,88 @@ fn restore(
                 }
             }
 
+            // The saved ranges must cover exactly the memory mapped by the
+            // pool, or a prefix of it if allowed. Checking this up front
+            // catches saved states with extra or missing ranges, which the
+            // comparison above does not.
+            let saved_len = state.ranges.iter().map(|range| range.len()).sum::<u64>();
+            let mapping_len = self.inner.mapping.len() as u64;
+            if saved_len > mapping_len
+                || (saved_len < mapping_len && !options.allow_smaller_saved_state)
+            {
+                return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
+                    anyhow::anyhow!(
+                        "saved ranges cover {saved_len:#x} bytes, but the pool maps {mapping_len:#x} bytes"
+                    ),
+                ));
+            }
+
             let mut inner = self.inner.state.lock();
 
-            // Verify there are no existing allocators present, as we rely on
-            // the pool being completely free since we will overwrite the state
-            // of the pool with the stored slot info.
-            //
-            // Note that this also means that the pool does not have any pending
-            // allocations, as it's impossible to allocate without creating an
-            // allocator.
-            if !inner.device_ids.is_empty() {
+            if allow_existing_allocators {
+                // Existing allocators keep their device IDs, but the pool must
+                // still be completely free since we will overwrite the state of
+                // the pool with the stored slot info.
+                if inner
+                    .slots
+                    .iter()
+                    .any(|slot| !matches!(slot.state, SlotState::Free))
+                {
+                    return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
+                        anyhow::anyhow!(
+                            "existing allocations present, pool must be empty to restore"
+                        ),
+                    ));
+                }
+
+                // Each existing allocator must match a device saved with
+                // allocations, or it could never pick up any restored state.
+                let saved_ids = state
+                    .state
+                    .iter()
+                    .filter_map(|slot| match &slot.state {
+                        InnerSlotState::Allocated { device_id, .. }
+                        | InnerSlotState::Leaked { device_id, .. } => Some(device_id.as_str()),
+                        InnerSlotState::Free | InnerSlotState::Carved => None,
+                    })
+                    .collect::<Vec<_>>();
+                let unmatched = inner
+                    .device_ids
+                    .iter()
+                    .filter_map(|device_id| match device_id {
+                        DeviceId::Used(name) if !saved_ids.contains(&name.as_str()) => {
+                            Some(name.as_str())
+                        }
+                        DeviceId::Used(_) | DeviceId::Unassigned(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+                if !unmatched.is_empty() {
+                    return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
+                        anyhow::anyhow!(
+                            "existing allocators not found in saved state: {unmatched:?}"
+                        ),
+                    ));
+                }
+            } else if !inner.device_ids.is_empty() {
+                // Verify there are no existing allocators present, as we rely on
+                // the pool being completely free since we will overwrite the
+                // state of the pool with the stored slot info.
+                //
+                // Note that this also means that the pool does not have any
+                // pending allocations, as it's impossible to allocate without
+                // creating an allocator.
+                let in_use = inner
+                    .device_ids
+                    .iter()
+                    .filter_map(|device_id| match device_id {
+                        DeviceId::Used(name) => Some(name.as_str()),
+                        DeviceId::Unassigned(_) => None,
+                    })
+                    .collect::<Vec<_>>();
                 return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
-                    anyhow::anyhow!("existing allocators present, pool must be empty to restore"),
+                    anyhow::anyhow!(
+                        "existing allocators present, pool must be empty to restore (in use: {in_use:?})"
+                    ),
                 ));
             }
 
@@ -166,6 +307,7 @@ fn restore(
                         InnerSlotState::Leaked { device_id, tag } => {
                             SlotState::Leaked { device_id, tag }
                         }
+                        InnerSlotState::Carved => SlotState::Carved,
                     };
 
                     let slot = Slot {
@@ -179,12 +321,29 @@ fn restore(
                 })
                 .collect();
 
-            if mapping_offset != self.inner.mapping.len() as u64 {
+            if mapping_offset != saved_len {
                 return Err(vmcore::save_restore::RestoreError::InvalidSavedState(
                     anyhow::anyhow!("missing slots in saved state"),
                 ));
             }
 
+            // Fill the memory not covered by the saved state with free slots,
+            // one per pool range.
+            let mut range_offset = 0;
+            for range in &self.inner.ranges {
+                let range_end = range_offset + range.len();
+                if range_end > mapping_offset {
+                    let skip = mapping_offset.saturating_sub(range_offset);
+                    inner.slots.push(Slot {
+                        base_pfn: (range.start() + skip) / PAGE_SIZE,
+                        mapping_offset: (range_offset + skip) as usize,
+                        size_pages: (range.len() - skip) / PAGE_SIZE,
+                        state: SlotState::Free,
+                    });
+                }
+                range_offset = range_end;
+            }
+
             Ok(())
         }
     }
@@ -207,6 +366,129 @@ pub enum Error {
     /// No matching allocation found for restore.
     #[error("no matching allocation found for restore")]
     NoMatchingAllocation,
+    /// The requested allocation size cannot be represented in bytes.
+    #[error("invalid page pool allocation size {size} with tag {tag}")]
+    InvalidSize {
+        /// The size in pages of the allocation.
+        size: u64,
+        /// The tag of the allocation.
+        tag: String,
+    },
+    /// The requested allocation is larger than the pool allows for a single
+    /// allocation.
+    #[error("page pool allocation size {size} with tag {tag} exceeds maximum of {max}")]
+    AllocationTooLarge {
+        /// The size in pages of the allocation.
+        size: u64,
+        /// The maximum size in pages of a single allocation.
+        max: u64,
+        /// The tag of the allocation.
+        tag: String,
+    },
+    /// The allocation was restricted to a range that the pool does not have.
+    #[error("page pool has no range {index} for allocation with tag {tag}")]
+    InvalidRangeIndex {
+        /// The index of the range.
+        index: usize,
+        /// The tag of the allocation.
+        tag: String,
+    },
+}
+
+/// The direction of device access for an allocation, which determines the
+/// protection of the CPU mapping.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DmaAccess {
+    /// The device both reads and writes the buffer. The CPU mapping is
+    /// read-write.
+    #[default]
+    Bidirectional,
+    /// The device only reads the buffer (e.g. TX). The CPU must fill the
+    /// buffer, so the CPU mapping is read-write.
+    ToDevice,
+    /// The device only writes the buffer (e.g. RX). The CPU mapping is
+    /// read-only to catch accidental CPU writes.
+    ///
+    /// On platforms that cannot change the protection of the mapping, the
+    /// CPU mapping is read-write.
+    FromDevice,
+}
+
+impl DmaAccess {
+    fn cpu_writable(&self) -> bool {
+        match self {
+            DmaAccess::Bidirectional | DmaAccess::ToDevice => true,
+            DmaAccess::FromDevice => false,
+        }
+    }
+}
+
+/// How the pool chooses the free slot to satisfy an allocation from.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Inspect)]
+pub enum AllocStrategy {
+    /// Use the first free slot that is large enough.
+    #[default]
+    FirstFit,
+    /// Use the smallest free slot that is large enough, leaving larger
+    /// contiguous regions for larger allocations.
+    BestFit,
+}
+
+/// Which end of the pool an allocation should be taken from, as passed to
+/// [`PagePoolAllocator::alloc_with_preference`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum AddressPreference {
+    /// Take the lowest free pages that fit, e.g. for devices that can only
+    /// address 32 bits.
+    Low,
+    /// Take the highest free pages that fit, leaving low pages for devices
+    /// that need them.
+    High,
+    /// Choose according to the pool's [`AllocStrategy`].
+    #[default]
+    Any,
+    /// Choose the free slot according to the pool's [`AllocStrategy`], but
+    /// take the highest pages in it, leaving the front of the slot free for
+    /// later allocations that prefer low addresses.
+    SlotEnd,
+}
+
+/// A summary of the usage and fragmentation of a [`PagePool`], as returned by
+/// [`PagePool::stats`].
+#[derive(Debug, Clone, PartialEq, Eq, Inspect)]
+pub struct PagePoolStats {
+    /// The total number of pages in the pool.
+    pub total_pages: u64,
+    /// The number of free pages.
+    pub free_pages: u64,
+    /// The size of the largest contiguous run of free pages, which bounds the
+    /// largest allocation that can currently succeed.
+    pub largest_free_run_pages: u64,
+    /// The number of pages allocated, including allocations pending restore.
+    pub allocated_pages: u64,
+    /// The number of allocated pages that were restored from saved state but
+    /// have not yet been claimed by their owner.
+    pub pending_restore_pages: u64,
+    /// The number of pages leaked by allocations that were not restored.
+    pub leaked_pages: u64,
+    /// The number of pages carved out into subpools by
+    /// [`PagePool::carve_subpool`].
+    pub carved_pages: u64,
+    /// The number of free slots.
+    pub free_slot_count: usize,
+}
+
+impl PagePoolStats {
+    /// Returns the fraction of free pages that are outside the largest
+    /// contiguous free run, from 0.0 when the free space is contiguous (or
+    /// there is none) to approaching 1.0 when it is split into many small
+    /// runs.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_pages == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_run_pages as f64 / self.free_pages as f64
+    }
 }
 
 /// Error returned when unrestored allocations are found.
@@ -214,7 +496,7 @@ pub enum Error {
 #[error("unrestored allocations found")]
 pub struct UnrestoredAllocations;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 struct Slot {
     base_pfn: u64,
     mapping_offset: usize,
@@ -222,6 +504,15 @@ struct Slot {
     state: SlotState,
 }
 
+impl Slot {
+    /// Returns true if `next` immediately follows this slot both in pfn space
+    /// and in the pool mapping.
+    fn is_followed_by(&self, next: &Slot) -> bool {
+        self.base_pfn + self.size_pages == next.base_pfn
+            && self.mapping_offset + (self.size_pages * PAGE_SIZE) as usize == next.mapping_offset
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum SlotState {
     Free,
@@ -242,6 +533,8 @@ enum SlotState {
         device_id: String,
         tag: String,
     },
+    /// These pages were given to a subpool by [`PagePool::carve_subpool`].
+    Carved,
 }
 
 impl Slot {
@@ -264,6 +557,7 @@ fn resolve<'a>(&'a self, device_ids: &'a [DeviceId]) -> ResolvedSlot<'a> {
                     ref device_id,
                     ref tag,
                 } => ResolvedSlotState::Leaked { device_id, tag },
+                SlotState::Carved => ResolvedSlotState::Carved,
             },
         }
     }
@@ -302,6 +596,7 @@ enum ResolvedSlotState<'a> {
     Allocated { device_id: &'a str, tag: &'a str },
     AllocatedPendingRestore { device_id: &'a str, tag: &'a str },
     Leaked { device_id: &'a str, tag: &'a str },
+    Carved,
 }
 
 #[derive(Inspect, Debug, Clone, PartialEq, Eq)]
@@ -330,9 +625,12 @@ struct PagePoolInner {
     /// The pfn_bias for the pool.
     pfn_bias: u64,
     /// The mapper used to create mappings for allocations.
-    source: Box<dyn PoolSource>,
+    source: Arc<dyn PoolSource>,
     #[inspect(skip)]
     mapping: SparseMapping,
+    /// The memory ranges managed by the pool, in mapping order.
+    #[inspect(iter_by_index)]
+    ranges: Vec<MemoryRange>,
 }
 
 impl Debug for PagePoolInner {
@@ -341,6 +639,7 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             .field("state", &self.state)
             .field("pfn_bias", &self.pfn_bias)
             .field("mapping", &self.mapping)
+            .field("ranges", &self.ranges)
             .finish()
     }
 }
@@ -352,15 +651,120 @@ struct PagePoolState {
     /// The list of device ids for outstanding allocators. Each name must be
     /// unique.
     device_ids: Vec<DeviceId>,
+    /// The maximum size in pages of a single allocation, if limited.
+    max_alloc_pages: Option<u64>,
+    /// How free slots are chosen for new allocations.
+    alloc_strategy: AllocStrategy,
 }
 
 impl Inspect for PagePoolState {
     fn inspect(&self, req: inspect::Request<'_>) {
-        let Self { slots, device_ids } = self;
-        req.respond().field(
-            "slots",
-            inspect::iter_by_index(slots).map_value(|s| s.resolve(device_ids)),
-        );
+        let Self {
+            slots,
+            device_ids,
+            max_alloc_pages,
+            alloc_strategy,
+        } = self;
+        let stats = self.stats();
+        req.respond()
+            .field(
+                "slots",
+                inspect::iter_by_index(slots).map_value(|s| s.resolve(device_ids)),
+            )
+            .field("max_alloc_pages", max_alloc_pages)
+            .field("alloc_strategy", alloc_strategy)
+            .field("stats", &stats)
+            .field("fragmentation", stats.fragmentation());
+    }
+}
+
+impl PagePoolState {
+    fn stats(&self) -> PagePoolStats {
+        let mut stats = PagePoolStats {
+            total_pages: 0,
+            free_pages: 0,
+            largest_free_run_pages: 0,
+            allocated_pages: 0,
+            pending_restore_pages: 0,
+            leaked_pages: 0,
+            carved_pages: 0,
+            free_slot_count: 0,
+        };
+        let mut free_slots = Vec::new();
+        for slot in &self.slots {
+            stats.total_pages += slot.size_pages;
+            match slot.state {
+                SlotState::Free => {
+                    stats.free_pages += slot.size_pages;
+                    stats.free_slot_count += 1;
+                    free_slots.push(slot);
+                }
+                SlotState::Allocated { .. } => stats.allocated_pages += slot.size_pages,
+                SlotState::AllocatedPendingRestore { .. } => {
+                    stats.allocated_pages += slot.size_pages;
+                    stats.pending_restore_pages += slot.size_pages;
+                }
+                SlotState::Leaked { .. } => stats.leaked_pages += slot.size_pages,
+                SlotState::Carved => stats.carved_pages += slot.size_pages,
+            }
+        }
+
+        // Free slots are not necessarily merged (e.g. after a restore), so
+        // join contiguous ones to find the longest run.
+        free_slots.sort_by_key(|slot| slot.mapping_offset);
+        let mut run: Option<(&Slot, u64)> = None;
+        for slot in free_slots {
+            let run_pages = match run {
+                Some((prev, run_pages)) if prev.is_followed_by(slot) => run_pages + slot.size_pages,
+                _ => slot.size_pages,
+            };
+            stats.largest_free_run_pages = stats.largest_free_run_pages.max(run_pages);
+            run = Some((slot, run_pages));
+        }
+        stats
+    }
+
+    /// Merges adjacent free slots that are contiguous both in pfn space and in
+    /// the pool mapping.
+    fn coalesce_free(&mut self) {
+        self.slots.sort_by_key(|slot| slot.mapping_offset);
+        let mut slots: Vec<Slot> = Vec::with_capacity(self.slots.len());
+        for slot in self.slots.drain(..) {
+            if let Some(prev) = slots.last_mut() {
+                if matches!(prev.state, SlotState::Free)
+                    && matches!(slot.state, SlotState::Free)
+                    && prev.is_followed_by(&slot)
+                {
+                    prev.size_pages += slot.size_pages;
+                    continue;
+                }
+            }
+            slots.push(slot);
+        }
+        self.slots = slots;
+    }
+
+    /// Merges the free slot at `index` with the free slots immediately before
+    /// and after it, if any.
+    fn merge_free(&mut self, index: usize) {
+        let mut slot = self.slots.swap_remove(index);
+        assert!(matches!(slot.state, SlotState::Free));
+        if let Some(next) = self
+            .slots
+            .iter()
+            .position(|next| matches!(next.state, SlotState::Free) && slot.is_followed_by(next))
+        {
+            slot.size_pages += self.slots.swap_remove(next).size_pages;
+        }
+        if let Some(prev) = self
+            .slots
+            .iter_mut()
+            .find(|prev| matches!(prev.state, SlotState::Free) && prev.is_followed_by(&slot))
+        {
+            prev.size_pages += slot.size_pages;
+        } else {
+            self.slots.push(slot);
+        }
     }
 }
 
@@ -372,6 +776,17 @@ pub struct PagePoolHandle {
     base_pfn: u64,
     size_pages: u64,
     mapping_offset: usize,
+    access: DmaAccess,
+    on_free: Option<OnFree>,
+}
+
+/// A callback registered with [`PagePoolHandle::set_on_free`].
+struct OnFree(Box<dyn FnOnce(u64, u64) + Send + Sync>);
+
+impl Debug for OnFree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad("OnFree")
+    }
 }
 
 impl PagePoolHandle {
@@ -390,16 +805,48 @@ pub fn size_pages(&self) -> u64 {
         self.size_pages
     }
 
+    /// The offset into the pool source's mappable object that this
+    /// allocation's first page maps to, as returned by
+    /// [`PoolSource::file_offset`].
+    pub fn file_offset(&self) -> u64 {
+        self.inner.source.file_offset(self.base_pfn * PAGE_SIZE)
+    }
+
+    /// The device access this allocation was made with.
+    pub fn access(&self) -> DmaAccess {
+        self.access
+    }
+
+    /// Registers `on_free` to be called with the base pfn (with bias) and the
+    /// number of pages of this allocation when it is freed, before the pages
+    /// are returned to the pool. Replaces any previously registered callback.
+    pub fn set_on_free(&mut self, on_free: impl FnOnce(u64, u64) + Send + Sync + 'static) {
+        self.on_free = Some(OnFree(Box::new(on_free)));
+    }
+
     /// The associated mapping with this allocation.
+    ///
+    /// If the allocation was made with [`DmaAccess::FromDevice`], the mapping
+    /// may be read-only.
     pub fn mapping(&self) -> &[AtomicU8] {
         self.inner
             .mapping
-            .atomic_slice(self.mapping_offset, (self.size_pages * PAGE_SIZE) as usize)
+            .atomic_slice(self.mapping_offset, self.len())
+    }
+
+    /// The length of this allocation in bytes.
+    fn len(&self) -> usize {
+        // Validated when the allocation was made.
+        pages_to_len(self.size_pages).expect("allocation size must fit in usize")
     }
 
     /// Create a memory block from this allocation.
     fn into_memory_block(self) -> anyhow::Result<user_driver::memory::MemoryBlock> {
-        let pfns: Vec<_> = (self.base_pfn()..self.base_pfn() + self.size_pages).collect();
+        let end_pfn = self
+            .base_pfn()
+            .checked_add(self.size_pages)
+            .context("allocation pfn range overflows")?;
+        let pfns: Vec<_> = (self.base_pfn()..end_pfn).collect();
         Ok(user_driver::memory::MemoryBlock::new(PagePoolDmaBuffer {
             alloc: self,
             pfns,
@@ -409,22 +856,61 @@ fn into_memory_block(self) -> anyhow::Result<user_driver::memory::MemoryBlock> {
 
 impl Drop for PagePoolHandle {
     fn drop(&mut self) {
+        // Notify the owner while the pages are still allocated, and without
+        // the pool lock held so that the callback can use the pool.
+        if let Some(OnFree(on_free)) = self.on_free.take() {
+            on_free(self.base_pfn(), self.size_pages);
+        }
+
+        // Restore the default protection so the pages can be reused by
+        // allocations with different access.
+        #[cfg(unix)]
+        if !self.access.cpu_writable() {
+            if let Err(err) = self
+                .inner
+                .mapping
+                .set_writable(self.mapping_offset, self.len(), true)
+            {
+                // Keep the pages allocated rather than hand out read-only
+                // pages to allocations that expect to write them.
+                tracing::error!(
+                    base_pfn = self.base_pfn,
+                    pfn_bias = self.inner.pfn_bias,
+                    size_pages = self.size_pages,
+                    error = &err as &dyn std::error::Error,
+                    "failed to restore mapping protection, leaking allocation"
+                );
+                return;
+            }
+        }
+
         let mut inner = self.inner.state.lock();
 
-        let slot = inner
-            .slots
-            .iter_mut()
-            .find(|slot| {
-                if matches!(slot.state, SlotState::Allocated { .. }) {
-                    slot.base_pfn == self.base_pfn && slot.size_pages == self.size_pages
-                } else {
-                    false
-                }
-            })
-            .expect("must find allocation");
+        let Some(index) = inner.slots.iter().position(|slot| {
+            if matches!(slot.state, SlotState::Allocated { .. }) {
+                slot.base_pfn == self.base_pfn && slot.size_pages == self.size_pages
+            } else {
+                false
+            }
+        }) else {
+            // This is an accounting bug, but not one worth crashing the
+            // process over.
+            tracing::error!(
+                base_pfn = self.base_pfn,
+                pfn_bias = self.inner.pfn_bias,
+                size_pages = self.size_pages,
+                "freed page pool allocation not found, possible double free"
+            );
+            return;
+        };
 
+        let slot = &mut inner.slots[index];
         assert_eq!(slot.mapping_offset, self.mapping_offset);
         slot.state = SlotState::Free;
+
+        // Merge with neighboring free slots so that freed pages can be reused
+        // for larger allocations.
+        inner.merge_free(index);
     }
 }
 
@@ -447,15 +933,25 @@ pub struct TestMapper {
     #[inspect(skip)]
     mem: Mappable,
     len: usize,
+    bias: u64,
 }
 
 impl TestMapper {
     /// Create a new test mapper that holds an internal buffer of `size_pages`.
     pub fn new(size_pages: u64) -> anyhow::Result<Self> {
+        Self::with_bias(size_pages, 0)
+    }
+
+    /// Create a new test mapper that holds an internal buffer of `size_pages`
+    /// and reports an address bias of `bias` bytes.
+    ///
+    /// The bias is only applied to the addresses reported to users of the
+    /// pool; file offsets are still computed from the unbiased address.
+    pub fn with_bias(size_pages: u64, bias: u64) -> anyhow::Result<Self> {
         let len = (size_pages * PAGE_SIZE) as usize;
         let fd = alloc_shared_memory(len, "page-pool").context("creating shared mem")?;
 
-        Ok(Self { mem: fd, len })
+        Ok(Self { mem: fd, len, bias })
     }
 
     /// Returns [`SparseMapping`] that maps starting at page 0.
@@ -473,7 +969,7 @@ fn inspect_extra(&self, resp: &mut Response<'_>) {
 
 impl PoolSource for TestMapper {
     fn address_bias(&self) -> u64 {
-        0
+        self.bias
     }
 
     fn file_offset(&self, address: u64) -> u64 {
@@ -502,18 +998,16 @@ fn mappable(&self) -> MappableRef<'_> {
 pub struct PagePool {
     #[inspect(flatten)]
     inner: Arc<PagePoolInner>,
-    #[inspect(iter_by_index)]
-    ranges: Vec<MemoryRange>,
 }
 
 impl PagePool {
     /// Returns a new page pool managing the address ranges in `ranges`,
     /// using `source` to access the memory.
     pub fn new<T: PoolSource + 'static>(ranges: &[MemoryRange], source: T) -> anyhow::Result<Self> {
-        Self::new_internal(ranges, Box::new(source))
+        Self::new_internal(ranges, Arc::new(source))
     }
 
-    fn new_internal(memory: &[MemoryRange], source: Box<dyn PoolSource>) -> anyhow::Result<Self> {
+    fn new_internal(memory: &[MemoryRange], source: Arc<dyn PoolSource>) -> anyhow::Result<Self> {
         let mut mapping_offset = 0;
         let pages = memory
             .iter()
@@ -551,12 +1045,14 @@ fn new_internal(memory: &[MemoryRange], source: Box<dyn PoolSource>) -> anyhow::
                 state: Mutex::new(PagePoolState {
                     slots: pages,
                     device_ids: Vec::new(),
+                    max_alloc_pages: None,
+                    alloc_strategy: AllocStrategy::FirstFit,
                 }),
                 pfn_bias: source.address_bias() / PAGE_SIZE,
                 source,
                 mapping,
+                ranges: memory.to_vec(),
             }),
-            ranges: memory.to_vec(),
         })
     }
 
@@ -569,6 +1065,116 @@ pub fn allocator(&self, device_name: String) -> anyhow::Result<PagePoolAllocator
         PagePoolAllocator::new(&self.inner, device_name)
     }
 
+    /// Removes `range` from the pool's free space and returns a new,
+    /// independent pool that manages it, for isolating the allocations of a
+    /// class of devices.
+    ///
+    /// The subpool maps the same memory through the same source, and has its
+    /// own allocators and saved state. This pool never hands out the carved
+    /// pages again, and records them as carved in its saved state; after
+    /// servicing, carve the same range from the new pool before restoring the
+    /// subpool.
+    ///
+    /// `range` is in the same address space as the ranges passed to
+    /// [`Self::new`], and must be page aligned and entirely free.
+    pub fn carve_subpool(&mut self, range: MemoryRange) -> anyhow::Result<PagePool> {
+        if range.is_empty() || range.start() % PAGE_SIZE != 0 || range.end() % PAGE_SIZE != 0 {
+            anyhow::bail!("subpool range {range} is empty or not page aligned");
+        }
+        let subpool = Self::new_internal(&[range], self.inner.source.clone())?;
+
+        let start_pfn = range.start() / PAGE_SIZE;
+        let size_pages = range.len() / PAGE_SIZE;
+        let mut inner = self.inner.state.lock();
+        inner.coalesce_free();
+        let index = inner
+            .slots
+            .iter()
+            .position(|slot| {
+                matches!(slot.state, SlotState::Free)
+                    && slot.base_pfn <= start_pfn
+                    && start_pfn + size_pages <= slot.base_pfn + slot.size_pages
+            })
+            .with_context(|| format!("subpool range {range} is not free in the pool"))?;
+
+        // Split the free slot around the carved pages.
+        let slot = inner.slots.swap_remove(index);
+        let before_pages = start_pfn - slot.base_pfn;
+        let after_pages = slot.size_pages - before_pages - size_pages;
+        let carved = Slot {
+            base_pfn: start_pfn,
+            mapping_offset: slot.mapping_offset + (before_pages * PAGE_SIZE) as usize,
+            size_pages,
+            state: SlotState::Carved,
+        };
+        if before_pages != 0 {
+            inner.slots.push(Slot {
+                size_pages: before_pages,
+                ..slot
+            });
+        }
+        if after_pages != 0 {
+            inner.slots.push(Slot {
+                base_pfn: start_pfn + size_pages,
+                mapping_offset: carved.mapping_offset + range.len() as usize,
+                size_pages: after_pages,
+                state: SlotState::Free,
+            });
+        }
+        inner.slots.push(carved);
+        Ok(subpool)
+    }
+
+    /// Returns the address ranges handed out by the pool, with the source's
+    /// address bias applied.
+    ///
+    /// These are the addresses devices will see, as reported by
+    /// [`PagePoolHandle::base_pfn`]. On isolated VMs, these can be checked
+    /// against other reserved regions to ensure they do not overlap.
+    pub fn biased_range(&self) -> Vec<MemoryRange> {
+        let bias = self.inner.pfn_bias * PAGE_SIZE;
+        self.inner
+            .ranges
+            .iter()
+            .map(|range| MemoryRange::new(range.start() + bias..range.end() + bias))
+            .collect()
+    }
+
+    /// Returns a summary of the pool's usage and fragmentation.
+    pub fn stats(&self) -> PagePoolStats {
+        self.inner.state.lock().stats()
+    }
+
+    /// Returns a single measure of how fragmented the pool's free space is,
+    /// as described in [`PagePoolStats::fragmentation`].
+    pub fn fragmentation(&self) -> f64 {
+        self.stats().fragmentation()
+    }
+
+    /// Returns the number of pages reserved for allocations that were
+    /// restored from saved state but not yet claimed by their owner.
+    ///
+    /// These pages are not available to other allocations, even though no
+    /// live handle refers to them yet.
+    pub fn pending_restore_pages(&self) -> u64 {
+        self.stats().pending_restore_pages
+    }
+
+    /// Limits the size of any single allocation from the pool to
+    /// `max_alloc_pages`, or removes the limit if `None`.
+    ///
+    /// Larger allocations fail with [`Error::AllocationTooLarge`], even if
+    /// there is enough free space in the pool.
+    pub fn set_max_alloc_pages(&self, max_alloc_pages: Option<u64>) {
+        self.inner.state.lock().max_alloc_pages = max_alloc_pages;
+    }
+
+    /// Sets how free slots are chosen for new allocations. Defaults to
+    /// [`AllocStrategy::FirstFit`].
+    pub fn set_alloc_strategy(&self, alloc_strategy: AllocStrategy) {
+        self.inner.state.lock().alloc_strategy = alloc_strategy;
+    }
+
     /// Create a spawner that allows creating multiple allocators.
     pub fn allocator_spawner(&self) -> PagePoolAllocatorSpawner {
         PagePoolAllocatorSpawner {
@@ -592,7 +1198,10 @@ pub fn validate_restore(&self, leak_unrestored: bool) -> Result<(), UnrestoredAl
         // Mark unrestored allocations as leaked.
         for slot in inner.slots.iter_mut() {
             match &slot.state {
-                SlotState::Free | SlotState::Allocated { .. } | SlotState::Leaked { .. } => {}
+                SlotState::Free
+                | SlotState::Allocated { .. }
+                | SlotState::Leaked { .. }
+                | SlotState::Carved => {}
                 SlotState::AllocatedPendingRestore { device_id, tag } => {
                     tracing::warn!(
                         base_pfn = slot.base_pfn,
@@ -621,6 +1230,43 @@ pub fn validate_restore(&self, leak_unrestored: bool) -> Result<(), UnrestoredAl
             Ok(())
         }
     }
+
+    /// Returns the allocations leaked by [`Self::validate_restore`] for
+    /// `device_id` to the pool, returning the number of pages reclaimed.
+    ///
+    /// This must only be called once it has been confirmed that the device
+    /// that owned the allocations is gone and can no longer access the pages,
+    /// as they may be handed out to other devices afterwards.
+    pub fn reclaim_leaked(&mut self, device_id: &str) -> u64 {
+        let mut inner = self.inner.state.lock();
+        let mut reclaimed = 0;
+
+        for slot in inner.slots.iter_mut() {
+            if let SlotState::Leaked {
+                device_id: leaked_id,
+                tag,
+            } = &slot.state
+            {
+                if leaked_id == device_id {
+                    tracing::info!(
+                        base_pfn = slot.base_pfn,
+                        pfn_bias = self.inner.pfn_bias,
+                        size_pages = slot.size_pages,
+                        device_id,
+                        tag = tag.as_str(),
+                        "reclaiming leaked allocation"
+                    );
+                    reclaimed += slot.size_pages;
+                    slot.state = SlotState::Free;
+                }
+            }
+        }
+
+        if reclaimed != 0 {
+            inner.coalesce_free();
+        }
+        reclaimed
+    }
 }
 
 /// A spawner for [`PagePoolAllocator`] instances.
@@ -698,34 +1344,168 @@ fn new(inner: &Arc<PagePoolInner>, device_name: String) -> anyhow::Result<Self>
         })
     }
 
-    fn alloc_inner(&self, size_pages: NonZeroU64, tag: String) -> Result<PagePoolHandle, Error> {
+    fn alloc_inner(
+        &self,
+        size_pages: NonZeroU64,
+        tag: String,
+        access: DmaAccess,
+        preference: AddressPreference,
+        align_pages: u64,
+        range_indices: Option<&[usize]>,
+    ) -> Result<PagePoolHandle, Error> {
         let mut inner = self.inner.state.lock();
         let size_pages = size_pages.get();
+        let (base_pfn, mapping_offset) = self.alloc_locked(
+            &mut inner,
+            size_pages,
+            tag,
+            access,
+            preference,
+            align_pages,
+            range_indices,
+        )?;
 
-        let index = inner
+        Ok(PagePoolHandle {
+            inner: self.inner.clone(),
+            base_pfn,
+            size_pages,
+            mapping_offset,
+            access,
+            on_free: None,
+        })
+    }
+
+    /// Allocates a slot with the pool state lock held, returning the base pfn
+    /// and mapping offset of the allocation.
+    ///
+    /// The biased base pfn of the allocation is a multiple of `align_pages`.
+    /// If `range_indices` is set, the allocation is taken from one of the
+    /// pool's ranges with those indices.
+    #[expect(clippy::too_many_arguments)]
+    fn alloc_locked(
+        &self,
+        inner: &mut PagePoolState,
+        size_pages: u64,
+        tag: String,
+        access: DmaAccess,
+        preference: AddressPreference,
+        align_pages: u64,
+        range_indices: Option<&[usize]>,
+    ) -> Result<(u64, usize), Error> {
+        if let Some(max) = inner.max_alloc_pages {
+            if size_pages > max {
+                return Err(Error::AllocationTooLarge {
+                    size: size_pages,
+                    max,
+                    tag,
+                });
+            }
+        }
+
+        if pages_to_len(size_pages).is_none() {
+            return Err(Error::InvalidSize {
+                size: size_pages,
+                tag,
+            });
+        }
+
+        // The pfn ranges the allocation may be taken from, or `None` for
+        // anywhere in the pool.
+        let allowed_pfns = range_indices
+            .map(|range_indices| {
+                range_indices
+                    .iter()
+                    .map(|&index| {
+                        let range = self.inner.ranges.get(index).ok_or_else(|| {
+                            Error::InvalidRangeIndex {
+                                index,
+                                tag: tag.clone(),
+                            }
+                        })?;
+                        Ok(range.start() / PAGE_SIZE..range.end() / PAGE_SIZE)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+
+        // Returns the pfn within `start..end` at which the allocation would
+        // start, or `None` if it does not fit. Allocations that prefer high
+        // addresses, or the end of the slot, are taken from the end.
+        let pfn_bias = self.inner.pfn_bias;
+        let place = |start: u64, end: u64| -> Option<u64> {
+            let base_pfn = match preference {
+                AddressPreference::High | AddressPreference::SlotEnd => {
+                    let base_pfn = end.checked_sub(size_pages)?.checked_add(pfn_bias)?;
+                    (base_pfn - base_pfn % align_pages).checked_sub(pfn_bias)?
+                }
+                AddressPreference::Low | AddressPreference::Any => start
+                    .checked_add(pfn_bias)?
+                    .checked_next_multiple_of(align_pages)?
+                    .checked_sub(pfn_bias)?,
+            };
+            (base_pfn >= start && base_pfn.checked_add(size_pages)? <= end).then_some(base_pfn)
+        };
+
+        // Returns the pfn within `slot` at which the allocation would start.
+        let placement = |slot: &Slot| -> Option<u64> {
+            let slot_pfns = slot.base_pfn..slot.base_pfn + slot.size_pages;
+            let Some(allowed_pfns) = &allowed_pfns else {
+                return place(slot_pfns.start, slot_pfns.end);
+            };
+            let placements = allowed_pfns.iter().filter_map(|allowed| {
+                place(
+                    slot_pfns.start.max(allowed.start),
+                    slot_pfns.end.min(allowed.end),
+                )
+            });
+            match preference {
+                AddressPreference::High | AddressPreference::SlotEnd => placements.max(),
+                AddressPreference::Low | AddressPreference::Any => placements.min(),
+            }
+        };
+
+        let mut candidates = inner
             .slots
             .iter()
-            .position(|slot| match slot.state {
-                SlotState::Free => slot.size_pages >= size_pages,
+            .enumerate()
+            .filter_map(|(index, slot)| match slot.state {
+                SlotState::Free => placement(slot).map(|base_pfn| (index, slot, base_pfn)),
                 SlotState::Allocated { .. }
                 | SlotState::AllocatedPendingRestore { .. }
-                | SlotState::Leaked { .. } => false,
-            })
-            .ok_or(Error::PagePoolOutOfMemory {
-                size: size_pages,
-                tag: tag.clone(),
-            })?;
+                | SlotState::Leaked { .. }
+                | SlotState::Carved => None,
+            });
+        let (index, base_pfn) = match (preference, inner.alloc_strategy) {
+            (AddressPreference::Low, _) => candidates.min_by_key(|&(_, _, base_pfn)| base_pfn),
+            (AddressPreference::High, _) => candidates.max_by_key(|&(_, _, base_pfn)| base_pfn),
+            (AddressPreference::Any | AddressPreference::SlotEnd, AllocStrategy::FirstFit) => {
+                candidates.next()
+            }
+            (AddressPreference::Any | AddressPreference::SlotEnd, AllocStrategy::BestFit) => {
+                candidates.min_by_key(|(_, slot, _)| slot.size_pages)
+            }
+        }
+        .map(|(index, _, base_pfn)| (index, base_pfn))
+        .ok_or(Error::PagePoolOutOfMemory {
+            size: size_pages,
+            tag: tag.clone(),
+        })?;
 
         // Track which slots we should append if the mapping creation succeeds.
         // If the mapping creation fails, we instead commit the original free
         // slot back to the pool.
-        let (allocation_slot, free_slot) = {
+        let (allocation_slot, free_slots) = {
             let slot = inner.slots.swap_remove(index);
             assert!(matches!(slot.state, SlotState::Free));
 
+            let head_pages = base_pfn - slot.base_pfn;
+            let tail_pages = slot.size_pages - head_pages - size_pages;
+            let mapping_offset =
+                |offset_pages: u64| slot.mapping_offset + (offset_pages * PAGE_SIZE) as usize;
+
             let allocation_slot = Slot {
-                base_pfn: slot.base_pfn,
-                mapping_offset: slot.mapping_offset,
+                base_pfn,
+                mapping_offset: mapping_offset(head_pages),
                 size_pages,
                 state: SlotState::Allocated {
                     device_id: self.device_id,
@@ -733,46 +1513,192 @@ fn alloc_inner(&self, size_pages: NonZeroU64, tag: String) -> Result<PagePoolHan
                 },
             };
 
-            let free_slot = if slot.size_pages > size_pages {
-                Some(Slot {
-                    base_pfn: slot.base_pfn + size_pages,
-                    mapping_offset: slot.mapping_offset + (size_pages * PAGE_SIZE) as usize,
-                    size_pages: slot.size_pages - size_pages,
+            // The pages before and after the allocation remain free.
+            let free_slots = [(0, head_pages), (head_pages + size_pages, tail_pages)]
+                .into_iter()
+                .filter(|&(_, size_pages)| size_pages > 0)
+                .map(|(offset_pages, size_pages)| Slot {
+                    base_pfn: slot.base_pfn + offset_pages,
+                    mapping_offset: mapping_offset(offset_pages),
+                    size_pages,
                     state: SlotState::Free,
                 })
-            } else {
-                None
-            };
+                .collect::<Vec<_>>();
 
-            (allocation_slot, free_slot)
+            (allocation_slot, free_slots)
         };
 
         let base_pfn = allocation_slot.base_pfn;
         let mapping_offset = allocation_slot.mapping_offset;
         assert_eq!(mapping_offset % PAGE_SIZE as usize, 0);
 
+        if !access.cpu_writable() {
+            #[cfg(unix)]
+            {
+                let len = pages_to_len(size_pages).expect("size validated above");
+                if let Err(err) = self.inner.mapping.set_writable(mapping_offset, len, false) {
+                    // Return the pages to the pool.
+                    inner.slots.push(Slot {
+                        state: SlotState::Free,
+                        ..allocation_slot
+                    });
+                    inner.slots.extend(free_slots);
+                    inner.coalesce_free();
+                    return Err(Error::Mapping(err.into()));
+                }
+            }
+            // The mapping protection cannot be changed, so the CPU mapping
+            // stays read-write, as documented on [`DmaAccess::FromDevice`].
+            #[cfg(not(unix))]
+            tracing::debug!(
+                base_pfn,
+                size_pages,
+                "read-only mapping not supported, mapping allocation read-write"
+            );
+        }
+
         // Commit state to the pool.
         inner.slots.push(allocation_slot);
-        if let Some(free_slot) = free_slot {
-            inner.slots.push(free_slot);
-        }
+        inner.slots.extend(free_slots);
 
-        Ok(PagePoolHandle {
-            inner: self.inner.clone(),
-            base_pfn,
-            size_pages,
-            mapping_offset,
-        })
+        Ok((base_pfn, mapping_offset))
     }
 
     /// Allocate contiguous pages from the page pool with the given tag. If a
     /// contiguous region of free pages is not available, then an error is
     /// returned.
     pub fn alloc(&self, size_pages: NonZeroU64, tag: String) -> Result<PagePoolHandle, Error> {
-        self.alloc_inner(size_pages, tag)
+        self.alloc_inner(
+            size_pages,
+            tag,
+            DmaAccess::Bidirectional,
+            AddressPreference::Any,
+            1,
+            None,
+        )
     }
 
-    /// Restore an allocation that was previously allocated in the pool. The
+    /// Allocate contiguous pages like [`Self::alloc`], with the CPU mapping
+    /// protected according to `access`.
+    pub fn alloc_with_access(
+        &self,
+        size_pages: NonZeroU64,
+        tag: String,
+        access: DmaAccess,
+    ) -> Result<PagePoolHandle, Error> {
+        self.alloc_inner(size_pages, tag, access, AddressPreference::Any, 1, None)
+    }
+
+    /// Allocate contiguous pages like [`Self::alloc`], taken from the end of
+    /// the pool given by `preference`.
+    pub fn alloc_with_preference(
+        &self,
+        size_pages: NonZeroU64,
+        tag: String,
+        preference: AddressPreference,
+    ) -> Result<PagePoolHandle, Error> {
+        self.alloc_inner(
+            size_pages,
+            tag,
+            DmaAccess::Bidirectional,
+            preference,
+            1,
+            None,
+        )
+    }
+
+    /// Allocate contiguous pages like [`Self::alloc`], with a base pfn (as
+    /// returned by [`PagePoolHandle::base_pfn`]) that is a multiple of
+    /// `align_pages`.
+    ///
+    /// The pages skipped to align the allocation remain free.
+    pub fn alloc_aligned(
+        &self,
+        size_pages: NonZeroU64,
+        align_pages: NonZeroU64,
+        tag: String,
+    ) -> Result<PagePoolHandle, Error> {
+        self.alloc_inner(
+            size_pages,
+            tag,
+            DmaAccess::Bidirectional,
+            AddressPreference::Any,
+            align_pages.get(),
+            None,
+        )
+    }
+
+    /// Allocate contiguous pages like [`Self::alloc`], but only from the
+    /// pool's ranges at `range_indices`, in the order the ranges were passed
+    /// to [`PagePool::new`].
+    ///
+    /// This can be used to keep a device's allocations in memory local to its
+    /// NUMA node.
+    pub fn alloc_in_ranges(
+        &self,
+        size_pages: NonZeroU64,
+        range_indices: &[usize],
+        tag: String,
+    ) -> Result<PagePoolHandle, Error> {
+        self.alloc_inner(
+            size_pages,
+            tag,
+            DmaAccess::Bidirectional,
+            AddressPreference::Any,
+            1,
+            Some(range_indices),
+        )
+    }
+
+    /// Allocate a set of contiguous page ranges, one per `(size_pages, tag)`
+    /// request, as a single operation. Either every allocation succeeds or
+    /// none are made: on failure, the pool is left as it was before the call
+    /// and the error for the first failing request is returned.
+    pub fn alloc_batch(
+        &self,
+        requests: &[(NonZeroU64, String)],
+    ) -> Result<Vec<PagePoolHandle>, Error> {
+        let mut inner = self.inner.state.lock();
+        let original_slots = inner.slots.clone();
+
+        let mut allocations = Vec::with_capacity(requests.len());
+        for (size_pages, tag) in requests {
+            match self.alloc_locked(
+                &mut inner,
+                size_pages.get(),
+                tag.clone(),
+                DmaAccess::Bidirectional,
+                AddressPreference::Any,
+                1,
+                None,
+            ) {
+                Ok((base_pfn, mapping_offset)) => {
+                    allocations.push((base_pfn, size_pages.get(), mapping_offset))
+                }
+                Err(err) => {
+                    // Nothing has been handed out yet, and bidirectional
+                    // allocations leave the mapping untouched, so restoring
+                    // the slots undoes the partial batch.
+                    inner.slots = original_slots;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(allocations
+            .into_iter()
+            .map(|(base_pfn, size_pages, mapping_offset)| PagePoolHandle {
+                inner: self.inner.clone(),
+                base_pfn,
+                size_pages,
+                mapping_offset,
+                access: DmaAccess::Bidirectional,
+                on_free: None,
+            })
+            .collect())
+    }
+
+    /// Restore an allocation that was previously allocated in the pool. The
     /// base_pfn, size_pages, and device must match.
     ///
     /// `with_mapping` specifies if a mapping should be created that can be used
@@ -807,6 +1733,8 @@ pub fn restore_alloc(
             base_pfn,
             size_pages,
             mapping_offset: slot.mapping_offset,
+            access: DmaAccess::Bidirectional,
+            on_free: None,
         })
     }
 
@@ -838,6 +1766,8 @@ pub fn restore_pending_allocs(&self) -> Vec<PagePoolHandle> {
                     base_pfn: slot.base_pfn,
                     size_pages: slot.size_pages,
                     mapping_offset: slot.mapping_offset,
+                    access: DmaAccess::Bidirectional,
+                    on_free: None,
                 }
             })
             .collect()
@@ -887,53 +1817,37 @@ fn attach_pending_buffers(&self) -> anyhow::Result<Vec<user_driver::memory::Memo
 
 #[cfg(test)]
 mod test {
+    use crate::AddressPreference;
+    use crate::AllocStrategy;
+    use crate::DmaAccess;
+    use crate::Error;
     use crate::PAGE_SIZE;
     use crate::PagePool;
-    use crate::PoolSource;
+    use crate::PagePoolHandle;
+    use crate::PagePoolStats;
+    use crate::SlotState;
     use crate::TestMapper;
-    use inspect::Inspect;
+    use crate::save_restore::RestoreOptions;
     use memory_range::MemoryRange;
+    use parking_lot::Mutex;
     use safeatomic::AtomicSliceOps;
-    use sparse_mmap::MappableRef;
+    use std::sync::Arc;
     use vmcore::save_restore::SaveRestore;
 
-    #[derive(Inspect)]
-    #[inspect(bound = "T: Inspect")]
-    struct BiasedMapper<T> {
-        mapper: T,
-        bias: u64,
-    }
-
-    impl<T: PoolSource> BiasedMapper<T> {
-        fn new(mapper: T, bias: u64) -> Self {
-            Self { mapper, bias }
-        }
-    }
-
-    impl<T: PoolSource> PoolSource for BiasedMapper<T> {
-        fn address_bias(&self) -> u64 {
-            self.bias.wrapping_add(self.mapper.address_bias())
-        }
-
-        fn file_offset(&self, address: u64) -> u64 {
-            self.mapper.file_offset(address)
-        }
-
-        fn mappable(&self) -> MappableRef<'_> {
-            self.mapper.mappable()
-        }
-    }
-
     fn big_test_mapper() -> TestMapper {
         TestMapper::new(1024 * 1024).unwrap()
     }
 
+    fn big_biased_test_mapper(bias: u64) -> TestMapper {
+        TestMapper::with_bias(1024 * 1024, bias).unwrap()
+    }
+
     #[test]
     fn test_basic_alloc() {
         let pfn_bias = 15;
         let pool = PagePool::new(
             &[MemoryRange::from_4k_gpn_range(10..30)],
-            BiasedMapper::new(big_test_mapper(), pfn_bias * PAGE_SIZE),
+            big_biased_test_mapper(pfn_bias * PAGE_SIZE),
         )
         .unwrap();
         let alloc = pool.allocator("test".into()).unwrap();
@@ -957,8 +1871,203 @@ fn test_basic_alloc() {
         drop(a1);
         drop(a2);
 
+        // The freed slots are merged back into one.
         let inner = alloc.inner.state.lock();
-        assert_eq!(inner.slots.len(), 2);
+        assert_eq!(inner.slots.len(), 1);
+    }
+
+    #[test]
+    fn test_free_coalesce() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let mut handles = (0..10)
+            .map(|i| {
+                Some(
+                    alloc
+                        .alloc(2.try_into().unwrap(), format!("alloc{i}"))
+                        .unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Free every other allocation. None of the holes are adjacent, so a
+        // larger allocation still cannot be satisfied.
+        for handle in handles.iter_mut().step_by(2) {
+            handle.take();
+        }
+        assert_eq!(alloc.inner.state.lock().slots.len(), 10);
+        assert!(alloc.alloc(4.try_into().unwrap(), "big".into()).is_err());
+
+        // Freeing the rest collapses the pool back to a single free slot.
+        drop(handles);
+        {
+            let inner = alloc.inner.state.lock();
+            assert_eq!(inner.slots.len(), 1);
+            assert!(matches!(inner.slots[0].state, SlotState::Free));
+        }
+        let a = alloc.alloc(20.try_into().unwrap(), "all".into()).unwrap();
+        assert_eq!(a.base_pfn(), 10);
+    }
+
+    #[test]
+    fn test_free_coalesce_ranges() {
+        let pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(10..20),
+                MemoryRange::from_4k_gpn_range(40..50),
+            ],
+            big_test_mapper(),
+        )
+        .unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let a1 = alloc
+            .alloc(10.try_into().unwrap(), "alloc1".into())
+            .unwrap();
+        let a2 = alloc
+            .alloc(10.try_into().unwrap(), "alloc2".into())
+            .unwrap();
+        drop(a1);
+        drop(a2);
+
+        // The ranges are adjacent in the mapping but not in pfn space, so they
+        // are not merged.
+        assert_eq!(alloc.inner.state.lock().slots.len(), 2);
+        assert!(alloc.alloc(11.try_into().unwrap(), "big".into()).is_err());
+    }
+
+    #[test]
+    fn test_huge_alloc() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // Too large to express in bytes.
+        let err = alloc
+            .alloc((u64::MAX - 1).try_into().unwrap(), "huge".into())
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidSize { size, .. } if size == u64::MAX - 1));
+
+        // Expressible in bytes, but larger than the pool.
+        let size_pages = u64::MAX / PAGE_SIZE;
+        let err = alloc
+            .alloc(size_pages.try_into().unwrap(), "large".into())
+            .unwrap_err();
+        assert!(matches!(err, Error::PagePoolOutOfMemory { size, .. } if size == size_pages));
+
+        // The pool is untouched.
+        let a1 = alloc
+            .alloc(20.try_into().unwrap(), "alloc1".into())
+            .unwrap();
+        assert_eq!(a1.mapping().len(), 20 * PAGE_SIZE as usize);
+    }
+
+    #[test]
+    fn test_alloc_batch() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // The last allocation in the batch does not fit, so nothing should be
+        // allocated.
+        let original_slots = alloc.inner.state.lock().slots.clone();
+        let err = alloc
+            .alloc_batch(&[
+                (5.try_into().unwrap(), "batch1".into()),
+                (10.try_into().unwrap(), "batch2".into()),
+                (10.try_into().unwrap(), "batch3".into()),
+            ])
+            .unwrap_err();
+        assert!(matches!(err, Error::PagePoolOutOfMemory { size: 10, ref tag } if tag == "batch3"));
+        assert_eq!(alloc.inner.state.lock().slots, original_slots);
+
+        // The full pool is still available.
+        let a1 = alloc
+            .alloc(20.try_into().unwrap(), "alloc1".into())
+            .unwrap();
+        drop(a1);
+
+        let handles = alloc
+            .alloc_batch(&[
+                (5.try_into().unwrap(), "batch1".into()),
+                (15.try_into().unwrap(), "batch2".into()),
+            ])
+            .unwrap();
+        assert_eq!(handles.len(), 2);
+        assert_eq!(handles[0].size_pages(), 5);
+        assert_eq!(handles[1].size_pages(), 15);
+        assert_ne!(handles[0].base_pfn(), handles[1].base_pfn());
+
+        drop(handles);
+        let inner = alloc.inner.state.lock();
+        assert!(
+            inner
+                .slots
+                .iter()
+                .all(|slot| matches!(slot.state, SlotState::Free))
+        );
+    }
+
+    #[test]
+    fn test_biased_range() {
+        let pfn_bias = 0x1000;
+        let pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(10..30),
+                MemoryRange::from_4k_gpn_range(40..50),
+            ],
+            big_biased_test_mapper(pfn_bias * PAGE_SIZE),
+        )
+        .unwrap();
+
+        assert_eq!(
+            pool.biased_range(),
+            [
+                MemoryRange::from_4k_gpn_range(10 + pfn_bias..30 + pfn_bias),
+                MemoryRange::from_4k_gpn_range(40 + pfn_bias..50 + pfn_bias),
+            ]
+        );
+
+        // Allocations are within the biased ranges.
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc
+            .alloc(15.try_into().unwrap(), "alloc1".into())
+            .unwrap();
+        let a1_range = MemoryRange::from_4k_gpn_range(a1.base_pfn()..a1.base_pfn() + 15);
+        assert!(
+            pool.biased_range()
+                .iter()
+                .any(|range| range.contains(&a1_range))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_alloc() {
+        trycopy::initialize_try_copy();
+
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let a1 = alloc
+            .alloc_with_access(2.try_into().unwrap(), "rx".into(), DmaAccess::FromDevice)
+            .unwrap();
+        let offset = a1.mapping_offset;
+        let len = (a1.size_pages * PAGE_SIZE) as usize;
+
+        // Reads succeed, writes fault.
+        let mut buf = vec![0xcc; len];
+        pool.inner.mapping.read_at(offset, &mut buf).unwrap();
+        assert!(pool.inner.mapping.write_at(offset, &[1, 2, 3, 4]).is_err());
+
+        // Once freed, the pages are writable again for other allocations.
+        drop(a1);
+        let a2 = alloc.alloc(2.try_into().unwrap(), "tx".into()).unwrap();
+        assert_eq!(a2.mapping_offset, offset);
+        pool.inner.mapping.write_at(offset, &[1, 2, 3, 4]).unwrap();
     }
 
     #[test]
@@ -1027,6 +2136,188 @@ fn test_save_restore() {
         pool.validate_restore(false).unwrap();
     }
 
+    #[test]
+    fn test_restore_with_allocators() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let a1_pfn = a1.base_pfn();
+        let a1_size = a1.size_pages;
+        let state1 = pool.save().unwrap();
+        let state2 = pool.save().unwrap();
+        let state3 = pool.save().unwrap();
+
+        // Create the allocators before restoring.
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // A regular restore requires that there are no allocators.
+        assert!(pool.restore(state1).is_err());
+
+        // The pool must not have any allocations.
+        let a2 = alloc.alloc(1.try_into().unwrap(), "alloc2".into()).unwrap();
+        assert!(pool.restore_with_allocators(state2).is_err());
+        drop(a2);
+
+        pool.restore_with_allocators(state3).unwrap();
+        let restored = alloc.restore_pending_allocs();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].base_pfn(), a1_pfn);
+        assert_eq!(restored[0].size_pages, a1_size);
+
+        pool.validate_restore(false).unwrap();
+    }
+
+    #[test]
+    fn test_restore_with_mismatched_allocator() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+        let _a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let state = pool.save().unwrap();
+
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let _alloc = pool.allocator("test".into()).unwrap();
+        let _other = pool.allocator("other".into()).unwrap();
+
+        let err = pool.restore_with_allocators(state).unwrap_err();
+        let vmcore::save_restore::RestoreError::InvalidSavedState(err) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        let message = err.to_string();
+        assert!(message.contains("\"other\""), "{message}");
+        assert!(!message.contains("\"test\""), "{message}");
+    }
+
+    #[test]
+    fn test_restore_existing_allocators() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let state = pool.save().unwrap();
+
+        let _alloc = pool.allocator("live".into()).unwrap();
+        drop(pool.allocator("dropped".into()).unwrap());
+
+        // Only the allocator that is still alive is named.
+        let err = pool.restore(state).unwrap_err();
+        let vmcore::save_restore::RestoreError::InvalidSavedState(err) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        let message = err.to_string();
+        assert!(message.contains("\"live\""), "{message}");
+        assert!(!message.contains("dropped"), "{message}");
+    }
+
+    #[test]
+    fn test_restore_smaller_saved_state() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let a1_pfn = a1.base_pfn();
+        let a2 = alloc
+            .alloc(15.try_into().unwrap(), "alloc2".into())
+            .unwrap();
+        let a2_pfn = a2.base_pfn();
+        let state1 = pool.save().unwrap();
+        let state2 = pool.save().unwrap();
+
+        // The new pool extends the saved range and adds another one.
+        let ranges = [
+            MemoryRange::from_4k_gpn_range(10..36),
+            MemoryRange::from_4k_gpn_range(40..50),
+        ];
+
+        // A regular restore requires the saved state to cover the whole pool.
+        let mut pool = PagePool::new(&ranges, big_test_mapper()).unwrap();
+        assert!(pool.restore(state1).is_err());
+
+        let mut pool = PagePool::new(&ranges, big_test_mapper()).unwrap();
+        pool.restore_with_options(
+            state2,
+            RestoreOptions {
+                allow_smaller_saved_state: true,
+            },
+        )
+        .unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let restored_a1 = alloc.restore_alloc(a1_pfn, 5.try_into().unwrap()).unwrap();
+        let restored_a2 = alloc.restore_alloc(a2_pfn, 15.try_into().unwrap()).unwrap();
+        assert_eq!(restored_a1.base_pfn(), 10);
+        assert_eq!(restored_a2.base_pfn(), 15);
+        pool.validate_restore(false).unwrap();
+
+        // The memory not covered by the saved state is free.
+        let a3 = alloc.alloc(6.try_into().unwrap(), "alloc3".into()).unwrap();
+        assert_eq!(a3.base_pfn(), 30);
+        let a4 = alloc
+            .alloc(10.try_into().unwrap(), "alloc4".into())
+            .unwrap();
+        assert_eq!(a4.base_pfn(), 40);
+        assert!(alloc.alloc(1.try_into().unwrap(), "alloc5".into()).is_err());
+    }
+
+    #[test]
+    fn test_restore_mismatched_ranges() {
+        let mut pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(10..30),
+                MemoryRange::from_4k_gpn_range(40..50),
+            ],
+            big_test_mapper(),
+        )
+        .unwrap();
+        let state = pool.save().unwrap();
+
+        // The first range matches, but the saved state has an extra range
+        // that the pool does not map.
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let err = pool.restore(state).unwrap_err();
+        let vmcore::save_restore::RestoreError::InvalidSavedState(err) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(
+            err.to_string(),
+            "saved ranges cover 0x1e000 bytes, but the pool maps 0x14000 bytes"
+        );
+    }
+
+    #[test]
+    fn test_pending_restore_pages() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let a1_pfn = a1.base_pfn();
+        let _a2 = alloc
+            .alloc(15.try_into().unwrap(), "alloc2".into())
+            .unwrap();
+        let state = pool.save().unwrap();
+
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        pool.restore(state).unwrap();
+        assert_eq!(pool.pending_restore_pages(), 20);
+
+        // Other allocators can't use the pages reserved for the owner.
+        let other = pool.allocator("other".into()).unwrap();
+        assert!(other.alloc(1.try_into().unwrap(), "other".into()).is_err());
+
+        let alloc = pool.allocator("test".into()).unwrap();
+        let _a1 = alloc.restore_alloc(a1_pfn, 5.try_into().unwrap()).unwrap();
+        assert_eq!(pool.pending_restore_pages(), 15);
+        let _rest = alloc.restore_pending_allocs();
+        assert_eq!(pool.pending_restore_pages(), 0);
+        assert_eq!(pool.stats().allocated_pages, 20);
+
+        pool.validate_restore(false).unwrap();
+    }
+
     #[test]
     fn test_save_restore_all_pending() {
         let mut pool =
@@ -1129,6 +2420,439 @@ fn test_save_restore_unmatched_allocations() {
         assert!(pool.validate_restore(false).is_err());
     }
 
+    #[test]
+    fn test_double_free() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let duplicate = PagePoolHandle {
+            inner: a1.inner.clone(),
+            base_pfn: a1.base_pfn,
+            size_pages: a1.size_pages,
+            mapping_offset: a1.mapping_offset,
+            access: a1.access,
+            on_free: None,
+        };
+
+        // The second free is logged and ignored.
+        drop(a1);
+        drop(duplicate);
+
+        let a2 = alloc.alloc(5.try_into().unwrap(), "alloc2".into()).unwrap();
+        assert_eq!(a2.base_pfn(), 10);
+    }
+
+    #[test]
+    fn test_on_free() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let _a1 = alloc.alloc(2.try_into().unwrap(), "alloc1".into()).unwrap();
+        let mut a2 = alloc.alloc(5.try_into().unwrap(), "alloc2".into()).unwrap();
+        let freed = Arc::new(Mutex::new(Vec::new()));
+        a2.set_on_free({
+            let freed = freed.clone();
+            move |base_pfn, size_pages| freed.lock().push((base_pfn, size_pages))
+        });
+
+        assert!(freed.lock().is_empty());
+        drop(a2);
+        assert_eq!(*freed.lock(), [(12, 5)]);
+
+        // The pages were returned to the pool after the callback ran.
+        let a3 = alloc.alloc(5.try_into().unwrap(), "alloc3".into()).unwrap();
+        assert_eq!(a3.base_pfn(), 12);
+    }
+
+    #[test]
+    fn test_file_offset() {
+        let pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(10..30),
+                MemoryRange::from_4k_gpn_range(40..50),
+            ],
+            big_biased_test_mapper(15 * PAGE_SIZE),
+        )
+        .unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // The file offset is computed from the unbiased address.
+        let a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        assert_eq!(a1.file_offset(), 10 * PAGE_SIZE);
+        let a2 = alloc
+            .alloc(15.try_into().unwrap(), "alloc2".into())
+            .unwrap();
+        assert_eq!(a2.file_offset(), 15 * PAGE_SIZE);
+        let a3 = alloc.alloc(2.try_into().unwrap(), "alloc3".into()).unwrap();
+        assert_eq!(a3.file_offset(), a3.base_pfn_without_bias() * PAGE_SIZE);
+        assert_eq!(a3.file_offset(), 40 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_max_alloc_pages() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        pool.set_max_alloc_pages(Some(8));
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        assert!(matches!(
+            alloc.alloc(9.try_into().unwrap(), "too_big".into()),
+            Err(Error::AllocationTooLarge {
+                size: 9,
+                max: 8,
+                ..
+            })
+        ));
+        let a1 = alloc.alloc(8.try_into().unwrap(), "max".into()).unwrap();
+        drop(a1);
+
+        pool.set_max_alloc_pages(None);
+        alloc.alloc(20.try_into().unwrap(), "all".into()).unwrap();
+    }
+
+    #[test]
+    fn test_alloc_strategy() {
+        let ranges = [
+            MemoryRange::from_4k_gpn_range(10..30),
+            MemoryRange::from_4k_gpn_range(40..42),
+        ];
+
+        // First fit carves the small allocation out of the large range, so
+        // the whole range is no longer available.
+        let pool = PagePool::new(&ranges, big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc.alloc(2.try_into().unwrap(), "small".into()).unwrap();
+        assert_eq!(a1.base_pfn(), 10);
+        assert!(alloc.alloc(20.try_into().unwrap(), "large".into()).is_err());
+
+        // Best fit uses the small hole instead.
+        let pool = PagePool::new(&ranges, big_test_mapper()).unwrap();
+        pool.set_alloc_strategy(AllocStrategy::BestFit);
+        let alloc = pool.allocator("test".into()).unwrap();
+        let a1 = alloc.alloc(2.try_into().unwrap(), "small".into()).unwrap();
+        assert_eq!(a1.base_pfn(), 40);
+        let a2 = alloc.alloc(20.try_into().unwrap(), "large".into()).unwrap();
+        assert_eq!(a2.base_pfn(), 10);
+    }
+
+    #[test]
+    fn test_address_preference() {
+        let pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(40..50),
+                MemoryRange::from_4k_gpn_range(10..30),
+            ],
+            big_test_mapper(),
+        )
+        .unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let low = alloc
+            .alloc_with_preference(2.try_into().unwrap(), "low".into(), AddressPreference::Low)
+            .unwrap();
+        assert_eq!(low.base_pfn(), 10);
+        let high = alloc
+            .alloc_with_preference(
+                2.try_into().unwrap(),
+                "high".into(),
+                AddressPreference::High,
+            )
+            .unwrap();
+        assert_eq!(high.base_pfn(), 48);
+
+        // Later allocations continue inward from each end.
+        let low2 = alloc
+            .alloc_with_preference(3.try_into().unwrap(), "low2".into(), AddressPreference::Low)
+            .unwrap();
+        assert_eq!(low2.base_pfn(), 12);
+        let high2 = alloc
+            .alloc_with_preference(
+                3.try_into().unwrap(),
+                "high2".into(),
+                AddressPreference::High,
+            )
+            .unwrap();
+        assert_eq!(high2.base_pfn(), 45);
+
+        // Freeing everything leaves one free slot per range.
+        drop((low, high, low2, high2));
+        let stats = pool.stats();
+        assert_eq!(stats.free_pages, 30);
+        assert_eq!(stats.free_slot_count, 2);
+    }
+
+    #[test]
+    fn test_slot_end() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let a = alloc
+            .alloc_with_preference(
+                5.try_into().unwrap(),
+                "end".into(),
+                AddressPreference::SlotEnd,
+            )
+            .unwrap();
+        assert_eq!(a.base_pfn(), 25);
+
+        // The front of the slot is left as a single free slot.
+        let inner = alloc.inner.state.lock();
+        let free = inner
+            .slots
+            .iter()
+            .filter(|slot| matches!(slot.state, SlotState::Free))
+            .collect::<Vec<_>>();
+        assert_eq!(free.len(), 1);
+        assert_eq!(free[0].base_pfn, 10);
+        assert_eq!(free[0].size_pages, 15);
+        assert_eq!(free[0].mapping_offset, 0);
+        drop(inner);
+
+        // A later low allocation is taken from the front.
+        let b = alloc.alloc(3.try_into().unwrap(), "front".into()).unwrap();
+        assert_eq!(b.base_pfn(), 10);
+    }
+
+    #[test]
+    fn test_alloc_aligned() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..50)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let a1 = alloc
+            .alloc_aligned(4.try_into().unwrap(), 16.try_into().unwrap(), "a1".into())
+            .unwrap();
+        assert_eq!(a1.base_pfn(), 16);
+        let a2 = alloc
+            .alloc_aligned(4.try_into().unwrap(), 16.try_into().unwrap(), "a2".into())
+            .unwrap();
+        assert_eq!(a2.base_pfn(), 32);
+
+        // The pages skipped for alignment remain free, before, between, and
+        // after the allocations.
+        let stats = pool.stats();
+        assert_eq!(stats.free_pages, 32);
+        assert_eq!(stats.free_slot_count, 3);
+        let head = alloc.alloc(6.try_into().unwrap(), "head".into()).unwrap();
+        assert_eq!(head.base_pfn(), 10);
+
+        // No aligned 16 page range is left.
+        assert!(
+            alloc
+                .alloc_aligned(16.try_into().unwrap(), 16.try_into().unwrap(), "a3".into())
+                .is_err()
+        );
+
+        drop((a1, a2, head));
+        assert_eq!(pool.stats().free_slot_count, 1);
+    }
+
+    #[test]
+    fn test_alloc_aligned_huge() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..50)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // Alignments whose multiples lie far beyond the pool fail cleanly
+        // rather than overflowing.
+        for align_pages in [1 << 63, u64::MAX] {
+            assert!(
+                alloc
+                    .alloc_aligned(
+                        1.try_into().unwrap(),
+                        align_pages.try_into().unwrap(),
+                        "huge".into()
+                    )
+                    .is_err()
+            );
+        }
+        assert_eq!(pool.stats().free_pages, 40);
+    }
+
+    #[test]
+    fn test_alloc_in_ranges() {
+        let pool = PagePool::new(
+            &[
+                MemoryRange::from_4k_gpn_range(10..30),
+                MemoryRange::from_4k_gpn_range(40..50),
+            ],
+            big_test_mapper(),
+        )
+        .unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // Without the restriction, the first range would be used.
+        let a1 = alloc
+            .alloc_in_ranges(5.try_into().unwrap(), &[1], "a1".into())
+            .unwrap();
+        assert!((40..50).contains(&a1.base_pfn()));
+        let _a2 = alloc
+            .alloc_in_ranges(5.try_into().unwrap(), &[1], "a2".into())
+            .unwrap();
+
+        // The second range is full, even though the first is not.
+        assert!(matches!(
+            alloc.alloc_in_ranges(1.try_into().unwrap(), &[1], "a3".into()),
+            Err(Error::PagePoolOutOfMemory { .. })
+        ));
+        let a3 = alloc
+            .alloc_in_ranges(1.try_into().unwrap(), &[1, 0], "a3".into())
+            .unwrap();
+        assert_eq!(a3.base_pfn(), 10);
+
+        assert!(matches!(
+            alloc.alloc_in_ranges(1.try_into().unwrap(), &[2], "a4".into()),
+            Err(Error::InvalidRangeIndex { index: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_stats() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        let _a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let hole = alloc.alloc(3.try_into().unwrap(), "hole".into()).unwrap();
+        let _a2 = alloc.alloc(2.try_into().unwrap(), "alloc2".into()).unwrap();
+        drop(hole);
+
+        // The pool now has a 3 page hole between the allocations and a 10
+        // page free tail.
+        assert_eq!(
+            pool.stats(),
+            PagePoolStats {
+                total_pages: 20,
+                free_pages: 13,
+                largest_free_run_pages: 10,
+                allocated_pages: 7,
+                pending_restore_pages: 0,
+                leaked_pages: 0,
+                carved_pages: 0,
+                free_slot_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let alloc = pool.allocator("test".into()).unwrap();
+
+        // A fresh pool is contiguous.
+        assert_eq!(pool.fragmentation(), 0.0);
+
+        // Allocating from the front leaves the free space contiguous.
+        let mut handles = Vec::new();
+        for i in 0..5 {
+            handles.push(
+                alloc
+                    .alloc(2.try_into().unwrap(), format!("alloc{i}"))
+                    .unwrap(),
+            );
+        }
+        assert_eq!(pool.fragmentation(), 0.0);
+
+        // Freeing every other allocation leaves 2 page holes in front of the
+        // 10 page tail.
+        handles.remove(3);
+        handles.remove(1);
+        assert_eq!(pool.stats().free_pages, 14);
+        assert_eq!(pool.fragmentation(), 1.0 - 10.0 / 14.0);
+
+        // A full pool has no free space to fragment.
+        let rest = alloc.alloc(10.try_into().unwrap(), "rest".into()).unwrap();
+        let hole1 = alloc.alloc(2.try_into().unwrap(), "hole1".into()).unwrap();
+        let hole2 = alloc.alloc(2.try_into().unwrap(), "hole2".into()).unwrap();
+        assert_eq!(pool.stats().free_pages, 0);
+        assert_eq!(pool.fragmentation(), 0.0);
+
+        // Freeing everything makes the pool contiguous again.
+        drop(handles);
+        drop((rest, hole1, hole2));
+        assert_eq!(pool.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_carve_subpool() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        let subpool = pool
+            .carve_subpool(MemoryRange::from_4k_gpn_range(15..20))
+            .unwrap();
+        assert_eq!(pool.stats().carved_pages, 5);
+        assert_eq!(subpool.stats().total_pages, 5);
+
+        // Carving an already carved range fails.
+        assert!(
+            pool.carve_subpool(MemoryRange::from_4k_gpn_range(18..22))
+                .is_err()
+        );
+
+        let alloc = pool.allocator("parent".into()).unwrap();
+        let sub_alloc = subpool.allocator("child".into()).unwrap();
+
+        // The parent's free space is split around the carved range, and is
+        // exhausted without handing out any carved pages.
+        let a1 = alloc.alloc(5.try_into().unwrap(), "a1".into()).unwrap();
+        let a2 = alloc.alloc(10.try_into().unwrap(), "a2".into()).unwrap();
+        assert!(alloc.alloc(1.try_into().unwrap(), "a3".into()).is_err());
+        let s1 = sub_alloc.alloc(5.try_into().unwrap(), "s1".into()).unwrap();
+
+        let range = |h: &PagePoolHandle| h.base_pfn..h.base_pfn + h.size_pages;
+        for h in [&a1, &a2] {
+            let r = range(h);
+            let s = range(&s1);
+            assert!(r.end <= s.start || s.end <= r.start, "{r:?} overlaps {s:?}");
+        }
+        assert_eq!(range(&s1), 15..20);
+
+        // The carved range is preserved across save and restore.
+        drop((a1, a2));
+        drop(alloc);
+        let state = pool.save().unwrap();
+        let mut new_pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        new_pool.restore(state).unwrap();
+        assert_eq!(new_pool.stats().carved_pages, 5);
+    }
+
+    #[test]
+    fn test_reclaim_leaked() {
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+
+        let alloc = pool.allocator("test".into()).unwrap();
+        let _a1 = alloc.alloc(5.try_into().unwrap(), "alloc1".into()).unwrap();
+        let _a2 = alloc
+            .alloc(10.try_into().unwrap(), "alloc2".into())
+            .unwrap();
+
+        let state = pool.save().unwrap();
+
+        let mut pool =
+            PagePool::new(&[MemoryRange::from_4k_gpn_range(10..30)], big_test_mapper()).unwrap();
+        pool.restore(state).unwrap();
+        pool.validate_restore(true).unwrap();
+
+        // Only the free remainder of the pool can be allocated.
+        let alloc = pool.allocator("test2".into()).unwrap();
+        assert!(alloc.alloc(6.try_into().unwrap(), "big".into()).is_err());
+
+        assert_eq!(pool.reclaim_leaked("other"), 0);
+        assert_eq!(pool.reclaim_leaked("test"), 15);
+        assert_eq!(pool.reclaim_leaked("test"), 0);
+
+        // The reclaimed slots are merged with the free remainder.
+        let a = alloc.alloc(20.try_into().unwrap(), "all".into()).unwrap();
+        assert_eq!(a.base_pfn(), 10);
+    }
+
     #[test]
     fn test_restore_other_allocator() {
         let mut pool =